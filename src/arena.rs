@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::mem::size_of;
+use std::ops::RangeInclusive;
+
+use num_traits::ToPrimitive;
+
+use crate::eval::{BuiltIn, EvalError, Value_};
+use crate::syntax::{Number, Var};
+use crate::types::Picture;
+
+// Terms live in an arena owned by their `State` and point to each other by index. Built-ins
+// and small numbers are interned, so making one allocates nothing, and the nodes nothing
+// refers to any more are reused after a collection.
+
+// A node of the arena. It stays valid until the next collection that doesn't reach it.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Value(u32);
+
+#[derive(Debug, PartialEq, Clone)]
+struct V {
+    val: Value_,
+    computed: bool,
+    // How many nodes point to this one
+    refs: u32,
+}
+
+// In the order of their discriminants, which are their indices
+const BUILTINS: [BuiltIn; 28] = [
+    BuiltIn::Inc,
+    BuiltIn::Dec,
+    BuiltIn::Add,
+    BuiltIn::Mul,
+    BuiltIn::Div,
+    BuiltIn::Eq,
+    BuiltIn::Lt,
+    BuiltIn::Neg,
+    BuiltIn::S,
+    BuiltIn::C,
+    BuiltIn::B,
+    BuiltIn::True,
+    BuiltIn::False,
+    BuiltIn::Pwr2,
+    BuiltIn::I,
+    BuiltIn::Cons,
+    BuiltIn::Head,
+    BuiltIn::Tail,
+    BuiltIn::Nil,
+    BuiltIn::IsNil,
+    BuiltIn::Draw,
+    BuiltIn::Checkerboard,
+    BuiltIn::MultipleDraw,
+    BuiltIn::Modem,
+    BuiltIn::Send,
+    BuiltIn::If0,
+    BuiltIn::F38,
+    BuiltIn::Interact,
+];
+
+// Galaxy's coordinates and counters mostly fall in here
+const SMALL: RangeInclusive<i64> = -256..=1024;
+
+const INTERNED: usize = BUILTINS.len() + (*SMALL.end() - *SMALL.start() + 1) as usize;
+
+pub fn b(b: BuiltIn) -> Value {
+    Value(b as u32)
+}
+
+pub struct Arena {
+    nodes: Vec<V>,
+    // Nodes freed by the last collection, lowest last
+    free: Vec<u32>,
+    consing: Option<Consing>,
+    // Nodes made since the arena was, for profiling
+    allocations: u64,
+}
+
+// With hash-consing, building a term that is still around gives back its node. Nodes are
+// overwritten with what they evaluate to, so the node found may already hold the result.
+#[derive(Default)]
+struct Consing {
+    applies: HashMap<(Value, Value), Value, BuildHasherDefault<NodeHasher>>,
+    numbers: HashMap<Number, Value>,
+}
+
+// Hashes node indices with a multiplication, the default hasher is several times slower and
+// `ap` hashes on every call
+#[derive(Default)]
+pub struct NodeHasher(u64);
+
+impl Hasher for NodeHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|b| self.write_u64(*b as u64));
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        let builtins = BUILTINS.iter().map(|b| Value_::BuiltIn(*b));
+        let numbers = SMALL.map(|n| Value_::Number(n.into()));
+        let nodes: Vec<V> = builtins
+            .chain(numbers)
+            .map(|val| V {
+                val,
+                computed: true,
+                refs: 0,
+            })
+            .collect();
+        debug_assert!(BUILTINS.iter().enumerate().all(|(i, b)| *b as usize == i));
+        Arena {
+            nodes,
+            free: vec![],
+            allocations: 0,
+            consing: None,
+        }
+    }
+}
+
+impl Arena {
+    fn node(&self, v: Value) -> &V {
+        &self.nodes[v.0 as usize]
+    }
+
+    fn node_mut(&mut self, v: Value) -> &mut V {
+        &mut self.nodes[v.0 as usize]
+    }
+
+    fn add_ref(&mut self, v: Value) {
+        let node = self.node_mut(v);
+        node.refs = node.refs.saturating_add(1);
+    }
+
+    fn add_refs(&mut self, val: &Value_) {
+        if let Value_::Apply(f, x) = *val {
+            self.add_ref(f);
+            self.add_ref(x);
+        }
+    }
+
+    // Takes back the references `val` holds, and those of the nodes only it pointed to except
+    // `keep`. The counts only decide what `is_shared` says, so a node they miss is harmless.
+    fn remove_refs(&mut self, val: &Value_, keep: Value) {
+        let mut pending = vec![];
+        if let Value_::Apply(f, x) = *val {
+            pending.push(f);
+            pending.push(x);
+        }
+        while let Some(v) = pending.pop() {
+            let node = self.node_mut(v);
+            if node.refs == 0 {
+                continue;
+            }
+            node.refs -= 1;
+            if let Value_::Apply(f, x) = node.val {
+                if node.refs == 0 && v != keep {
+                    pending.push(f);
+                    pending.push(x);
+                }
+            }
+        }
+    }
+
+    fn alloc(&mut self, val: Value_, computed: bool) -> Value {
+        self.add_refs(&val);
+        self.allocations += 1;
+        let node = V {
+            val,
+            computed,
+            refs: 0,
+        };
+        match self.free.pop() {
+            Some(i) => {
+                self.nodes[i as usize] = node;
+                Value(i)
+            }
+            None => {
+                self.nodes.push(node);
+                Value(self.nodes.len() as u32 - 1)
+            }
+        }
+    }
+
+    pub fn var(&mut self, v: Var) -> Value {
+        self.alloc(Value_::Var(v), false)
+    }
+
+    // The node of a small number
+    pub fn interned(&self, n: &Number) -> Option<Value> {
+        match n.to_i64() {
+            Some(i) if SMALL.contains(&i) => {
+                Some(Value((BUILTINS.len() as i64 + i - SMALL.start()) as u32))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn number(&mut self, n: Number) -> Value {
+        if let Some(v) = self.interned(&n) {
+            return v;
+        }
+        if let Some(v) = self.consing.as_ref().and_then(|c| c.numbers.get(&n)) {
+            return *v;
+        }
+        let v = self.alloc(Value_::Number(n.clone()), true);
+        if let Some(consing) = self.consing.as_mut() {
+            consing.numbers.insert(n, v);
+        }
+        v
+    }
+
+    pub fn picture(&mut self, p: Picture) -> Value {
+        self.alloc(Value_::Picture(p), true)
+    }
+
+    pub fn ap(&mut self, f: Value, arg: Value) -> Value {
+        // Two sends of the same data still send twice
+        if self.consing.is_none() || f == b(BuiltIn::Send) {
+            return self.alloc(Value_::Apply(f, arg), false);
+        }
+        if let Some(v) = self.consing.as_ref().unwrap().applies.get(&(f, arg)) {
+            return *v;
+        }
+        let v = self.alloc(Value_::Apply(f, arg), false);
+        self.consing.as_mut().unwrap().applies.insert((f, arg), v);
+        v
+    }
+
+    // Makes `ap` and `number` share the nodes of equal terms from now on
+    pub fn set_hash_consing(&mut self, on: bool) {
+        match (on, self.consing.is_some()) {
+            (true, false) => self.consing = Some(Consing::default()),
+            (false, true) => self.consing = None,
+            _ => {}
+        }
+    }
+
+    // A pair that is already evaluated, `cons` requires both of its arguments to be
+    pub fn cons(&mut self, head: Value, tail: Value) -> Value {
+        let f = self.ap(b(BuiltIn::Cons), head);
+        let cons = self.ap(f, tail);
+        self.node_mut(cons).computed = true;
+        cons
+    }
+
+    pub fn get(&self, v: Value) -> &Value_ {
+        &self.node(v).val
+    }
+
+    pub fn is_computed(&self, v: Value) -> bool {
+        self.node(v).computed
+    }
+
+    pub fn set_computed(&mut self, v: Value) {
+        self.node_mut(v).computed = true;
+    }
+
+    // Whether more than one node points to `v`
+    pub fn is_shared(&self, v: Value) -> bool {
+        self.node(v).refs > 1
+    }
+
+    // Overwrites `target` with the term in `v`, which is how results are shared
+    pub fn copy(&mut self, target: Value, v: Value) {
+        let val = self.node(v).val.clone();
+        let old = std::mem::replace(&mut self.node_mut(target).val, val);
+        self.remove_refs(&old, v);
+        // Nothing points to a fresh result, its children only change hands
+        if self.node(v).refs > 0 {
+            let val = self.node(v).val.clone();
+            self.add_refs(&val);
+        }
+    }
+
+    // Forgets `v` if nothing points to it, as happens to the steps on the way to a result
+    pub fn release(&mut self, v: Value, keep: Value) {
+        if self.node(v).refs == 0 {
+            let val = self.node(v).val.clone();
+            self.remove_refs(&val, keep);
+        }
+    }
+
+    pub fn as_number(&self, v: Value, builtin: BuiltIn) -> Result<Number, EvalError> {
+        if let Value_::Number(n) = self.get(v) {
+            Ok(n.clone())
+        } else {
+            Err(EvalError::TypeMismatch {
+                builtin,
+                expected: "number",
+            })
+        }
+    }
+
+    // Whether two terms have the same shape
+    pub fn equal(&self, a: Value, b: Value) -> bool {
+        let mut pending = vec![(a, b)];
+        while let Some((a, b)) = pending.pop() {
+            match (self.get(a), self.get(b)) {
+                (Value_::Apply(f, x), Value_::Apply(g, y)) => {
+                    pending.push((*f, *g));
+                    pending.push((*x, *y));
+                }
+                (a, b) if a != b => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    // The number of nodes in use
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    pub fn allocations(&self) -> u64 {
+        self.allocations
+    }
+
+    // Roughly how many bytes the tables of hash-consing take, not counting big numbers
+    pub fn consing_memory(&self) -> usize {
+        self.consing.as_ref().map_or(0, |c| {
+            let apply = size_of::<((Value, Value), Value)>();
+            let number = size_of::<(Number, Value)>();
+            c.applies.capacity() * apply + c.numbers.capacity() * number
+        })
+    }
+
+    // The node hash-consing gives the term of `v`. An evaluated node holds a copy of the
+    // term it evaluated to, which is equal to the node of that term but isn't the same one.
+    pub fn canonical(&self, v: Value) -> Value {
+        if !self.is_computed(v) {
+            return v;
+        }
+        let consing = self.consing.as_ref();
+        let found = match self.get(v) {
+            Value_::BuiltIn(builtin) => Some(b(*builtin)),
+            Value_::Number(n) => self
+                .interned(n)
+                .or_else(|| consing.and_then(|c| c.numbers.get(n).copied())),
+            Value_::Apply(f, x) => consing.and_then(|c| c.applies.get(&(*f, *x)).copied()),
+            _ => None,
+        };
+        found.unwrap_or(v)
+    }
+
+    // Frees every node the roots don't reach and gives back the end of the arena if it's free
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        let mut marked = vec![false; self.nodes.len()];
+        let mut pending: Vec<Value> = roots.into_iter().collect();
+        while let Some(v) = pending.pop() {
+            let i = v.0 as usize;
+            if marked[i] {
+                continue;
+            }
+            marked[i] = true;
+            if let Value_::Apply(f, x) = self.nodes[i].val {
+                pending.push(f);
+                pending.push(x);
+            }
+        }
+        marked[..INTERNED].iter_mut().for_each(|m| *m = true);
+
+        // Only the references from the nodes that stay count
+        self.nodes.iter_mut().for_each(|node| node.refs = 0);
+        let children: Vec<Value> = (self.nodes.iter().zip(marked.iter()))
+            .filter_map(|(node, marked)| match node.val {
+                Value_::Apply(f, x) if *marked => Some(vec![f, x]),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        children.into_iter().for_each(|v| self.add_ref(v));
+
+        let end = marked.iter().rposition(|m| *m).map_or(0, |i| i + 1);
+        self.nodes.truncate(end);
+        if self.nodes.capacity() > 2 * end {
+            self.nodes.shrink_to_fit();
+        }
+        self.free = (INTERNED..end)
+            .rev()
+            .filter(|i| !marked[*i])
+            .map(|i| i as u32)
+            .collect();
+        for &i in self.free.iter() {
+            self.nodes[i as usize].val = Value_::BuiltIn(BuiltIn::Nil);
+        }
+        // Freed nodes will be reused for other terms
+        if let Some(consing) = self.consing.as_mut() {
+            let live = |v: &Value| marked.get(v.0 as usize) == Some(&true);
+            (consing.applies).retain(|(f, x), v| live(f) && live(x) && live(v));
+            consing.numbers.retain(|_, v| live(v));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interning() {
+        let mut arena = Arena::default();
+        let len = arena.len();
+        assert_eq!(arena.number(5.into()), arena.number(5.into()));
+        let n = arena.number((-256).into());
+        assert_eq!(*arena.get(n), Value_::Number((-256).into()));
+        assert_eq!(
+            *arena.get(b(BuiltIn::Interact)),
+            Value_::BuiltIn(BuiltIn::Interact)
+        );
+        assert_eq!(arena.len(), len);
+        assert_ne!(arena.number(1025.into()), arena.number(1025.into()));
+    }
+
+    #[test]
+    fn test_sharing() {
+        let mut arena = Arena::default();
+        let one = arena.number(1.into());
+        let inc = arena.ap(b(BuiltIn::Inc), one);
+        let pair = arena.ap(inc, inc);
+        assert!(arena.is_shared(inc));
+
+        // Once the pair is overwritten, only the new term points to `inc`
+        let single = arena.ap(b(BuiltIn::I), inc);
+        arena.copy(pair, single);
+        assert!(!arena.is_shared(inc));
+    }
+
+    #[test]
+    fn test_collect() {
+        let mut arena = Arena::default();
+        let one = arena.number(1.into());
+        let kept = arena.cons(one, b(BuiltIn::Nil));
+        let len = arena.len();
+        for _ in 0..100 {
+            arena.ap(b(BuiltIn::Inc), one);
+        }
+        arena.collect(vec![kept]);
+        assert_eq!(arena.len(), len);
+        assert!(arena.is_computed(kept));
+
+        // Freed nodes are reused before the arena grows
+        let dropped = arena.ap(b(BuiltIn::Inc), one);
+        arena.collect(vec![kept]);
+        assert_eq!(arena.ap(b(BuiltIn::Dec), one), dropped);
+    }
+
+    #[test]
+    fn test_hash_consing() {
+        let mut arena = Arena::default();
+        arena.set_hash_consing(true);
+        let big = arena.number(5000.into());
+        let inc = arena.ap(b(BuiltIn::Inc), big);
+        assert_eq!(arena.number(5000.into()), big);
+        assert_eq!(arena.ap(b(BuiltIn::Inc), big), inc);
+        let send = arena.ap(b(BuiltIn::Send), big);
+        assert_ne!(arena.ap(b(BuiltIn::Send), big), send);
+
+        // A node found again after a collection is the same term
+        arena.collect(vec![inc]);
+        let dec = arena.ap(b(BuiltIn::Dec), big);
+        assert_eq!(arena.ap(b(BuiltIn::Inc), big), inc);
+        assert_ne!(dec, inc);
+        arena.collect(vec![]);
+        let other = arena.number(6000.into());
+        let inc = arena.ap(b(BuiltIn::Inc), other);
+        assert_eq!(*arena.get(inc), Value_::Apply(b(BuiltIn::Inc), other));
+    }
+}
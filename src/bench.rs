@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::eval::{Backend, State};
+use crate::interact::run_interaction;
+use crate::memo::MemoStats;
+use crate::send::Transport;
+use crate::syntax::parse_file;
+use crate::types::NestedList;
+
+// The start of galaxy's tutorial, which doesn't send anything
+const CLICKS: &[(i64, i64)] = &[
+    (0, 0),
+    (0, 0),
+    (0, 0),
+    (0, 0),
+    (0, 0),
+    (0, 0),
+    (0, 0),
+    (0, 0),
+    (8, 4),
+    (2, -8),
+    (3, 6),
+    (0, -14),
+    (-4, 10),
+    (9, -3),
+    (-4, 10),
+    (1, 4),
+];
+
+// The backend of every run and whether it memoizes calls
+const BACKENDS: &[(Backend, bool, &str)] = &[
+    (Backend::Interpreter, false, "interpreter"),
+    (Backend::Compiled, false, "compiled"),
+    (Backend::Interpreter, true, "interpreter + memo"),
+    (Backend::Compiled, true, "compiled + memo"),
+];
+
+struct Timing {
+    first_frame: Duration,
+    click_path: Duration,
+    // What every click of the path drew
+    frames: Vec<NestedList>,
+    // Terms still in use at the end of the path
+    nodes: usize,
+    memo: Option<MemoStats>,
+}
+
+fn load(
+    name: &str,
+    text: &str,
+    (backend, memo): (Backend, bool),
+    transport: Box<dyn Transport>,
+) -> Result<State, Box<dyn Error>> {
+    let mut state = State::new();
+    state.set_transport(transport);
+    for stmt in parse_file(name, text)? {
+        state.interpret(stmt)?;
+    }
+    state.set_backend(backend);
+    state.set_memo(memo);
+    Ok(state)
+}
+
+// Every run starts from a fresh state, results of constant definitions are kept between clicks
+fn time(
+    name: &str,
+    text: &str,
+    protocol: &str,
+    run: (Backend, bool),
+    transport: &dyn Fn() -> Result<Box<dyn Transport>, Box<dyn Error>>,
+) -> Result<Timing, Box<dyn Error>> {
+    let state = load(name, text, run, transport()?)?;
+    let start = Instant::now();
+    run_interaction(&state, protocol, NestedList::Nil, 0, 0)?;
+    let first_frame = start.elapsed();
+
+    let state = load(name, text, run, transport()?)?;
+    let start = Instant::now();
+    let mut st = NestedList::Nil;
+    let mut frames = vec![];
+    for &(x, y) in CLICKS {
+        let (new_state, data) = run_interaction(&state, protocol, st, x, y)?;
+        st = new_state;
+        frames.push(data);
+    }
+    let click_path = start.elapsed();
+    let nodes = state.arena().len();
+    Ok(Timing {
+        first_frame,
+        click_path,
+        frames,
+        nodes,
+        memo: state.memo_stats(),
+    })
+}
+
+fn seconds(d: Duration) -> String {
+    format!("{:.3} s", d.as_secs_f64())
+}
+
+// Times the first frame and a path of clicks with every backend
+pub fn run_bench(
+    name: &str,
+    text: &str,
+    protocol: &str,
+    transport: &dyn Fn() -> Result<Box<dyn Transport>, Box<dyn Error>>,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut timings = vec![];
+    for &(backend, memo, _) in BACKENDS {
+        timings.push(time(name, text, protocol, (backend, memo), transport)?);
+    }
+    if timings.iter().any(|t| t.frames != timings[0].frames) {
+        return Err("The backends drew different frames".into());
+    }
+
+    let clicks = format!("{} clicks", CLICKS.len());
+    writeln!(
+        out,
+        "{:<26} {:>12} {:>12} {:>10}",
+        "", "first frame", clicks, "nodes"
+    )?;
+    for (&(_, _, label), t) in BACKENDS.iter().zip(timings.iter()) {
+        let (first, path) = (seconds(t.first_frame), seconds(t.click_path));
+        writeln!(
+            out,
+            "{:<26} {:>12} {:>12} {:>10}",
+            label, first, path, t.nodes
+        )?;
+    }
+    let base = &timings[0];
+    for (&(_, _, label), t) in BACKENDS.iter().zip(timings.iter()).skip(1) {
+        let speedup =
+            |a: Duration, b: Duration| format!("{:.1}x", a.as_secs_f64() / b.as_secs_f64());
+        writeln!(
+            out,
+            "{:<26} {:>12} {:>12}",
+            format!("{} speedup", label),
+            speedup(base.first_frame, t.first_frame),
+            speedup(base.click_path, t.click_path)
+        )?;
+    }
+    for (&(_, _, label), t) in BACKENDS.iter().zip(timings.iter()) {
+        if let Some(stats) = t.memo {
+            writeln!(out, "{}: {}", label, stats)?;
+        }
+    }
+    Ok(())
+}
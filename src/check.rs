@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::decompile::{decompile_term, show, Term};
+use crate::eval::{BuiltIn, State};
+use crate::printer::{builtin_name, var_name};
+use crate::syntax::Var;
+
+// Finds definitions that can only fail. Definitions are decompiled to lambda terms and every
+// application in them is given a simple type. Parameters are never inferred, so only the
+// literals, built-ins and definitions a term is made of can make it ill-typed.
+
+// Inferring the definitions again stops once nothing changes or after this many rounds
+const MAX_ROUNDS: usize = 16;
+
+// Offending terms are cut short after this many characters
+const MAX_TERM_LEN: usize = 80;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Type {
+    Number,
+    // `t` or `f`, which select one of their next two arguments
+    Bool,
+    // `nil` or a pair, which pass their items to what they are applied to
+    List,
+    // Needs this many more arguments before it does anything
+    Function(usize),
+    // Anything, as far as the checker can tell
+    Any,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "a number"),
+            Type::Bool => write!(f, "a boolean"),
+            Type::List => write!(f, "a list"),
+            Type::Function(1) => write!(f, "a function of 1 argument"),
+            Type::Function(n) => write!(f, "a function of {} arguments", n),
+            Type::Any => write!(f, "anything"),
+        }
+    }
+}
+
+// What a branch of `if0` or a boolean can be
+fn join(a: Type, b: Type) -> Type {
+    if a == b {
+        a
+    } else {
+        Type::Any
+    }
+}
+
+pub fn builtin_type(b: BuiltIn) -> Type {
+    match b {
+        BuiltIn::True | BuiltIn::False => Type::Bool,
+        BuiltIn::Nil => Type::List,
+        _ => Type::Function(b.arity()),
+    }
+}
+
+// The arguments a built-in evaluates and the types they must have. `head`, `tail` and
+// `isnil` apply their argument to something, which only a number refuses.
+fn parameters(b: BuiltIn) -> &'static [&'static [Type]] {
+    const NUMBER: &[Type] = &[Type::Number, Type::Any];
+    const APPLICABLE: &[Type] = &[Type::Bool, Type::List, Type::Function(0), Type::Any];
+    const LIST: &[Type] = &[Type::List, Type::Any];
+    const DATA: &[Type] = &[Type::Number, Type::List, Type::Any];
+    match b {
+        BuiltIn::Inc | BuiltIn::Dec | BuiltIn::Neg | BuiltIn::Pwr2 | BuiltIn::If0 => &[NUMBER],
+        BuiltIn::Checkerboard => &[NUMBER],
+        BuiltIn::Add | BuiltIn::Mul | BuiltIn::Div | BuiltIn::Eq | BuiltIn::Lt => &[NUMBER, NUMBER],
+        BuiltIn::Head | BuiltIn::Tail | BuiltIn::IsNil => &[APPLICABLE],
+        BuiltIn::Draw | BuiltIn::MultipleDraw => &[LIST],
+        BuiltIn::Modem | BuiltIn::Send => &[DATA],
+        _ => &[],
+    }
+}
+
+fn allows(types: &[Type], t: Type) -> bool {
+    match t {
+        Type::Function(_) => types.iter().any(|t| matches!(t, Type::Function(_))),
+        t => types.contains(&t),
+    }
+}
+
+// What a built-in applied to all of `args` evaluates to, before any further arguments
+fn builtin_result(b: BuiltIn, args: &[Type]) -> Type {
+    match b {
+        BuiltIn::Inc | BuiltIn::Dec | BuiltIn::Neg | BuiltIn::Pwr2 => Type::Number,
+        BuiltIn::Add | BuiltIn::Mul | BuiltIn::Div => Type::Number,
+        BuiltIn::Eq | BuiltIn::Lt | BuiltIn::IsNil => Type::Bool,
+        BuiltIn::Cons => Type::List,
+        BuiltIn::I => args[0],
+        BuiltIn::True => args[0],
+        BuiltIn::False => args[1],
+        BuiltIn::Nil => Type::Bool,
+        BuiltIn::If0 => join(args[1], args[2]),
+        _ => Type::Any,
+    }
+}
+
+fn excerpt(term: &Term) -> String {
+    let text = show(term);
+    match text.char_indices().nth(MAX_TERM_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+// The head of an application and its arguments, in order
+fn spine(term: &Term) -> (&Term, Vec<&Term>) {
+    let mut args = vec![];
+    let mut head = term;
+    while let Term::Apply(f, x) = head {
+        args.push(&**x);
+        head = f;
+    }
+    args.reverse();
+    (head, args)
+}
+
+struct Inference<'a> {
+    types: &'a HashMap<Var, Type>,
+    // Variables bound by `let`, those bound by lambdas can be anything
+    lets: HashMap<usize, Type>,
+    errors: Vec<String>,
+}
+
+impl Inference<'_> {
+    fn infer(&mut self, term: &Term) -> Type {
+        match term {
+            Term::Bound(x) => self.lets.get(x).copied().unwrap_or(Type::Any),
+            Term::Global(v) => match self.types.get(v) {
+                Some(t) => *t,
+                None => {
+                    self.errors
+                        .push(format!("`{}` is not defined", var_name(v)));
+                    Type::Any
+                }
+            },
+            Term::Number(_) => Type::Number,
+            Term::BuiltIn(b) => builtin_type(*b),
+            Term::List(items) => {
+                items.iter().for_each(|item| {
+                    self.infer(item);
+                });
+                Type::List
+            }
+            Term::Lambda(..) => {
+                let mut params = 0;
+                let mut body = term;
+                while let Term::Lambda(_, b) = body {
+                    params += 1;
+                    body = b;
+                }
+                self.infer(body);
+                Type::Function(params)
+            }
+            Term::Let(x, value, body) => {
+                let t = self.infer(value);
+                self.lets.insert(*x, t);
+                self.infer(body)
+            }
+            Term::Apply(..) => {
+                let (head, args) = spine(term);
+                let args: Vec<Type> = args.into_iter().map(|a| self.infer(a)).collect();
+                match head {
+                    Term::BuiltIn(b) => self.apply_builtin(*b, &args, term),
+                    _ => {
+                        let t = self.infer(head);
+                        self.apply(t, &args, term)
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply_builtin(&mut self, b: BuiltIn, args: &[Type], term: &Term) -> Type {
+        for (types, arg) in parameters(b).iter().zip(args) {
+            if !allows(types, *arg) {
+                self.errors.push(format!(
+                    "`{}` can't take {} in `{}`",
+                    builtin_name(b),
+                    arg,
+                    excerpt(term)
+                ));
+            }
+        }
+        if args.len() < b.arity() {
+            return match b {
+                BuiltIn::True | BuiltIn::False if args.is_empty() => Type::Bool,
+                BuiltIn::Nil => Type::List,
+                _ => Type::Function(b.arity() - args.len()),
+            };
+        }
+        let (taken, rest) = args.split_at(b.arity());
+        let t = builtin_result(b, taken);
+        self.apply(t, rest, term)
+    }
+
+    fn apply(&mut self, t: Type, args: &[Type], term: &Term) -> Type {
+        if args.is_empty() {
+            return t;
+        }
+        match t {
+            Type::Number => {
+                let msg = format!("a number is applied to an argument in `{}`", excerpt(term));
+                self.errors.push(msg);
+                Type::Any
+            }
+            Type::Bool if args.len() >= 2 => self.apply(join(args[0], args[1]), &args[2..], term),
+            Type::Bool => Type::Function(1),
+            Type::Function(n) if args.len() < n => Type::Function(n - args.len()),
+            _ => Type::Any,
+        }
+    }
+}
+
+pub struct Checker {
+    terms: HashMap<Var, Term>,
+    types: HashMap<Var, Type>,
+}
+
+impl Checker {
+    // Infers the type of every definition of the state
+    pub fn new(state: &State) -> Self {
+        let terms: HashMap<Var, Term> = state
+            .definitions()
+            .map(|(var, val)| (var.clone(), decompile_term(&state.arena(), *val)))
+            .collect();
+        let mut checker = Checker {
+            types: terms.keys().map(|var| (var.clone(), Type::Any)).collect(),
+            terms,
+        };
+        for _ in 0..MAX_ROUNDS {
+            let changed: Vec<(Var, Type)> = (checker.terms.iter())
+                .map(|(var, term)| (var.clone(), checker.infer(term).0))
+                .filter(|(var, t)| checker.types[var] != *t)
+                .collect();
+            if changed.is_empty() {
+                break;
+            }
+            checker.types.extend(changed);
+        }
+        checker
+    }
+
+    fn infer(&self, term: &Term) -> (Type, Vec<String>) {
+        let mut inference = Inference {
+            types: &self.types,
+            lets: HashMap::new(),
+            errors: vec![],
+        };
+        let t = inference.infer(term);
+        (t, inference.errors)
+    }
+
+    pub fn type_of(&self, var: &Var) -> Option<Type> {
+        self.types.get(var).copied()
+    }
+
+    // Why the definition of `var` can only fail, if it can
+    pub fn errors(&self, var: &Var) -> Vec<String> {
+        match self.terms.get(var) {
+            Some(term) => self.infer(term).1,
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::{parse_file, parse_line};
+
+    fn checker(lines: &[&str]) -> Checker {
+        let mut state = State::new();
+        for line in lines {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        Checker::new(&state)
+    }
+
+    fn named(name: &str) -> Var {
+        Var::Named(name.to_string())
+    }
+
+    #[test]
+    fn test_types() {
+        let checker = checker(&[
+            "n = ap ap add 1 ap inc m",
+            "m = ap neg 2",
+            "inc2 = ap ap b inc inc",
+            "l = ap ap cons 1 nil",
+            "abs = ap ap s ap ap s ap ap c lt 0 neg i",
+            "pick = ap ap ap ap lt 1 2 n 3",
+            "k = ap cons 1",
+        ]);
+        let type_of = |name| checker.type_of(&named(name)).unwrap();
+        assert_eq!(type_of("n"), Type::Number);
+        assert_eq!(type_of("m"), Type::Number);
+        assert_eq!(type_of("inc2"), Type::Function(1));
+        assert_eq!(type_of("l"), Type::List);
+        assert_eq!(type_of("abs"), Type::Function(1));
+        assert_eq!(type_of("pick"), Type::Number);
+        assert_eq!(type_of("k"), Type::Function(2));
+        assert!(checker.errors(&named("abs")).is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        let checker = checker(&[
+            "sum = ap ap add 1 ap ap cons 1 nil",
+            "first = ap car 5",
+            "call = ap 1 2",
+            "later = ap ap b inc cons",
+            "missing = ap inc nowhere",
+            "n = 5",
+            "indirect = ap n 1",
+        ]);
+        let errors = |name| checker.errors(&named(name));
+        assert_eq!(
+            errors("sum"),
+            vec!["`add` can't take a list in `add 1 [1]`"]
+        );
+        assert_eq!(
+            errors("first"),
+            vec!["`car` can't take a number in `car 5`"]
+        );
+        assert_eq!(
+            errors("call"),
+            vec!["a number is applied to an argument in `1 2`"]
+        );
+        assert_eq!(
+            errors("later"),
+            vec!["`inc` can't take a function of 2 arguments in `inc (cons x0)`"]
+        );
+        assert_eq!(errors("missing"), vec!["`nowhere` is not defined"]);
+        assert_eq!(
+            errors("indirect"),
+            vec!["a number is applied to an argument in `n 1`"]
+        );
+    }
+
+    #[test]
+    fn test_galaxy() {
+        let mut state = State::new();
+        for stmt in parse_file("galaxy.txt", include_str!("../data/galaxy.txt")).unwrap() {
+            state.interpret(stmt).unwrap();
+        }
+        let checker = Checker::new(&state);
+        for (var, _) in state.definitions() {
+            assert_eq!(checker.errors(var), Vec::<String>::new(), "{:?}", var);
+        }
+        assert_eq!(checker.type_of(&named("galaxy")), Some(Type::Function(2)));
+    }
+}
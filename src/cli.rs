@@ -0,0 +1,347 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::eval::{Backend, Limits};
+use crate::evaluator::EvaluatorKind;
+use crate::script::FrameFormat;
+use crate::syntax::{parse_var, Var};
+use crate::trace::TraceOptions;
+
+pub const USAGE: &str = "Usage: galaxy [OPTIONS] <COMMAND>
+
+Commands:
+  test <file>            Run a TEST file, fails if any check fails
+  explore [file]         Open an INTERACTIVE file in a window (default: ./data/i_galaxy.txt)
+  script <file>          Click through a SCRIPT file without a window
+  eval <expr>            Evaluate an expression
+  debug <expr>           Evaluate an expression stopping at every rewrite or at breakpoints
+  modulate <expr>        Print the signal a list expression is sent as
+  demodulate <bits>      Print a signal in list notation
+  render <expr>          Draw the pictures an expression evaluates to
+  replay <log>           Print the exchanges of a recorded log
+  print <file>           Print the definitions in a file in canonical form
+  decompile <file>       Print the definitions in a file as lambda terms
+  check <file>           Report the definitions in a file that can only fail, fails if any
+  repl                   Evaluate definitions and expressions interactively
+  bench [file]           Time galaxy's first frame and tutorial with each backend (default: ./data/galaxy.txt)
+  diff [file]            Compare the evaluators on a TEST file and random programs, fails if they differ (default: ./data/test.txt)
+  help                   Print this message
+
+Options:
+  -p, --protocol <name>  Protocol to explore or script, overrides PROTOCOL
+  -d, --data <folder>    Folder for INCLUDE, OUTPUT and REPLAY lines (default: the file's folder)
+  -i, --include <file>   Load definitions before evaluating anything, can be repeated
+  -o, --output <path>    Folder for the frames of `script`, image file for `render`
+  -f, --format <format>  Frame format for `script`: ascii or bmp
+      --record <log>     Append all send traffic to a log
+      --replay <log>     Answer sends from a recorded log instead of the server
+  -b, --backend <name>   How definitions are run: interpreter (default) or compiled
+  -e, --evaluator <name> What runs `test` and `eval`: graph (default), which has the backends, or closures
+      --max-steps <n>    Fail evaluations that take more steps than this
+      --max-nodes <n>    Fail evaluations that grow the term graph past this many nodes
+      --timeout <secs>   Fail evaluations that take longer than this
+      --trace <file>     Write every rewrite to a file, - for the standard output
+      --trace-var <name> Only trace the unfolds of this variable, can be repeated
+      --break <name>     Make `debug` stop whenever this variable is unfolded, can be repeated
+  -O, --optimize         Simplify the definitions before evaluating, for `eval`, `script` and `explore`
+      --memo             Remember the calls of definitions across evaluations, for `eval`, `script` and `explore`
+      --profile <file>   Print what evaluation spent the most on at the end, and its stacks for flame graphs to a file
+      --profile-top <n>  How many definitions and built-ins --profile prints (default: 20)
+  -s, --sugar            Print lists as ( 1 , 2 ) in `print`
+  -v, --verbose          Print every passing check and every send
+  -q, --quiet            Only print failures and results";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Test(PathBuf),
+    Explore(PathBuf),
+    Script(PathBuf),
+    Eval(String),
+    Debug(String),
+    Modulate(String),
+    Demodulate(String),
+    Render(String),
+    Replay(PathBuf),
+    Print(PathBuf),
+    Decompile(PathBuf),
+    Check(PathBuf),
+    Repl,
+    Bench(PathBuf),
+    Diff(PathBuf),
+    Help,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Options {
+    pub protocol: Option<String>,
+    pub data_folder: Option<PathBuf>,
+    pub includes: Vec<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub format: Option<FrameFormat>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub backend: Backend,
+    pub evaluator: EvaluatorKind,
+    pub limits: Limits,
+    pub trace: Option<PathBuf>,
+    pub trace_vars: Vec<Var>,
+    pub breakpoints: Vec<Var>,
+    pub optimize: bool,
+    pub memo: bool,
+    pub profile: Option<PathBuf>,
+    pub profile_top: Option<usize>,
+    pub sugar: bool,
+    pub verbosity: Verbosity,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            protocol: None,
+            data_folder: None,
+            includes: vec![],
+            output: None,
+            format: None,
+            record: None,
+            replay: None,
+            backend: Backend::Interpreter,
+            evaluator: EvaluatorKind::Graph,
+            limits: Limits::default(),
+            trace: None,
+            trace_vars: vec![],
+            breakpoints: vec![],
+            optimize: false,
+            memo: false,
+            profile: None,
+            profile_top: None,
+            sugar: false,
+            verbosity: Verbosity::Normal,
+        }
+    }
+}
+
+impl Options {
+    pub fn quiet(&self) -> bool {
+        self.verbosity == Verbosity::Quiet
+    }
+
+    pub fn verbose(&self) -> bool {
+        self.verbosity == Verbosity::Verbose
+    }
+
+    pub fn trace(&self) -> Option<TraceOptions> {
+        self.trace.as_ref().map(|path| TraceOptions {
+            path: path.clone(),
+            vars: self.trace_vars.clone(),
+        })
+    }
+}
+
+// Negative numbers are arguments of `eval` and friends rather than options
+fn is_option(arg: &str) -> bool {
+    arg.starts_with('-') && !arg[1..].starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn parse_variable(option: &str, value: String) -> Result<Var, String> {
+    parse_var(&value, 1).map_err(|_| format!("Invalid variable {} for {}", value, option))
+}
+
+fn parse_number<T: FromStr>(option: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value {} for {}", value, option))
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<(Command, Options), String> {
+    let mut options = Options::default();
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        if !is_option(&arg) {
+            positional.push(arg);
+            continue;
+        }
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing a value for {}", arg))
+        };
+        match arg.as_str() {
+            "-p" | "--protocol" => options.protocol = Some(value()?),
+            "-d" | "--data" => options.data_folder = Some(value()?.into()),
+            "-i" | "--include" => options.includes.push(value()?.into()),
+            "-o" | "--output" => options.output = Some(value()?.into()),
+            "-f" | "--format" => {
+                options.format = Some(match value()?.as_str() {
+                    "ascii" => FrameFormat::Ascii,
+                    "bmp" => FrameFormat::Bmp,
+                    f => return Err(format!("Unknown format {}", f)),
+                })
+            }
+            "--record" => options.record = Some(value()?.into()),
+            "--replay" => options.replay = Some(value()?.into()),
+            "-b" | "--backend" => {
+                options.backend = match value()?.as_str() {
+                    "interpreter" => Backend::Interpreter,
+                    "compiled" => Backend::Compiled,
+                    b => return Err(format!("Unknown backend {}", b)),
+                }
+            }
+            "-e" | "--evaluator" => {
+                options.evaluator = match value()?.as_str() {
+                    "graph" => EvaluatorKind::Graph,
+                    "closures" => EvaluatorKind::Closures,
+                    e => return Err(format!("Unknown evaluator {}", e)),
+                }
+            }
+            "--max-steps" => options.limits.steps = Some(parse_number(&arg, value()?)?),
+            "--max-nodes" => options.limits.nodes = Some(parse_number(&arg, value()?)?),
+            "--timeout" => {
+                let secs: f64 = parse_number(&arg, value()?)?;
+                if !(secs >= 0.0 && secs.is_finite()) {
+                    return Err(format!("Invalid value {} for {}", secs, arg));
+                }
+                options.limits.time = Some(Duration::from_secs_f64(secs));
+            }
+            "--trace" => options.trace = Some(value()?.into()),
+            "--trace-var" => options.trace_vars.push(parse_variable(&arg, value()?)?),
+            "--break" => options.breakpoints.push(parse_variable(&arg, value()?)?),
+            "-O" | "--optimize" => options.optimize = true,
+            "--memo" => options.memo = true,
+            "--profile" => options.profile = Some(value()?.into()),
+            "--profile-top" => options.profile_top = Some(parse_number(&arg, value()?)?),
+            "-s" | "--sugar" => options.sugar = true,
+            "-v" | "--verbose" => options.verbosity = Verbosity::Verbose,
+            "-q" | "--quiet" => options.verbosity = Verbosity::Quiet,
+            "-h" | "--help" => return Ok((Command::Help, options)),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    if !options.trace_vars.is_empty() && options.trace.is_none() {
+        return Err("--trace-var needs --trace".to_string());
+    }
+    if options.profile_top.is_some() && options.profile.is_none() {
+        return Err("--profile-top needs --profile".to_string());
+    }
+
+    let mut positional = positional.into_iter();
+    // Exploring galaxy is what the binary did without arguments before it had commands
+    let name = positional.next().unwrap_or_else(|| "explore".to_string());
+    let mut argument = |what: &str| {
+        positional
+            .next()
+            .ok_or_else(|| format!("Missing the {} to {}", what, name))
+    };
+    let command = match name.as_str() {
+        "explore" => match argument("file") {
+            Ok(file) => Command::Explore(file.into()),
+            Err(_) => Command::Explore("./data/i_galaxy.txt".into()),
+        },
+        "test" => Command::Test(argument("file")?.into()),
+        "script" => Command::Script(argument("file")?.into()),
+        "eval" => Command::Eval(argument("expression")?),
+        "debug" => Command::Debug(argument("expression")?),
+        "modulate" => Command::Modulate(argument("expression")?),
+        "demodulate" => Command::Demodulate(argument("signal")?),
+        "render" => Command::Render(argument("expression")?),
+        "replay" => Command::Replay(argument("log")?.into()),
+        "print" => Command::Print(argument("file")?.into()),
+        "decompile" => Command::Decompile(argument("file")?.into()),
+        "check" => Command::Check(argument("file")?.into()),
+        "repl" => Command::Repl,
+        "bench" => match argument("file") {
+            Ok(file) => Command::Bench(file.into()),
+            Err(_) => Command::Bench("./data/galaxy.txt".into()),
+        },
+        "diff" => match argument("file") {
+            Ok(file) => Command::Diff(file.into()),
+            Err(_) => Command::Diff("./data/test.txt".into()),
+        },
+        "help" => Command::Help,
+        _ => return Err(format!("Unknown command {}", name)),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument {}", extra));
+    }
+    Ok((command, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Command, Options), String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_commands() {
+        let (command, options) = parse(&[]).unwrap();
+        assert_eq!(command, Command::Explore("./data/i_galaxy.txt".into()));
+        assert_eq!(options, Options::default());
+
+        let (command, options) = parse(&["-q", "test", "data/test.txt"]).unwrap();
+        assert_eq!(command, Command::Test("data/test.txt".into()));
+        assert_eq!(options.verbosity, Verbosity::Quiet);
+
+        let (command, options) =
+            parse(&["eval", "-i", "a.txt", "ap inc -1", "--include", "b.txt"]).unwrap();
+        assert_eq!(command, Command::Eval("ap inc -1".to_string()));
+        assert_eq!(
+            options.includes,
+            vec![PathBuf::from("a.txt"), "b.txt".into()]
+        );
+
+        assert_eq!(
+            parse(&["eval", "-1"]).unwrap().0,
+            Command::Eval("-1".to_string())
+        );
+        assert_eq!(
+            parse(&["check", "data/galaxy.txt"]).unwrap().0,
+            Command::Check("data/galaxy.txt".into())
+        );
+        assert!(parse(&["-O", "eval", "1"]).unwrap().1.optimize);
+        assert!(parse(&["script", "a.txt", "--memo"]).unwrap().1.memo);
+        let (command, options) = parse(&["diff", "-e", "closures"]).unwrap();
+        assert_eq!(command, Command::Diff("./data/test.txt".into()));
+        assert_eq!(options.evaluator, EvaluatorKind::Closures);
+        let (_, options) = parse(&["test", "a.txt", "--profile", "out.folded"]).unwrap();
+        assert_eq!(options.profile, Some("out.folded".into()));
+        assert_eq!(options.profile_top, None);
+
+        let (_, options) =
+            parse(&["test", "a.txt", "--max-steps", "100", "--timeout", "0.5"]).unwrap();
+        assert_eq!(options.limits.steps, Some(100));
+        assert_eq!(options.limits.time, Some(Duration::from_millis(500)));
+        assert_eq!(options.limits.nodes, None);
+
+        let args = ["eval", "1", "--trace-var", ":1029", "--trace", "-"];
+        let trace = parse(&args).unwrap().1.trace().unwrap();
+        assert_eq!(trace.path, PathBuf::from("-"));
+        assert_eq!(trace.vars, vec![Var::Temp(1029)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse(&["frobnicate"]).is_err());
+        assert!(parse(&["test"]).is_err());
+        assert!(parse(&["test", "a.txt", "b.txt"]).is_err());
+        assert!(parse(&["script", "a.txt", "--format"]).is_err());
+        assert!(parse(&["script", "a.txt", "--format", "png"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["eval", "1", "--backend", "jit"]).is_err());
+        assert!(parse(&["eval", "1", "--evaluator", "jit"]).is_err());
+        assert!(parse(&["eval", "1", "--max-steps", "-1"]).is_err());
+        assert!(parse(&["eval", "1", "--timeout", "soon"]).is_err());
+        assert!(parse(&["eval", "1", "--trace-var", ":1"]).is_err());
+        assert!(parse(&["test", "a.txt", "--profile-top", "5"]).is_err());
+        assert!(parse(&["debug", "1", "--break", "1x"]).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use num_traits::Zero;
+
+use crate::eval::{pwr2, BuiltIn, EvalError};
+use crate::evaluator::{Data, Evaluator};
+use crate::printer::builtin_name;
+use crate::syntax::{Number, Stmt, Token, Var};
+
+// A second evaluator, kept to check the one of `eval` against: terms are trees of shared
+// values, built-ins are closures taking one argument at a time and every application
+// remembers what it evaluated to. It recurses on the native stack and has no limits, so it's
+// for small programs like the ones in tests. Pictures and `send` are left to `eval`.
+
+// What evaluating a value or applying a closure gives
+type Eval = Result<Rc<Value>, EvalError>;
+
+type Closure = Rc<dyn Fn(Rc<Value>, &Closures) -> Eval>;
+
+enum Value {
+    // A built-in, maybe applied to some of its arguments already
+    Lambda(Closure),
+    Var(Var),
+    Number(Number),
+    Nil,
+    True,
+    False,
+    Cons(Rc<Value>, Rc<Value>),
+    Application(Application),
+}
+
+struct Application {
+    f: Rc<Value>,
+    x: Rc<Value>,
+    result: RefCell<Option<Rc<Value>>>,
+}
+
+fn apply(f: Rc<Value>, x: Rc<Value>) -> Rc<Value> {
+    Rc::new(Value::Application(Application {
+        f,
+        x,
+        result: RefCell::new(None),
+    }))
+}
+
+fn number(n: Number) -> Rc<Value> {
+    Rc::new(Value::Number(n))
+}
+
+fn boolean(b: bool) -> Rc<Value> {
+    Rc::new(if b { Value::True } else { Value::False })
+}
+
+fn lambda(f: impl Fn(Rc<Value>, &Closures) -> Eval + 'static) -> Rc<Value> {
+    Rc::new(Value::Lambda(Rc::new(f)))
+}
+
+fn lambda2(f: impl Fn(Rc<Value>, Rc<Value>, &Closures) -> Eval + 'static) -> Rc<Value> {
+    let f = Rc::new(f);
+    lambda(move |x, _| {
+        let f = f.clone();
+        Ok(lambda(move |y, c| f(x.clone(), y, c)))
+    })
+}
+
+fn lambda3(f: impl Fn(Rc<Value>, Rc<Value>, Rc<Value>, &Closures) -> Eval + 'static) -> Rc<Value> {
+    let f = Rc::new(f);
+    lambda2(move |x, y, _| {
+        let f = f.clone();
+        Ok(lambda(move |z, c| f(x.clone(), y.clone(), z, c)))
+    })
+}
+
+fn unary(b: BuiltIn, f: fn(Number) -> Eval) -> Rc<Value> {
+    lambda(move |x, c| f(c.number(&x, b)?))
+}
+
+fn binary(b: BuiltIn, f: fn(Number, Number) -> Eval) -> Rc<Value> {
+    lambda2(move |x, y, c| f(c.number(&x, b)?, c.number(&y, b)?))
+}
+
+// The same rules as the interpreter of `eval`, a built-in at a time
+fn builtin(b: BuiltIn) -> Rc<Value> {
+    match b {
+        BuiltIn::True => Rc::new(Value::True),
+        BuiltIn::False => Rc::new(Value::False),
+        BuiltIn::Nil => Rc::new(Value::Nil),
+        BuiltIn::Inc => unary(b, |n| Ok(number(n + 1))),
+        BuiltIn::Dec => unary(b, |n| Ok(number(n - 1))),
+        BuiltIn::Neg => unary(b, |n| Ok(number(-n))),
+        BuiltIn::Pwr2 => unary(b, |n| Ok(number(pwr2(&n)?))),
+        BuiltIn::Add => binary(b, |x, y| Ok(number(x + y))),
+        BuiltIn::Mul => binary(b, |x, y| Ok(number(x * y))),
+        BuiltIn::Div => binary(b, |x, y| {
+            if y.is_zero() {
+                return Err(EvalError::DivisionByZero);
+            }
+            Ok(number(x / y))
+        }),
+        BuiltIn::Eq => binary(b, |x, y| Ok(boolean(x == y))),
+        BuiltIn::Lt => binary(b, |x, y| Ok(boolean(x < y))),
+        BuiltIn::I => lambda(|x, _| Ok(x)),
+        BuiltIn::S => lambda3(|x, y, z, _| Ok(apply(apply(x, z.clone()), apply(y, z)))),
+        BuiltIn::C => lambda3(|x, y, z, _| Ok(apply(apply(x, z), y))),
+        BuiltIn::B => lambda3(|x, y, z, _| Ok(apply(x, apply(y, z)))),
+        BuiltIn::Cons => lambda2(|x, y, _| Ok(Rc::new(Value::Cons(x, y)))),
+        BuiltIn::Head => lambda(|x, _| Ok(apply(x, boolean(true)))),
+        BuiltIn::Tail => lambda(|x, _| Ok(apply(x, boolean(false)))),
+        BuiltIn::IsNil => lambda(|x, _| {
+            let f = apply(boolean(true), boolean(false));
+            Ok(apply(x, apply(boolean(true), f)))
+        }),
+        BuiltIn::If0 => lambda3(|n, x, y, c| {
+            let zero = c.number(&n, BuiltIn::If0)?.is_zero();
+            Ok(if zero { x } else { y })
+        }),
+        _ => lambda(move |_, _| {
+            let msg = format!("`{}` is only run by the graph evaluator", builtin_name(b));
+            Err(EvalError::Stuck(msg))
+        }),
+    }
+}
+
+#[derive(Default)]
+pub struct Closures {
+    vars: HashMap<Var, Rc<Value>>,
+}
+
+impl Closures {
+    pub fn new() -> Self {
+        Closures::default()
+    }
+
+    // Evaluates `val` until it's a number, a list, a boolean or a function
+    fn eval(&self, val: &Rc<Value>) -> Eval {
+        match &**val {
+            Value::Application(app) => {
+                if let Some(result) = app.result.borrow().as_ref() {
+                    return Ok(result.clone());
+                }
+                let f = self.eval(&app.f)?;
+                let result = self.eval(&self.call(&f, app.x.clone())?)?;
+                *app.result.borrow_mut() = Some(result.clone());
+                Ok(result)
+            }
+            Value::Var(v) => match self.vars.get(v) {
+                Some(def) => self.eval(def),
+                None => Err(EvalError::UnboundVariable(v.clone())),
+            },
+            _ => Ok(val.clone()),
+        }
+    }
+
+    // Applies the evaluated `f` to `x`
+    fn call(&self, f: &Value, x: Rc<Value>) -> Eval {
+        match f {
+            Value::Lambda(f) => f(x, self),
+            Value::Cons(head, tail) => Ok(apply(apply(x, head.clone()), tail.clone())),
+            Value::Nil => Ok(boolean(true)),
+            Value::True => Ok(lambda(move |_, _| Ok(x.clone()))),
+            Value::False => Ok(lambda(|y, _| Ok(y))),
+            Value::Number(_) => Err(EvalError::Arity("a number can't be applied".to_string())),
+            Value::Application(_) | Value::Var(_) => unreachable!("`f` is evaluated"),
+        }
+    }
+
+    fn number(&self, val: &Rc<Value>, builtin: BuiltIn) -> Result<Number, EvalError> {
+        match &*self.eval(val)? {
+            Value::Number(n) => Ok(n.clone()),
+            _ => Err(EvalError::TypeMismatch {
+                builtin,
+                expected: "number",
+            }),
+        }
+    }
+
+    fn data(&self, val: &Rc<Value>) -> Result<Data, EvalError> {
+        // Long lists are walked along their tails in a loop, only the heads recurse
+        let mut heads = vec![];
+        let mut curr = self.eval(val)?;
+        let last = loop {
+            curr = match &*curr {
+                Value::Cons(head, tail) => {
+                    heads.push(self.data(head)?);
+                    self.eval(tail)?
+                }
+                Value::Number(n) => break Data::Number(n.clone()),
+                Value::Nil => break Data::Nil,
+                Value::True => break Data::Bool(true),
+                Value::False => break Data::Bool(false),
+                _ => break Data::Function,
+            };
+        };
+        Ok(Data::list(heads, last))
+    }
+}
+
+impl Evaluator for Closures {
+    fn interpret(&mut self, stmt: Stmt) -> Result<(), EvalError> {
+        let Stmt { var, code } = stmt;
+        let unbalanced =
+            || EvalError::Arity(format!("unbalanced `ap` in the definition of {:?}", var));
+        let mut stack = vec![];
+        for token in code.into_iter().rev() {
+            let val = match token {
+                Token::Var(v) => Rc::new(Value::Var(v)),
+                Token::Number(n) => number(n),
+                Token::Ap => {
+                    let f = stack.pop().ok_or_else(unbalanced)?;
+                    let x = stack.pop().ok_or_else(unbalanced)?;
+                    apply(f, x)
+                }
+                token => builtin(BuiltIn::from_token(&token).ok_or_else(unbalanced)?),
+            };
+            stack.push(val);
+        }
+        match (stack.pop(), stack.is_empty()) {
+            (Some(val), true) => {
+                self.vars.insert(var, val);
+                Ok(())
+            }
+            _ => Err(unbalanced()),
+        }
+    }
+
+    fn eval_data(&mut self, var: &Var) -> Result<Data, EvalError> {
+        let val = Rc::new(Value::Var(var.clone()));
+        self.data(&val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::parse_line;
+
+    fn eval(lines: &[&str]) -> Result<Data, EvalError> {
+        let mut closures = Closures::new();
+        for line in lines {
+            closures.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        closures.eval_data(&Var::Named("x".to_string()))
+    }
+
+    #[test]
+    fn test_eval() {
+        let n = |n: i64| Data::Number(n.into());
+        assert_eq!(eval(&["x = ap ap ap s mul ap add 1 6"]), Ok(n(42)));
+        assert_eq!(eval(&["x = ap car ap cdr y", "y = ( 1 , 2 )"]), Ok(n(2)));
+        assert_eq!(eval(&["x = ap isnil ap cdr ( 1 )"]), Ok(Data::Bool(true)));
+        assert_eq!(eval(&["x = ap ap t ap inc 5 ap ap div 1 0"]), Ok(n(6)));
+        assert_eq!(
+            eval(&["x = ap ap cons 1 nil"]),
+            Ok(Data::list(vec![n(1)], Data::Nil))
+        );
+        assert_eq!(eval(&["x = ap add 1"]), Ok(Data::Function));
+        assert_eq!(eval(&["x = ap ap div 1 0"]), Err(EvalError::DivisionByZero));
+        assert_eq!(
+            eval(&["x = ap inc y"]),
+            Err(EvalError::UnboundVariable(Var::Named("y".to_string())))
+        );
+        assert!(eval(&["x = ap draw nil"]).is_err());
+    }
+}
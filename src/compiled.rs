@@ -0,0 +1,351 @@
+use std::rc::Rc;
+
+use crate::decompile::{decompile_term, Term};
+use crate::eval::*;
+use crate::printer::var_name;
+use crate::syntax::Var;
+
+// The compiled backend turns every definition into a supercombinator: the decompiler
+// recovers its parameters, and the body becomes a closure that builds the graph of the body
+// for the given arguments. `ap ap :1131 x y` then takes one step instead of dozens of S, B and
+// C rewrites. Lambdas inside the body are lifted out into supercombinators of their own.
+
+type Code = Box<dyn Fn(&mut Arena, &[Value]) -> Value>;
+
+pub struct Supercombinator {
+    pub arity: usize,
+    // The same function as combinators, for when it is applied to fewer arguments
+    pub value: Value,
+    // The terms `code` shares between calls
+    constants: Vec<Value>,
+    code: Code,
+}
+
+impl Supercombinator {
+    // `args` must hold exactly `arity` values
+    pub fn instantiate(&self, arena: &mut Arena, args: &[Value]) -> Value {
+        (self.code)(arena, args)
+    }
+
+    // What has to survive a collection for this to keep working
+    pub fn roots(&self) -> impl Iterator<Item = Value> + '_ {
+        std::iter::once(self.value).chain(self.constants.iter().copied())
+    }
+}
+
+fn occurs(x: usize, term: &Term) -> bool {
+    match term {
+        Term::Bound(y) => x == *y,
+        Term::Apply(f, a) => occurs(x, f) || occurs(x, a),
+        Term::Lambda(y, body) => x != *y && occurs(x, body),
+        Term::Let(y, value, body) => occurs(x, value) || (x != *y && occurs(x, body)),
+        Term::List(items) => items.iter().any(|i| occurs(x, i)),
+        Term::Global(_) | Term::Number(_) | Term::BuiltIn(_) => false,
+    }
+}
+
+fn apply(f: Term, x: Term) -> Term {
+    Term::Apply(Box::new(f), Box::new(x))
+}
+
+// Bracket abstraction, the same as `syntax` does for `def` lines
+fn abstract_var(x: usize, term: Term) -> Term {
+    if !occurs(x, &term) {
+        return apply(Term::BuiltIn(BuiltIn::True), term);
+    }
+    match term {
+        Term::Bound(_) => Term::BuiltIn(BuiltIn::I),
+        Term::Apply(f, a) => match (occurs(x, &f), occurs(x, &a)) {
+            (false, _) if *a == Term::Bound(x) => *f,
+            (true, false) => apply(apply(Term::BuiltIn(BuiltIn::C), abstract_var(x, *f)), *a),
+            (false, _) => apply(apply(Term::BuiltIn(BuiltIn::B), *f), abstract_var(x, *a)),
+            (true, true) => apply(
+                apply(Term::BuiltIn(BuiltIn::S), abstract_var(x, *f)),
+                abstract_var(x, *a),
+            ),
+        },
+        Term::List(mut items) => {
+            let first = items.remove(0);
+            let cons = apply(
+                apply(Term::BuiltIn(BuiltIn::Cons), first),
+                Term::List(items),
+            );
+            abstract_var(x, cons)
+        }
+        Term::Let(y, value, body) => abstract_var(x, apply(abstract_var(y, *body), *value)),
+        _ => unreachable!(),
+    }
+}
+
+fn remove_lambdas(term: Term) -> Term {
+    match term {
+        Term::Lambda(x, body) => abstract_var(x, remove_lambdas(*body)),
+        Term::Apply(f, a) => apply(remove_lambdas(*f), remove_lambdas(*a)),
+        Term::List(items) => Term::List(items.into_iter().map(remove_lambdas).collect()),
+        Term::Let(x, value, body) => Term::Let(
+            x,
+            Box::new(remove_lambdas(*value)),
+            Box::new(remove_lambdas(*body)),
+        ),
+        term => term,
+    }
+}
+
+fn to_value(arena: &mut Arena, term: &Term) -> Value {
+    match term {
+        Term::Global(v) => arena.var(v.clone()),
+        Term::Number(n) => arena.number(n.clone()),
+        Term::BuiltIn(builtin) => b(*builtin),
+        Term::Apply(f, x) => {
+            let f = to_value(arena, f);
+            let x = to_value(arena, x);
+            arena.ap(f, x)
+        }
+        Term::List(items) => items.iter().rev().fold(b(BuiltIn::Nil), |tail, item| {
+            let item = to_value(arena, item);
+            let cons = arena.ap(b(BuiltIn::Cons), item);
+            arena.ap(cons, tail)
+        }),
+        Term::Let(x, value, body) => {
+            let term = apply(abstract_var(*x, (**body).clone()), (**value).clone());
+            to_value(arena, &term)
+        }
+        Term::Bound(_) | Term::Lambda(..) => unreachable!(),
+    }
+}
+
+// `params[i]` is the variable bound to the `i`th argument. Terms built once go in `constants`.
+fn compile_body(
+    term: Term,
+    params: &[usize],
+    arena: &mut Arena,
+    constants: &mut Vec<Value>,
+) -> Code {
+    match term {
+        Term::Bound(x) => {
+            let i = params.iter().position(|p| *p == x).unwrap();
+            Box::new(move |_, args| args[i])
+        }
+        term if !params.iter().any(|p| occurs(*p, &term)) => {
+            // Closed sub-terms are built once and shared, like the definitions themselves
+            let val = to_value(arena, &term);
+            constants.push(val);
+            Box::new(move |_, _| val)
+        }
+        Term::Apply(f, x) => {
+            let f = match *f {
+                // A call gets its own head, as evaluating a shared one would replace the
+                // variable that `instantiate` looks for
+                Term::Global(v) => {
+                    Box::new(move |arena: &mut Arena, _: &[Value]| arena.var(v.clone()))
+                }
+                f => compile_body(f, params, arena, constants),
+            };
+            let x = compile_body(*x, params, arena, constants);
+            Box::new(move |arena, args| {
+                let f = f(arena, args);
+                let x = x(arena, args);
+                arena.ap(f, x)
+            })
+        }
+        Term::List(items) => {
+            let items: Vec<Code> = items
+                .into_iter()
+                .map(|i| compile_body(i, params, arena, constants))
+                .collect();
+            Box::new(move |arena, args| {
+                items.iter().rev().fold(b(BuiltIn::Nil), |tail, item| {
+                    let item = item(arena, args);
+                    let cons = arena.ap(b(BuiltIn::Cons), item);
+                    arena.ap(cons, tail)
+                })
+            })
+        }
+        Term::Let(x, value, body) => {
+            // The value is built once and every use in the body points to it
+            let value = compile_body(*value, params, arena, constants);
+            let mut inner = params.to_vec();
+            inner.push(x);
+            let body = compile_body(*body, &inner, arena, constants);
+            Box::new(move |arena, args| {
+                let mut env = args.to_vec();
+                env.push(value(arena, args));
+                body(arena, &env)
+            })
+        }
+        _ => unreachable!(),
+    }
+}
+
+// The variables a term uses without binding them, in the order they first occur
+fn free_vars(term: &Term, bound: &mut Vec<usize>, free: &mut Vec<usize>) {
+    match term {
+        Term::Bound(x) => {
+            if !bound.contains(x) && !free.contains(x) {
+                free.push(*x);
+            }
+        }
+        Term::Apply(f, a) => {
+            free_vars(f, bound, free);
+            free_vars(a, bound, free);
+        }
+        Term::List(items) => items.iter().for_each(|i| free_vars(i, bound, free)),
+        Term::Lambda(x, body) => {
+            bound.push(*x);
+            free_vars(body, bound, free);
+            bound.pop();
+        }
+        Term::Let(x, value, body) => {
+            free_vars(value, bound, free);
+            bound.push(*x);
+            free_vars(body, bound, free);
+            bound.pop();
+        }
+        Term::Global(_) | Term::Number(_) | Term::BuiltIn(_) => {}
+    }
+}
+
+// Lambda lifting. Every lambda becomes a supercombinator that takes the variables it uses
+// from outside before its own parameters, and is named after the definition with a `#`,
+// which no source can write.
+struct Lifter {
+    name: String,
+    lifted: Vec<(Var, Vec<usize>, Term)>,
+}
+
+impl Lifter {
+    fn lift(&mut self, term: Term) -> Term {
+        match term {
+            Term::Lambda(..) => {
+                let mut params = vec![];
+                let mut body = term;
+                while let Term::Lambda(x, b) = body {
+                    params.push(x);
+                    body = *b;
+                }
+                let body = self.lift(body);
+                let mut free = vec![];
+                free_vars(&body, &mut params.clone(), &mut free);
+                let var = Var::Named(format!("{}#{}", self.name, self.lifted.len()));
+                let head = free
+                    .iter()
+                    .fold(Term::Global(var.clone()), |f, x| apply(f, Term::Bound(*x)));
+                free.extend(params);
+                self.lifted.push((var, free, body));
+                head
+            }
+            Term::Apply(f, a) => apply(self.lift(*f), self.lift(*a)),
+            Term::List(items) => Term::List(items.into_iter().map(|i| self.lift(i)).collect()),
+            Term::Let(x, value, body) => {
+                Term::Let(x, Box::new(self.lift(*value)), Box::new(self.lift(*body)))
+            }
+            term => term,
+        }
+    }
+}
+
+fn supercombinator(
+    arena: &mut Arena,
+    params: Vec<usize>,
+    body: Term,
+    value: Value,
+) -> Rc<Supercombinator> {
+    let mut constants = vec![];
+    let code = compile_body(body, &params, arena, &mut constants);
+    Rc::new(Supercombinator {
+        arity: params.len(),
+        value,
+        constants,
+        code,
+    })
+}
+
+// The supercombinators of a definition and the lambdas inside it. Definitions that don't take
+// arguments are left to the interpreter.
+pub fn compile(arena: &mut Arena, var: &Var, val: Value) -> Vec<(Var, Rc<Supercombinator>)> {
+    let mut term = decompile_term(arena, val);
+    let mut params = vec![];
+    while let Term::Lambda(x, body) = term {
+        params.push(x);
+        term = *body;
+    }
+    if params.is_empty() {
+        return vec![];
+    }
+    let mut lifter = Lifter {
+        name: var_name(var),
+        lifted: vec![],
+    };
+    let body = lifter.lift(term);
+    let mut compiled = vec![(var.clone(), supercombinator(arena, params, body, val))];
+    for (var, params, body) in lifter.lifted {
+        let lambda = params
+            .iter()
+            .rev()
+            .fold(body.clone(), |body, x| Term::Lambda(*x, Box::new(body)));
+        let value = to_value(arena, &remove_lambdas(lambda));
+        compiled.push((var, supercombinator(arena, params, body, value)));
+    }
+    compiled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::differential::assert_same_frames;
+    use crate::syntax::{parse_file, parse_line};
+
+    fn load(text: &str, backend: Backend) -> State {
+        let mut state = State::new();
+        for stmt in parse_file("test", text).unwrap() {
+            state.interpret(stmt).unwrap();
+        }
+        state.set_backend(backend);
+        state
+    }
+
+    fn compile_line(line: &str) -> Vec<(Var, Rc<Supercombinator>)> {
+        let mut state = State::new();
+        let stmt = parse_line(line).unwrap();
+        let var = stmt.var.clone();
+        state.interpret(stmt).unwrap();
+        let val = state.definition(&var).unwrap();
+        let compiled = compile(&mut state.arena_mut(), &var, val);
+        compiled
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiled = compile_line("def twice f x = f (f x)");
+        assert_eq!(compiled.len(), 1);
+        assert_eq!(compiled[0].1.arity, 2);
+
+        // The lambda is lifted out, taking `y` before its own parameter
+        let compiled = compile_line("def twice y = [\\x -> add x y]");
+        let names: Vec<String> = compiled.iter().map(|(v, _)| var_name(v)).collect();
+        assert_eq!(names, ["twice", "twice#0"]);
+        assert_eq!(compiled[1].1.arity, 2);
+    }
+
+    #[test]
+    fn test_compiled_backend() {
+        let text = "def twice f x = f (f x)\n\
+                    def sum xs = if isnil xs then 0 else add (car xs) (sum (cdr xs))\n\
+                    def adder y = \\x -> add x y\n\
+                    x = ap ap twice ap adder 3 ap sum ( 1 , 2 , 3 )";
+        let x = Var::Named("x".to_string());
+        for &backend in [Backend::Interpreter, Backend::Compiled].iter() {
+            let state = load(text, backend);
+            let val = state.eval_v(&x).unwrap();
+            assert_eq!(*state.arena().get(val), Value_::Number(12.into()));
+        }
+    }
+
+    #[test]
+    fn test_galaxy_frames() {
+        let text = include_str!("../data/galaxy.txt");
+        let interpreter = load(text, Backend::Interpreter);
+        let compiled = load(text, Backend::Compiled);
+        assert_same_frames(&interpreter, &compiled, &[(0, 0), (0, 0), (8, 4), (2, -8)]);
+    }
+}
@@ -0,0 +1,675 @@
+use std::collections::HashSet;
+
+use crate::eval::*;
+use crate::printer::{builtin_name, var_name};
+use crate::syntax::*;
+use crate::types::Picture;
+
+// Turns combinator definitions back into lambda terms. Every combinator that is short of
+// arguments gets fresh variables until its rule fires, so `ap ap b inc inc` becomes
+// `\x0 -> inc (inc x0)`. Definitions are never unfolded, only the combinators are reduced.
+
+// Galaxy needs a few hundred rewrites for its largest definition, anything that takes more
+// is likely looping and is printed as it is from there on
+const FUEL: usize = 100_000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Term {
+    // A variable bound by a lambda
+    Bound(usize),
+    Global(Var),
+    Number(Number),
+    BuiltIn(BuiltIn),
+    Apply(Box<Term>, Box<Term>),
+    Lambda(usize, Box<Term>),
+    // Keeps an argument that S would copy shared, the variable is bound in the body
+    Let(usize, Box<Term>, Box<Term>),
+    // A `cons` chain ending in `nil`. Galaxy has lists of thousands of numbers, as a chain
+    // they would take that deep a recursion to walk.
+    List(Vec<Term>),
+}
+
+fn apply(f: Term, x: Term) -> Term {
+    Term::Apply(Box::new(f), Box::new(x))
+}
+
+fn apply_all(f: Term, args: Vec<Term>) -> Term {
+    args.into_iter().fold(f, apply)
+}
+
+// The items of a non-empty `cons` chain ending in `nil`. The cells of a chain that doesn't
+// end in `nil` go in `not_lists`, so its tails aren't walked to the end again.
+fn list_values(arena: &Arena, val: Value, not_lists: &mut HashSet<Value>) -> Option<Vec<Value>> {
+    let mut items = vec![];
+    let mut cells = vec![];
+    let mut curr = val;
+    while !not_lists.contains(&curr) {
+        curr = match arena.get(curr) {
+            Value_::BuiltIn(BuiltIn::Nil) if !items.is_empty() => return Some(items),
+            Value_::Apply(f, tail) => match arena.get(*f) {
+                Value_::Apply(cons, head)
+                    if *arena.get(*cons) == Value_::BuiltIn(BuiltIn::Cons) =>
+                {
+                    items.push(*head);
+                    cells.push(curr);
+                    *tail
+                }
+                _ => break,
+            },
+            _ => break,
+        };
+    }
+    not_lists.extend(cells);
+    None
+}
+
+// Pictures have no syntax of their own, like in the printer they are the `draw` that makes them
+fn picture_term(p: &Picture) -> Term {
+    let points: Vec<Term> = (p.points.iter())
+        .map(|point| {
+            let (x, y) = (Term::Number(point.x.into()), Term::Number(point.y.into()));
+            apply(apply(Term::BuiltIn(BuiltIn::Cons), x), y)
+        })
+        .collect();
+    let points = if points.is_empty() {
+        Term::BuiltIn(BuiltIn::Nil)
+    } else {
+        Term::List(points)
+    };
+    apply(Term::BuiltIn(BuiltIn::Draw), points)
+}
+
+enum Item {
+    Visit(Value),
+    // The function and the argument are the last two terms made
+    Apply,
+    // The items are the last terms made
+    List(usize),
+}
+
+fn from_value(arena: &Arena, root: Value) -> Term {
+    // Terms nest as deep as evaluation allows, so they are walked with an explicit stack
+    let mut stack = vec![Item::Visit(root)];
+    let mut done = vec![];
+    let mut not_lists = HashSet::new();
+    while let Some(item) = stack.pop() {
+        match item {
+            Item::Visit(val) => {
+                if let Some(items) = list_values(arena, val, &mut not_lists) {
+                    stack.push(Item::List(items.len()));
+                    stack.extend(items.into_iter().rev().map(Item::Visit));
+                    continue;
+                }
+                match arena.get(val) {
+                    Value_::Var(v) => done.push(Term::Global(v.clone())),
+                    Value_::Number(n) => done.push(Term::Number(n.clone())),
+                    Value_::BuiltIn(b) => done.push(Term::BuiltIn(*b)),
+                    Value_::Apply(f, x) => {
+                        stack.push(Item::Apply);
+                        stack.push(Item::Visit(*x));
+                        stack.push(Item::Visit(*f));
+                    }
+                    // A definition that was evaluated to a picture
+                    Value_::Picture(p) => done.push(picture_term(p)),
+                }
+            }
+            Item::Apply => {
+                let x = done.pop().unwrap();
+                let f = done.pop().unwrap();
+                done.push(apply(f, x));
+            }
+            Item::List(n) => {
+                let items = done.split_off(done.len() - n);
+                done.push(Term::List(items));
+            }
+        }
+    }
+    done.pop().unwrap()
+}
+
+// Dropping a term recurses as deep as it nests, so deep ones are taken apart a level at a time
+fn release(term: Term) {
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+        match term {
+            Term::Apply(f, x) => {
+                stack.push(*f);
+                stack.push(*x);
+            }
+            Term::Lambda(_, body) => stack.push(*body),
+            Term::Let(_, value, body) => {
+                stack.push(*value);
+                stack.push(*body);
+            }
+            Term::List(items) => stack.extend(items),
+            Term::Bound(_) | Term::Global(_) | Term::Number(_) | Term::BuiltIn(_) => {}
+        }
+    }
+}
+
+// The head of an application and its arguments, in order
+fn unwind(term: Term) -> (Term, Vec<Term>) {
+    let mut args = vec![];
+    let mut head = term;
+    while let Term::Apply(f, x) = head {
+        args.push(*x);
+        head = *f;
+    }
+    args.reverse();
+    (head, args)
+}
+
+// Built-ins whose rules only move their arguments around
+fn is_combinator(b: BuiltIn) -> bool {
+    matches!(
+        b,
+        BuiltIn::S
+            | BuiltIn::C
+            | BuiltIn::B
+            | BuiltIn::I
+            | BuiltIn::True
+            | BuiltIn::False
+            | BuiltIn::Cons
+            | BuiltIn::Nil
+    )
+}
+
+// Whether a combinator applied to `args` arguments is better read as a lambda. Booleans,
+// pairs and `nil` mean something on their own.
+fn wants_lambda(b: BuiltIn, args: usize) -> bool {
+    match b {
+        BuiltIn::S | BuiltIn::C | BuiltIn::B | BuiltIn::I => true,
+        BuiltIn::True | BuiltIn::False => args == 1,
+        _ => false,
+    }
+}
+
+struct Decompiler {
+    next_var: usize,
+    fuel: usize,
+}
+
+// A term with the combinators at its head reduced: the lambdas and `let`s around it and what
+// is left applied
+struct Reduced {
+    head: Term,
+    args: Vec<Term>,
+    params: Vec<usize>,
+    lets: Vec<(usize, Term)>,
+}
+
+enum Work {
+    Visit(Term),
+    // The normalized items of a list head, arguments and `let` values are the last terms made
+    Build {
+        // None for a list
+        head: Option<Term>,
+        items: usize,
+        args: usize,
+        params: Vec<usize>,
+        lets: Vec<usize>,
+    },
+}
+
+impl Decompiler {
+    fn fresh(&mut self) -> usize {
+        self.next_var += 1;
+        self.next_var - 1
+    }
+
+    // Reduces the combinators at the head of a term, the parts it's left with are normalized
+    // on their own
+    fn reduce(&mut self, term: Term) -> Reduced {
+        let (mut head, mut args) = unwind(term);
+        let mut params = vec![];
+        let mut lets = vec![];
+        loop {
+            // Applied lists act as the `cons` they are made of
+            if let Term::List(items) = &mut head {
+                if args.is_empty() {
+                    break;
+                }
+                head = match items.len() {
+                    0 => Term::BuiltIn(BuiltIn::Nil),
+                    _ => {
+                        let first = items.remove(0);
+                        let rest = Term::List(std::mem::take(items));
+                        apply(apply(Term::BuiltIn(BuiltIn::Cons), first), rest)
+                    }
+                };
+                let (h, mut new_args) = unwind(head);
+                new_args.append(&mut args);
+                head = h;
+                args = new_args;
+            }
+            let b = match head {
+                Term::BuiltIn(b) if is_combinator(b) && self.fuel > 0 => b,
+                _ => break,
+            };
+            if args.len() < b.arity() {
+                if !wants_lambda(b, args.len()) {
+                    break;
+                }
+                let x = self.fresh();
+                params.push(x);
+                args.push(Term::Bound(x));
+                continue;
+            }
+            self.fuel -= 1;
+            let mut rest = args.split_off(b.arity()).into_iter();
+            let mut taken = args.into_iter();
+            let mut arg = || taken.next().unwrap();
+            let new_head = match b {
+                BuiltIn::I => arg(),
+                BuiltIn::True => {
+                    let x = arg();
+                    release(arg());
+                    x
+                }
+                BuiltIn::False => {
+                    release(arg());
+                    arg()
+                }
+                BuiltIn::Nil => {
+                    release(arg());
+                    Term::BuiltIn(BuiltIn::True)
+                }
+                BuiltIn::S => {
+                    let (x, y, mut z) = (arg(), arg(), arg());
+                    // Copying anything bigger than a name would evaluate it twice
+                    if let Term::Apply(..) | Term::List(_) = z {
+                        let v = self.fresh();
+                        lets.push((v, z));
+                        z = Term::Bound(v);
+                    }
+                    apply(apply(x, z.clone()), apply(y, z))
+                }
+                BuiltIn::B => {
+                    let (x, y, z) = (arg(), arg(), arg());
+                    apply(x, apply(y, z))
+                }
+                BuiltIn::C => {
+                    let (x, y, z) = (arg(), arg(), arg());
+                    apply(apply(x, z), y)
+                }
+                BuiltIn::Cons => {
+                    let (x, y, z) = (arg(), arg(), arg());
+                    apply(apply(z, x), y)
+                }
+                _ => unreachable!(),
+            };
+            let (h, mut new_args) = unwind(new_head);
+            new_args.extend(&mut rest);
+            head = h;
+            args = new_args;
+        }
+        Reduced {
+            head,
+            args,
+            params,
+            lets,
+        }
+    }
+
+    fn normalize(&mut self, term: Term) -> Term {
+        // Like `from_value`, with an explicit stack. The parts are normalized in the order
+        // they are made, which is the order their fresh variables are numbered in.
+        let mut work = vec![Work::Visit(term)];
+        let mut done = vec![];
+        while let Some(w) = work.pop() {
+            match w {
+                Work::Visit(term) => {
+                    let Reduced {
+                        head,
+                        args,
+                        params,
+                        lets,
+                    } = self.reduce(term);
+                    let mut parts = vec![];
+                    let (head, items) = match head {
+                        Term::List(items) => {
+                            let n = items.len();
+                            parts.extend(items);
+                            (None, n)
+                        }
+                        head => (Some(head), 0),
+                    };
+                    let n_args = args.len();
+                    parts.extend(args);
+                    // The last `let` is the innermost
+                    let (lets, values): (Vec<usize>, Vec<Term>) = lets.into_iter().rev().unzip();
+                    parts.extend(values);
+                    work.push(Work::Build {
+                        head,
+                        items,
+                        args: n_args,
+                        params,
+                        lets,
+                    });
+                    work.extend(parts.into_iter().rev().map(Work::Visit));
+                }
+                Work::Build {
+                    head,
+                    items,
+                    args,
+                    params,
+                    lets,
+                } => {
+                    let values = done.split_off(done.len() - lets.len());
+                    let args = done.split_off(done.len() - args);
+                    let items = done.split_off(done.len() - items);
+                    let mut body = apply_all(head.unwrap_or(Term::List(items)), args);
+                    for (v, value) in lets.into_iter().zip(values) {
+                        body = Term::Let(v, Box::new(value), Box::new(body));
+                    }
+                    let term = (params.into_iter().rev())
+                        .fold(body, |body, x| Term::Lambda(x, Box::new(body)));
+                    done.push(term);
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+}
+
+pub fn decompile_term(arena: &Arena, val: Value) -> Term {
+    let mut decompiler = Decompiler {
+        next_var: 0,
+        fuel: FUEL,
+    };
+    decompiler.normalize(from_value(arena, val))
+}
+
+// Terms known not to be lists, by address, see `list_values`
+type NotLists = HashSet<*const Term>;
+
+// Items of a `cons` chain ending in `nil`
+fn list_items<'a>(term: &'a Term, not_lists: &mut NotLists) -> Option<Vec<&'a Term>> {
+    let mut items = vec![];
+    let mut cells = vec![];
+    let mut curr = term;
+    while !not_lists.contains(&(curr as *const Term)) {
+        let (f, tail) = match curr {
+            Term::BuiltIn(BuiltIn::Nil) => return Some(items),
+            Term::List(rest) => {
+                items.extend(rest);
+                return Some(items);
+            }
+            Term::Apply(f, tail) => (f, tail),
+            _ => break,
+        };
+        match &**f {
+            Term::Apply(cons, head) if **cons == Term::BuiltIn(BuiltIn::Cons) => {
+                items.push(&**head)
+            }
+            _ => break,
+        }
+        cells.push(curr as *const Term);
+        curr = tail;
+    }
+    not_lists.extend(cells);
+    None
+}
+
+// The head and arguments of an application, without taking it apart
+fn spine(term: &Term) -> (&Term, Vec<&Term>) {
+    let mut args = vec![];
+    let mut head = term;
+    while let Term::Apply(f, x) = head {
+        args.push(&**x);
+        head = f;
+    }
+    args.reverse();
+    (head, args)
+}
+
+// Booleans select between their next two arguments, so a comparison applied to them reads
+// as an `if`
+fn condition(head: &Term, args: usize) -> Option<BuiltIn> {
+    match head {
+        Term::BuiltIn(b @ BuiltIn::Eq)
+        | Term::BuiltIn(b @ BuiltIn::Lt)
+        | Term::BuiltIn(b @ BuiltIn::IsNil)
+            if args >= b.arity() + 2 =>
+        {
+            Some(*b)
+        }
+        _ => None,
+    }
+}
+
+// Whether `show` of the term starts with a list, which needs no parentheses as an argument
+fn starts_with_list(term: &Term, not_lists: &mut NotLists) -> bool {
+    let mut curr = term;
+    loop {
+        if list_items(curr, not_lists).is_some() {
+            return true;
+        }
+        if !matches!(curr, Term::Apply(..)) {
+            return false;
+        }
+        let (head, args) = spine(curr);
+        if condition(head, args.len()).is_some() || matches!(head, Term::Lambda(..) | Term::Let(..))
+        {
+            return false;
+        }
+        curr = head;
+    }
+}
+
+enum Piece<'a> {
+    Text(String),
+    Term(&'a Term),
+    // Anything but a name or a literal needs parentheses as an argument
+    Arg(&'a Term),
+}
+
+pub fn show(term: &Term) -> String {
+    // Terms nest as deep as evaluation allows, so they are printed with an explicit stack
+    let mut out = String::new();
+    let mut stack = vec![Piece::Term(term)];
+    let mut not_lists = NotLists::new();
+    while let Some(piece) = stack.pop() {
+        let term = match piece {
+            Piece::Text(text) => {
+                out.push_str(&text);
+                continue;
+            }
+            Piece::Arg(term) => {
+                match term {
+                    Term::Apply(..) | Term::Lambda(..) | Term::Let(..)
+                        if !starts_with_list(term, &mut not_lists) =>
+                    {
+                        out.push('(');
+                        stack.push(Piece::Text(")".to_string()));
+                    }
+                    _ => {}
+                }
+                stack.push(Piece::Term(term));
+                continue;
+            }
+            Piece::Term(term) => term,
+        };
+        // In the order they are printed
+        let mut pieces = vec![];
+        let text = |t: &str| Piece::Text(t.to_string());
+        if let Some(items) = list_items(term, &mut not_lists) {
+            pieces.push(text("["));
+            for (i, item) in items.into_iter().enumerate() {
+                if i > 0 {
+                    pieces.push(text(", "));
+                }
+                pieces.push(Piece::Term(item));
+            }
+            pieces.push(text("]"));
+        } else {
+            match term {
+                Term::Bound(x) => out.push_str(&format!("x{}", x)),
+                Term::Global(v) => out.push_str(&var_name(v)),
+                Term::Number(n) => out.push_str(&n.to_string()),
+                Term::BuiltIn(b) => out.push_str(builtin_name(*b)),
+                Term::List(_) => unreachable!(),
+                Term::Lambda(..) => {
+                    let (params, body) = lambdas(term);
+                    pieces.push(Piece::Text(format!("\\{} -> ", params.join(" "))));
+                    pieces.push(Piece::Term(body));
+                }
+                Term::Let(x, value, body) => {
+                    pieces.push(Piece::Text(format!("let x{} = ", x)));
+                    pieces.push(Piece::Term(value));
+                    pieces.push(text(" in "));
+                    pieces.push(Piece::Term(body));
+                }
+                Term::Apply(..) => {
+                    let (head, args) = spine(term);
+                    let mut rest = &args[..];
+                    if let Some(b) = condition(head, args.len()) {
+                        let n = b.arity();
+                        let wrapped = args.len() > n + 2;
+                        if wrapped {
+                            pieces.push(text("("));
+                        }
+                        pieces.push(Piece::Text(format!("if {}", builtin_name(b))));
+                        for arg in &args[..n] {
+                            pieces.push(text(" "));
+                            pieces.push(Piece::Arg(arg));
+                        }
+                        pieces.push(text(" then "));
+                        pieces.push(Piece::Term(args[n]));
+                        pieces.push(text(" else "));
+                        pieces.push(Piece::Term(args[n + 1]));
+                        if wrapped {
+                            pieces.push(text(")"));
+                        }
+                        rest = &args[n + 2..];
+                    } else if let Term::Lambda(..) | Term::Let(..) = head {
+                        pieces.push(text("("));
+                        pieces.push(Piece::Term(head));
+                        pieces.push(text(")"));
+                    } else {
+                        pieces.push(Piece::Term(head));
+                    }
+                    for arg in rest {
+                        pieces.push(text(" "));
+                        pieces.push(Piece::Arg(arg));
+                    }
+                }
+            }
+        }
+        stack.extend(pieces.into_iter().rev());
+    }
+    out
+}
+
+// The parameters of nested lambdas and the body inside them
+fn lambdas(term: &Term) -> (Vec<String>, &Term) {
+    let mut params = vec![];
+    let mut body = term;
+    while let Term::Lambda(x, b) = body {
+        params.push(format!("x{}", x));
+        body = b;
+    }
+    (params, body)
+}
+
+// One definition as `def name x0 x1 = body`, which `syntax::parse_line` reads back
+pub fn decompile(arena: &Arena, var: &Var, val: Value) -> String {
+    let term = decompile_term(arena, val);
+    let (params, body) = lambdas(&term);
+    let mut lhs = vec!["def".to_string(), var_name(var)];
+    lhs.extend(params);
+    let text = format!("{} = {}", lhs.join(" "), show(body));
+    release(term);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::differential::assert_same_frames;
+
+    fn decompiled(line: &str) -> String {
+        let mut state = State::new();
+        let stmt = parse_line(line).unwrap();
+        let var = stmt.var.clone();
+        state.interpret(stmt).unwrap();
+        let val = state.definition(&var).unwrap();
+        let text = decompile(&state.arena(), &var, val);
+        text
+    }
+
+    #[test]
+    fn test_decompile() {
+        assert_eq!(
+            decompiled("inc2 = ap ap b inc inc"),
+            "def inc2 x0 = inc (inc x0)"
+        );
+        assert_eq!(
+            decompiled("twice = ap ap s b i"),
+            "def twice x0 x1 = x0 (x0 x1)"
+        );
+        assert_eq!(decompiled("x = ap ap c cons nil"), "def x x0 = [x0]");
+        assert_eq!(
+            decompiled(":5 = ap ap cons 1 ap ap cons ap ap c add 1 nil"),
+            "def :5 = [1, \\x0 -> add x0 1]"
+        );
+        assert_eq!(
+            decompiled("abs = ap ap s ap ap s ap ap c lt 0 neg i"),
+            "def abs x0 = if lt x0 0 then neg x0 else x0"
+        );
+        assert_eq!(decompiled("k = t"), "def k = t");
+        assert_eq!(decompiled("k = ap t :1"), "def k x0 = :1");
+        assert_eq!(decompiled("pair = ap cons 1"), "def pair = cons 1");
+        assert_eq!(decompiled("y = ap ap s i i"), "def y x0 = x0 x0");
+        assert_eq!(
+            decompiled("z = ap ap ap ap ap lt :1 0 i neg 5"),
+            "def z = (if lt :1 0 then \\x0 -> x0 else neg) 5"
+        );
+    }
+
+    #[test]
+    fn test_deep_terms() {
+        let depth = 100_000;
+        let improper = format!("x = {}5", "ap ap cons 1 ".repeat(depth));
+        let text = decompiled(&improper);
+        assert!(
+            text.starts_with("def x = cons 1 (cons 1 "),
+            "{}",
+            &text[..40]
+        );
+        assert!(text.ends_with(&format!(" 5{}", ")".repeat(depth - 1))));
+        let spine = format!("x = {}inc{}", "ap ".repeat(depth), " 1".repeat(depth));
+        assert_eq!(
+            decompiled(&spine),
+            format!("def x = inc{}", " 1".repeat(depth))
+        );
+    }
+
+    #[test]
+    fn test_picture() {
+        let mut state = State::new();
+        let stmt = parse_line("p = ap draw ap ap cons ap ap cons 1 2 nil").unwrap();
+        let var = stmt.var.clone();
+        state.interpret(stmt).unwrap();
+        let val = state.eval_v(&var).unwrap();
+        assert!(matches!(state.arena().get(val), Value_::Picture(_)));
+        assert_eq!(
+            decompile(&state.arena(), &var, val),
+            "def p = draw [cons 1 2]"
+        );
+    }
+
+    #[test]
+    fn test_round_trip_galaxy() {
+        let mut original = State::new();
+        let mut decompiled = State::new();
+        for stmt in parse_file("galaxy.txt", include_str!("../data/galaxy.txt")).unwrap() {
+            let var = stmt.var.clone();
+            original.interpret(stmt).unwrap();
+            let val = original.definition(&var).unwrap();
+            let line = decompile(&original.arena(), &var, val);
+            decompiled.interpret(parse_line(&line).unwrap()).unwrap();
+        }
+        assert_same_frames(&original, &decompiled, &[(0, 0), (0, 0), (8, 4)]);
+    }
+}
@@ -0,0 +1,381 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::closures::Closures;
+use crate::eval::{Backend, EvalError, State};
+use crate::evaluator::{Data, Evaluator};
+use crate::syntax::{is_blank, is_definition, parse_line, parse_test, Stmt};
+
+// Runs the same programs through the graph evaluator and the closures one and reports where
+// they disagree. Random programs are made of typed expressions that can't fail or loop, so
+// any disagreement is a bug in one of them.
+
+// Sub-expressions of random programs stop nesting this deep
+const MAX_DEPTH: u32 = 4;
+// How many definitions a random program has, each may use the ones before it
+const DEFINITIONS: usize = 6;
+// How many random programs `diff` compares the evaluators on
+pub const RANDOM_PROGRAMS: u64 = 1000;
+
+// Something two evaluators evaluated differently
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    pub program: String,
+    pub graph: Result<Data, EvalError>,
+    pub closures: Result<Data, EvalError>,
+}
+
+fn show(result: &Result<Data, EvalError>) -> String {
+    match result {
+        Ok(data) => data.to_string(),
+        Err(e) => format!("error: {}", e),
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.program)?;
+        writeln!(f, "  graph:    {}", show(&self.graph))?;
+        write!(f, "  closures: {}", show(&self.closures))
+    }
+}
+
+// Errors only have to be errors in both, they are worded differently
+fn agree(a: &Result<Data, EvalError>, b: &Result<Data, EvalError>) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => a == b,
+        (Err(_), Err(_)) => true,
+        _ => false,
+    }
+}
+
+struct Evaluators {
+    graph: State,
+    closures: Closures,
+}
+
+impl Evaluators {
+    fn new(backend: Backend) -> Self {
+        let mut graph = State::new();
+        graph.set_backend(backend);
+        Evaluators {
+            graph,
+            closures: Closures::new(),
+        }
+    }
+
+    // Defines what `parse` makes in both and compares what it evaluates to, `program` is
+    // what it's reported as
+    fn check<P>(&mut self, program: &str, parse: P) -> Result<Option<Divergence>, Box<dyn Error>>
+    where
+        P: Fn() -> Result<Stmt, Box<dyn Error>>,
+    {
+        let stmt = parse()?;
+        let var = stmt.var.clone();
+        self.graph.interpret(stmt)?;
+        self.closures.interpret(parse()?)?;
+        let graph = self.graph.eval_data(&var);
+        let closures = self.closures.eval_data(&var);
+        if agree(&graph, &closures) {
+            return Ok(None);
+        }
+        Ok(Some(Divergence {
+            program: program.to_string(),
+            graph,
+            closures,
+        }))
+    }
+}
+
+// Compares the evaluators on the definitions of a TEST file and on both sides of its checks.
+// Other lines are skipped.
+pub fn compare_file(text: &str, backend: Backend) -> Result<Vec<Divergence>, Box<dyn Error>> {
+    let mut evaluators = Evaluators::new(backend);
+    let mut divergences = vec![];
+    // Skip the "TEST" line
+    for line in text.lines().skip(1) {
+        if is_blank(line) || line.starts_with("PRINT ") {
+        } else if is_definition(line) {
+            divergences.extend(evaluators.check(line, || Ok(parse_line(line)?))?);
+        } else if parse_test(line).is_ok() {
+            divergences.extend(evaluators.check(line, || Ok(parse_test(line)?.0))?);
+            divergences.extend(evaluators.check(line, || Ok(parse_test(line)?.1))?);
+        }
+    }
+    Ok(divergences)
+}
+
+// xorshift64*, random programs only have to be different and the same for a seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+
+    fn range(&mut self, low: i64, high: i64) -> i64 {
+        low + self.below((high - low + 1) as u64) as i64
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Kind {
+    Number,
+    Bool,
+    List,
+    // From a number to a number
+    Function,
+}
+
+const KINDS: [Kind; 4] = [Kind::Number, Kind::Bool, Kind::List, Kind::Function];
+
+// Writes random expressions of a kind, in the notation of the messages
+struct Generator {
+    rng: Rng,
+    defined: Vec<(String, Kind)>,
+}
+
+impl Generator {
+    fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+        choices[self.rng.below(choices.len() as u64) as usize]
+    }
+
+    // One of the definitions of that kind so far
+    fn defined(&mut self, kind: Kind) -> Option<String> {
+        let names: Vec<&String> = (self.defined.iter())
+            .filter(|(_, k)| *k == kind)
+            .map(|(name, _)| name)
+            .collect();
+        if names.is_empty() {
+            return None;
+        }
+        Some(names[self.rng.below(names.len() as u64) as usize].clone())
+    }
+
+    fn expr(&mut self, kind: Kind, depth: u32) -> String {
+        match kind {
+            Kind::Number => self.number(depth),
+            Kind::Bool => self.boolean(depth),
+            Kind::List => self.list(depth),
+            Kind::Function => self.function(depth),
+        }
+    }
+
+    fn literal(&mut self) -> String {
+        self.rng.range(-20, 20).to_string()
+    }
+
+    fn number(&mut self, depth: u32) -> String {
+        if depth >= MAX_DEPTH {
+            return self.literal();
+        }
+        let d = depth + 1;
+        match self.rng.below(12) {
+            0 => self.literal(),
+            1 => format!(
+                "ap {} {}",
+                self.pick(&["inc", "dec", "neg", "i"]),
+                self.number(d)
+            ),
+            2 => {
+                let op = self.pick(&["add", "mul"]);
+                format!("ap ap {} {} {}", op, self.number(d), self.number(d))
+            }
+            3 => {
+                // Never 0, a division by zero fails in both and isn't worth comparing
+                let divisor = self.rng.range(1, 5) * if self.rng.below(2) == 0 { 1 } else { -1 };
+                format!("ap ap div {} {}", self.number(d), divisor)
+            }
+            4 => format!("ap pwr2 {}", self.rng.range(0, 10)),
+            5 => {
+                let b = self.boolean(d);
+                format!("ap ap {} {} {}", b, self.number(d), self.number(d))
+            }
+            6 => {
+                let n = self.number(d);
+                format!("ap ap ap if0 {} {} {}", n, self.number(d), self.number(d))
+            }
+            7 => format!("ap {} {}", self.function(d), self.number(d)),
+            8 => {
+                let f = self.function2(d);
+                format!("ap ap {} {} {}", f, self.number(d), self.number(d))
+            }
+            9 => format!("ap car ap ap cons {} {}", self.number(d), self.list(d)),
+            10 => {
+                let (x, y) = (self.number(d), self.number(d));
+                format!("ap car ap cdr ( {} , {} )", x, y)
+            }
+            _ => self.defined(Kind::Number).unwrap_or_else(|| self.literal()),
+        }
+    }
+
+    fn boolean(&mut self, depth: u32) -> String {
+        if depth >= MAX_DEPTH {
+            return self.pick(&["t", "f"]).to_string();
+        }
+        let d = depth + 1;
+        match self.rng.below(6) {
+            0 => self.pick(&["t", "f"]).to_string(),
+            1 | 2 => {
+                let op = self.pick(&["eq", "lt"]);
+                format!("ap ap {} {} {}", op, self.number(d), self.number(d))
+            }
+            3 => format!("ap isnil {}", self.list(d)),
+            4 => {
+                let b = self.boolean(d);
+                format!("ap ap {} {} {}", b, self.boolean(d), self.boolean(d))
+            }
+            _ => (self.defined(Kind::Bool)).unwrap_or_else(|| "t".to_string()),
+        }
+    }
+
+    fn list(&mut self, depth: u32) -> String {
+        if depth >= MAX_DEPTH {
+            return "nil".to_string();
+        }
+        let d = depth + 1;
+        match self.rng.below(6) {
+            0 => "nil".to_string(),
+            1 | 2 => format!("ap ap cons {} {}", self.number(d), self.list(d)),
+            3 => format!("ap cdr ap ap cons {} {}", self.number(d), self.list(d)),
+            4 => format!("( {} , {} )", self.number(d), self.number(d)),
+            _ => (self.defined(Kind::List)).unwrap_or_else(|| "nil".to_string()),
+        }
+    }
+
+    fn function(&mut self, depth: u32) -> String {
+        if depth >= MAX_DEPTH {
+            return self.pick(&["inc", "dec", "neg", "i"]).to_string();
+        }
+        let d = depth + 1;
+        match self.rng.below(9) {
+            0 => self.pick(&["inc", "dec", "neg", "i"]).to_string(),
+            1 => format!("ap {} {}", self.pick(&["add", "mul"]), self.number(d)),
+            2 => format!("ap ap b {} {}", self.function(d), self.function(d)),
+            3 => format!("ap ap c {} {}", self.function2(d), self.number(d)),
+            4 => format!("ap ap s {} {}", self.function2(d), self.function(d)),
+            5 => format!("ap t {}", self.number(d)),
+            6 => format!("ap f {}", self.number(d)),
+            7 => format!(
+                "ap ap ap if0 {} {} {}",
+                self.number(d),
+                self.function(d),
+                self.function(d)
+            ),
+            _ => (self.defined(Kind::Function)).unwrap_or_else(|| "inc".to_string()),
+        }
+    }
+
+    // From two numbers to a number
+    fn function2(&mut self, depth: u32) -> String {
+        if depth >= MAX_DEPTH {
+            return self.pick(&["add", "mul", "t", "f"]).to_string();
+        }
+        let d = depth + 1;
+        match self.rng.below(4) {
+            0 | 1 => self.pick(&["add", "mul", "t", "f"]).to_string(),
+            2 => format!("ap c {}", self.function2(d)),
+            _ => format!("ap ap b {} {}", self.function2(d), self.function(d)),
+        }
+    }
+}
+
+// The definitions of a random program, the same for a seed
+pub fn random_program(seed: u64) -> Vec<String> {
+    let mut generator = Generator {
+        rng: Rng::new(seed),
+        defined: vec![],
+    };
+    (0..DEFINITIONS)
+        .map(|i| {
+            let kind = KINDS[generator.rng.below(KINDS.len() as u64) as usize];
+            let name = format!(":{}", i + 1);
+            let line = format!("{} = {}", name, generator.expr(kind, 0));
+            generator.defined.push((name, kind));
+            line
+        })
+        .collect()
+}
+
+// Compares the evaluators on the random programs of `count` seeds from `first`. A divergence
+// is reported with the program up to the definition it's about.
+pub fn compare_random(
+    first: u64,
+    count: u64,
+    backend: Backend,
+) -> Result<Vec<Divergence>, Box<dyn Error>> {
+    let mut divergences = vec![];
+    for seed in first..first + count {
+        let mut evaluators = Evaluators::new(backend);
+        let program = random_program(seed);
+        for (i, line) in program.iter().enumerate() {
+            let text = format!("# seed {}\n{}", seed, program[..=i].join("\n"));
+            divergences.extend(evaluators.check(&text, || Ok(parse_line(line)?))?);
+        }
+    }
+    Ok(divergences)
+}
+
+// Shared by the tests of the backends and passes that must not change what galaxy draws
+#[cfg(test)]
+mod frames {
+    use crate::eval::State;
+    use crate::interact::run_interaction;
+    use crate::types::NestedList;
+
+    // Clicks through galaxy with both states and checks that every frame and state is the same
+    pub fn assert_same_frames(a: &State, b: &State, clicks: &[(i64, i64)]) {
+        let (mut st_a, mut st_b) = (NestedList::Nil, NestedList::Nil);
+        for &(x, y) in clicks {
+            let (new_a, data_a) = run_interaction(a, "galaxy", st_a, x, y).unwrap();
+            let (new_b, data_b) = run_interaction(b, "galaxy", st_b, x, y).unwrap();
+            assert_eq!((&new_a, &data_a), (&new_b, &data_b), "click ({}, {})", x, y);
+            st_a = new_a;
+            st_b = new_b;
+        }
+    }
+}
+
+#[cfg(test)]
+pub use frames::assert_same_frames;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file() {
+        let text = include_str!("../data/test.txt");
+        for backend in [Backend::Interpreter, Backend::Compiled] {
+            let divergences = compare_file(text, backend).unwrap();
+            assert!(divergences.is_empty(), "{}", divergences[0]);
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        assert_eq!(random_program(7), random_program(7));
+        assert_ne!(random_program(7), random_program(8));
+        for backend in [Backend::Interpreter, Backend::Compiled] {
+            let divergences = compare_random(0, 300, backend).unwrap();
+            assert!(divergences.is_empty(), "{}", divergences[0]);
+        }
+    }
+
+    #[test]
+    fn test_divergence() {
+        // Errors agree however they are worded, data has to be the same
+        let e = || Err(EvalError::DivisionByZero);
+        assert!(agree(&e(), &Err(EvalError::Stuck("x".to_string()))));
+        assert!(!agree(&e(), &Ok(Data::Nil)));
+        assert!(!agree(&Ok(Data::Bool(true)), &Ok(Data::Nil)));
+        assert!(agree(&Ok(Data::Function), &Ok(Data::Function)));
+    }
+}
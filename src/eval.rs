@@ -1,272 +1,1113 @@
-use std::cell::RefCell;
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::syntax::{Stmt, Token, Var};
+use num_traits::{ToPrimitive, Zero};
 
-#[derive(Debug, Default)]
+pub use crate::arena::{b, Arena, Value};
+use crate::compiled::{compile, Supercombinator};
+use crate::memo::{Memo, MemoStats};
+use crate::modem::{dem_list, mod_list, try_dem_list};
+use crate::optimize::optimize;
+use crate::profile::Profile;
+use crate::send::Transport;
+use crate::syntax::{Number, Stmt, Token, Var};
+use crate::types::{NestedList, Picture, PictureBuilder};
+
+#[derive(Default)]
 pub struct State {
     vars: HashMap<Var, Value>,
+    // Every term, from the definitions to what evaluation makes of them
+    arena: RefCell<Arena>,
+    // Delivers the argument of `send`, evaluation only borrows the state immutably
+    transport: RefCell<Option<Box<dyn Transport>>>,
+    tracer: RefCell<Option<Tracer>>,
+    cancel: CancelToken,
+    limits: Limits,
+    backend: Backend,
+    // Only filled in with the compiled backend
+    compiled: HashMap<Var, Rc<Supercombinator>>,
+    // Only there while memoizing, see `set_memo`
+    memo: RefCell<Option<Memo>>,
+    // How many times `send` was called, a call that sends isn't remembered
+    sends: Cell<u64>,
+    // Only there while profiling, see `set_profiling`
+    profile: RefCell<Option<Profile>>,
+}
+
+// How definitions are run. Both give the same data, but functions that are results of their
+// own can differ in shape, as the compiled backend doesn't keep the combinators.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Backend {
+    // Rewrites the combinators of the definitions as they are
+    #[default]
+    Interpreter,
+    // Instantiates each definition applied to all of its arguments at once
+    Compiled,
+}
+
+// Stops evaluations of a state from another thread. A cancelled token stays cancelled until
+// it's reset.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Bounds on every call to `State::eval`, there are none by default
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Limits {
+    // Steps of the evaluation loop, each rewrite or move to a sub-term is one
+    pub steps: Option<u64>,
+    pub time: Option<Duration>,
+    // Nodes in the arena, the definitions' included
+    pub nodes: Option<usize>,
+}
+
+// The limit an evaluation ran into
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Limit {
+    Steps(u64),
+    Time(Duration),
+    Nodes(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps(n) => write!(f, "{} steps", n),
+            Limit::Time(d) => write!(f, "{} s", d.as_secs_f64()),
+            Limit::Nodes(n) => write!(f, "{} nodes", n),
+        }
+    }
+}
+
+// Looking at the clock is the slowest of the checks, so it's only done this often
+const CLOCK_INTERVAL: u64 = 1024;
+
+// `pwr2` of more than this fails, the number would take more memory than there is
+const MAX_EXPONENT: usize = 1 << 24;
+
+// `checkerboard` of a size with more points than this fails, whatever the limits
+const MAX_CHECKERBOARD_POINTS: i64 = 1 << 24;
+
+// Shared with the closures evaluator, so both fail on the same exponents
+pub fn pwr2(n: &Number) -> Result<Number, EvalError> {
+    if *n < Number::zero() {
+        return Err(EvalError::TypeMismatch {
+            builtin: BuiltIn::Pwr2,
+            expected: "non-negative number",
+        });
+    }
+    match n.to_usize().filter(|n| *n <= MAX_EXPONENT) {
+        Some(n) => Ok(Number::from(1) << n),
+        None => Err(EvalError::TooBig {
+            builtin: BuiltIn::Pwr2,
+            max: "2^24 bits",
+        }),
+    }
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State").field("vars", &self.vars).finish()
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value_ {
     Var(Var),
-    Number(i64),
+    Number(Number),
     BuiltIn(BuiltIn),
     Apply(Value, Value),
+    Picture(Picture),
 }
 
-pub fn var(v: Var) -> Value {
-    Rc::new(RefCell::new(V {
-        val: Value_::Var(v),
-        computed: false,
-    }))
+fn bool_value(x: bool) -> Value {
+    if x {
+        b(BuiltIn::True)
+    } else {
+        b(BuiltIn::False)
+    }
 }
 
-pub fn number(n: i64) -> Value {
-    Rc::new(RefCell::new(V {
-        val: Value_::Number(n),
-        computed: true,
-    }))
+// Built-in functions except `ap`
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum BuiltIn {
+    Inc,          // #5
+    Dec,          // #6
+    Add,          // #7
+    Mul,          // #9
+    Div,          // #10
+    Eq,           // #11
+    Lt,           // #12
+    Neg,          // #16
+    S,            // #18
+    C,            // #19
+    B,            // #20
+    True,         // #21
+    False,        // #22
+    Pwr2,         // #23
+    I,            // #24
+    Cons,         // #25
+    Head,         // #26
+    Tail,         // #27
+    Nil,          // #28
+    IsNil,        // #29
+    Draw,         // #32
+    Checkerboard, // #33
+    MultipleDraw, // #34
+    Modem,        // #35
+    Send,         // #36
+    If0,          // #37
+    F38,          // #38
+    Interact,     // #39
 }
 
-pub fn b(b: BuiltIn) -> Value {
-    Rc::new(RefCell::new(V {
-        val: Value_::BuiltIn(b),
-        computed: true,
-    }))
-}
+impl BuiltIn {
+    // The number of arguments the rewrite rule needs
+    pub fn arity(self) -> usize {
+        match self {
+            BuiltIn::Nil => 1,
+            BuiltIn::Inc | BuiltIn::Dec | BuiltIn::Neg | BuiltIn::Pwr2 | BuiltIn::I => 1,
+            BuiltIn::Head | BuiltIn::Tail | BuiltIn::IsNil => 1,
+            BuiltIn::Draw | BuiltIn::MultipleDraw | BuiltIn::Modem | BuiltIn::Send => 1,
+            BuiltIn::Add | BuiltIn::Mul | BuiltIn::Div | BuiltIn::Eq | BuiltIn::Lt => 2,
+            BuiltIn::True | BuiltIn::False | BuiltIn::Checkerboard | BuiltIn::F38 => 2,
+            BuiltIn::S | BuiltIn::C | BuiltIn::B | BuiltIn::Cons => 3,
+            BuiltIn::If0 | BuiltIn::Interact => 3,
+        }
+    }
 
-pub fn ap(f: Value, arg: Value) -> Value {
-    Rc::new(RefCell::new(V {
-        val: Value_::Apply(f, arg),
-        computed: false,
-    }))
+    // The built-in a token stands for, if it's one
+    pub fn from_token(token: &Token) -> Option<BuiltIn> {
+        Some(match token {
+            Token::True => BuiltIn::True,
+            Token::False => BuiltIn::False,
+            Token::Nil => BuiltIn::Nil,
+            Token::Inc => BuiltIn::Inc,
+            Token::Dec => BuiltIn::Dec,
+            Token::Add => BuiltIn::Add,
+            Token::Mul => BuiltIn::Mul,
+            Token::Div => BuiltIn::Div,
+            Token::Eq => BuiltIn::Eq,
+            Token::Lt => BuiltIn::Lt,
+            Token::Neg => BuiltIn::Neg,
+            Token::S => BuiltIn::S,
+            Token::C => BuiltIn::C,
+            Token::B => BuiltIn::B,
+            Token::Pwr2 => BuiltIn::Pwr2,
+            Token::I => BuiltIn::I,
+            Token::Cons => BuiltIn::Cons,
+            Token::Head => BuiltIn::Head,
+            Token::Tail => BuiltIn::Tail,
+            Token::IsNil => BuiltIn::IsNil,
+            Token::Draw => BuiltIn::Draw,
+            Token::Checkerboard => BuiltIn::Checkerboard,
+            Token::MultipleDraw => BuiltIn::MultipleDraw,
+            Token::Modem => BuiltIn::Modem,
+            Token::Send => BuiltIn::Send,
+            Token::If0 => BuiltIn::If0,
+            Token::F38 => BuiltIn::F38,
+            Token::Interact => BuiltIn::Interact,
+            Token::Number(_) | Token::Var(_) | Token::Ap => return None,
+        })
+    }
 }
 
+// A rewrite done during evaluation, as reported to the tracer
 #[derive(Debug, PartialEq, Clone)]
-pub struct V {
-    pub val: Value_,
-    computed: bool,
+pub enum Rewrite {
+    // A variable was replaced by its definition. The interpreter unfolds variables on their
+    // own, the compiled backend together with the arguments of the call.
+    Unfold(Var, Vec<Value>),
+    // The rule of a built-in fired on these arguments
+    Rule(BuiltIn, Vec<Value>),
 }
 
-impl V {
-    pub fn unwrap_number(&self) -> i64 {
-        if let Value_::Number(n) = &self.val {
-            *n
-        } else {
-            panic!("Not a number");
+// Gets the arena too, the arguments are still as they were before the rewrite
+pub type Tracer = Box<dyn FnMut(&Arena, Rewrite)>;
+
+// What rewrite reducing `val` is, judging by the head of its spine
+fn rewrite_of(arena: &Arena, val: Value) -> Option<Rewrite> {
+    let mut args = vec![];
+    let mut curr = val;
+    while let Value_::Apply(f, x) = arena.get(curr) {
+        args.push(*x);
+        curr = *f;
+    }
+    args.reverse();
+    match arena.get(curr) {
+        Value_::Var(v) => Some(Rewrite::Unfold(v.clone(), args)),
+        Value_::BuiltIn(b) => {
+            args.truncate(b.arity());
+            Some(Rewrite::Rule(*b, args))
         }
+        _ => None,
     }
 }
 
-pub type Value = Rc<RefCell<V>>;
+// What a single reduction step did to the term on top of the evaluation stack
+enum Step {
+    // The term is in normal form
+    Done,
+    // The term was rewritten into another one
+    Reduced(Value),
+    // This sub-term has to be evaluated before the term can be reduced
+    Need(Value),
+    // The term is a call made before, this is what it evaluated to
+    Remembered(Value),
+}
+
+// A call that is being evaluated for the memo table: the depth of the entry of the evaluation
+// stack it's the term of, the call, and how many sends were made before it
+type PendingCall = (usize, (Var, Vec<Value>), u64);
+
+// Asks for the first of `vals` that hasn't been evaluated yet
+fn require(arena: &Arena, vals: &[Value]) -> Option<Step> {
+    vals.iter()
+        .find(|v| !arena.is_computed(**v))
+        .map(|v| Step::Need(*v))
+}
+
+// Builds `( a , b )`
+fn list2(arena: &mut Arena, a: Value, b_: Value) -> Value {
+    let cons = arena.ap(b(BuiltIn::Cons), b_);
+    let tail = arena.ap(cons, b(BuiltIn::Nil));
+    let cons = arena.ap(b(BuiltIn::Cons), a);
+    arena.ap(cons, tail)
+}
+
+// Reads an evaluated value as data, which requires all of its elements to be evaluated as well
+fn as_list(arena: &Arena, val: Value, builtin: BuiltIn) -> Result<NestedList, EvalError> {
+    NestedList::from_value(arena, val).map_err(|_| EvalError::TypeMismatch {
+        builtin,
+        expected: "list",
+    })
+}
+
+// Applies a numeric built-in once its argument is evaluated
+fn unary(
+    arena: &mut Arena,
+    arg: Value,
+    builtin: BuiltIn,
+    f: fn(&mut Arena, Number) -> Result<Value, EvalError>,
+) -> Result<Step, EvalError> {
+    if let Some(step) = require(arena, &[arg]) {
+        return Ok(step);
+    }
+    let x = arena.as_number(arg, builtin)?;
+    Ok(Step::Reduced(f(arena, x)?))
+}
+
+// Applies a numeric built-in once both of its arguments are evaluated
+fn binary(
+    arena: &mut Arena,
+    arg1: Value,
+    arg0: Value,
+    builtin: BuiltIn,
+    f: fn(&mut Arena, Number, Number) -> Result<Value, EvalError>,
+) -> Result<Step, EvalError> {
+    if let Some(step) = require(arena, &[arg1, arg0]) {
+        return Ok(step);
+    }
+    let x = arena.as_number(arg1, builtin)?;
+    let y = arena.as_number(arg0, builtin)?;
+    Ok(Step::Reduced(f(arena, x, y)?))
+}
 
-// Built-in functions except `ap`
 #[derive(Debug, PartialEq, Clone)]
-pub enum BuiltIn {
-    Inc,   // #5
-    Dec,   // #6
-    Add,   // #7
-    Mul,   // #9
-    Div,   // #10
-    Eq,    // #11
-    Lt,    // #12
-    Neg,   // #16
-    S,     // #18
-    C,     // #19
-    B,     // #20
-    True,  // #21
-    False, // #22
-    Pwr2,  // #23
-    I,     // #24
-    Cons,  // #25
-    Head,  // #26
-    Tail,  // #27
-    Nil,   // #28
-    IsNil, // #29
+pub enum EvalError {
+    // A built-in got an argument of the wrong kind
+    TypeMismatch {
+        builtin: BuiltIn,
+        expected: &'static str,
+    },
+    UnboundVariable(Var),
+    DivisionByZero,
+    // Too many arguments (e.g. a number applied to something) or unbalanced `ap`s
+    Arity(String),
+    // The result can't be used the way the caller needs (e.g. not a list)
+    Stuck(String),
+    // `send` couldn't reach the aliens or didn't understand their answer
+    Transport(String),
+    // A built-in would make a value too big to hold, like `pwr2` of a huge number
+    TooBig {
+        builtin: BuiltIn,
+        max: &'static str,
+    },
+    // The state's `CancelToken` was cancelled
+    Cancelled,
+    LimitExceeded(Limit),
 }
 
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::TypeMismatch { builtin, expected } => {
+                write!(f, "`{:?}` expected a {}", builtin, expected)
+            }
+            EvalError::UnboundVariable(v) => write!(f, "Unbound variable {:?}", v),
+            EvalError::DivisionByZero => write!(f, "Division by zero"),
+            EvalError::Arity(msg) => write!(f, "Arity error: {}", msg),
+            EvalError::Stuck(msg) => write!(f, "Stuck: {}", msg),
+            EvalError::Transport(msg) => write!(f, "Transport error: {}", msg),
+            EvalError::TooBig { builtin, max } => {
+                write!(f, "`{:?}` would make more than {}", builtin, max)
+            }
+            EvalError::Cancelled => write!(f, "Evaluation was cancelled"),
+            EvalError::LimitExceeded(limit) => write!(f, "Evaluation exceeded {}", limit),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
 impl State {
     pub fn new() -> Self {
         State::default()
     }
 
-    pub fn eval_v(&self, var: &Var) -> Value {
-        let v = self.vars.get(var).unwrap();
-        self.eval(v.clone())
+    pub fn set_transport(&mut self, transport: Box<dyn Transport>) {
+        *self.transport.get_mut() = Some(transport);
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        *self.tracer.get_mut() = tracer;
+    }
+
+    // Cancelling the token makes the running evaluation, and any later one until the token is
+    // reset, fail with `EvalError::Cancelled`
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
     }
 
-    pub fn eval(&self, val: Value) -> Value {
-        if val.borrow().computed {
-            return val;
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.compiled.clear();
+        if backend == Backend::Compiled {
+            let arena = self.arena.get_mut();
+            for (var, val) in self.vars.iter() {
+                self.compiled.extend(compile(arena, var, *val));
+            }
         }
-        let mut curr = val.clone();
-        loop {
-            let new = self.eval_core(curr.clone());
-            if Rc::ptr_eq(&new, &curr) {
-                let value = new.borrow().val.clone();
-                val.borrow_mut().val = value;
-                val.borrow_mut().computed = true;
-                break val;
+    }
+
+    // Simplifies the definitions, see `optimize`. Returns how many rewrites that took.
+    pub fn optimize(&mut self) -> usize {
+        let rewrites = optimize(self.arena.get_mut(), &mut self.vars);
+        // The supercombinators have to be made from the new definitions
+        self.set_backend(self.backend);
+        let memo = self.memo.get_mut().is_some();
+        self.set_memo(memo);
+        self.collect();
+        rewrites
+    }
+
+    // Remembers the result of every call of a definition and hash-conses terms, so calls
+    // already made, in this evaluation or an earlier one, are answered without evaluating
+    pub fn set_memo(&mut self, on: bool) {
+        let arena = self.arena.get_mut();
+        arena.set_hash_consing(on);
+        *self.memo.get_mut() = if on {
+            Some(Memo::new(arena, self.vars.iter()))
+        } else {
+            None
+        };
+    }
+
+    // Counts the reductions, time and allocations of every definition and built-in in the
+    // evaluations from now on, starting over
+    pub fn set_profiling(&mut self, on: bool) {
+        *self.profile.get_mut() = if on { Some(Profile::default()) } else { None };
+    }
+
+    pub fn profile(&self) -> Option<Ref<'_, Profile>> {
+        Ref::filter_map(self.profile.borrow(), Option::as_ref).ok()
+    }
+
+    pub fn memo_stats(&self) -> Option<MemoStats> {
+        let memo = self.memo.borrow();
+        Some(memo.as_ref()?.stats(&self.arena()))
+    }
+
+    // The terms of this state. Evaluation needs the arena to itself, so this must not be held
+    // across a call to `eval`.
+    pub fn arena(&self) -> Ref<'_, Arena> {
+        self.arena.borrow()
+    }
+
+    pub fn arena_mut(&self) -> RefMut<'_, Arena> {
+        self.arena.borrow_mut()
+    }
+
+    // Frees the terms that are no longer reachable from the definitions, which makes any
+    // other value from before invalid
+    pub fn collect(&self) {
+        let compiled = self.compiled.values().flat_map(|sc| sc.roots());
+        let memo = self.memo.borrow_mut().as_mut().map(|m| m.roots());
+        let roots = (self.vars.values().copied())
+            .chain(compiled)
+            .chain(memo.into_iter().flatten());
+        self.arena.borrow_mut().collect(roots);
+    }
+
+    // A definition applied to all the arguments its supercombinator takes, built as its body
+    fn instantiate(&self, arena: &mut Arena, val: Value) -> Option<Value> {
+        if self.compiled.is_empty() {
+            return None;
+        }
+        // Most steps aren't calls, so find the head before collecting any arguments
+        let mut depth = 0;
+        let mut curr = val;
+        let sc = loop {
+            let next = match arena.get(curr) {
+                Value_::Apply(f, _) => *f,
+                Value_::Var(v) => break self.compiled.get(v)?,
+                _ => return None,
+            };
+            // An evaluated head can't be a variable any more. A shared partial application is
+            // left to the interpreter, which evaluates it once instead of rebuilding the body
+            // for every use.
+            if arena.is_computed(next)
+                || arena.is_shared(next) && matches!(arena.get(next), Value_::Apply(..))
+            {
+                return None;
             }
-            curr = new.clone();
+            depth += 1;
+            curr = next;
+        };
+        if depth < sc.arity {
+            return None;
+        }
+        let mut args = Vec::with_capacity(depth);
+        let mut curr = val;
+        for _ in 0..depth {
+            curr = match arena.get(curr) {
+                Value_::Apply(f, x) => {
+                    args.push(*x);
+                    *f
+                }
+                _ => unreachable!(),
+            };
         }
+        args.reverse();
+        let rest = args.split_off(sc.arity);
+        let body = sc.instantiate(arena, &args);
+        Some(rest.into_iter().fold(body, |f, x| arena.ap(f, x)))
     }
 
-    fn eval_core(&self, val: Value) -> Value {
-        // println!("eval_value: {:?}", val);
-        if val.borrow().computed {
-            return val;
+    pub fn send(&self, data: NestedList) -> Result<NestedList, EvalError> {
+        self.sends.set(self.sends.get() + 1);
+        let mut transport = self.transport.borrow_mut();
+        let transport = transport
+            .as_mut()
+            .ok_or_else(|| EvalError::Transport("no transport for `send`".to_string()))?;
+        let response = transport
+            .send(&mod_list(&data))
+            .map_err(|err| EvalError::Transport(err.to_string()))?;
+        try_dem_list(&response).map_err(|_| EvalError::Transport("malformed response".to_string()))
+    }
+
+    pub fn definition(&self, var: &Var) -> Option<Value> {
+        self.vars.get(var).copied()
+    }
+
+    pub fn definitions(&self) -> impl Iterator<Item = (&Var, &Value)> {
+        self.vars.iter()
+    }
+
+    pub fn eval_v(&self, var: &Var) -> Result<Value, EvalError> {
+        let v = self
+            .vars
+            .get(var)
+            .ok_or_else(|| EvalError::UnboundVariable(var.clone()))?;
+        self.eval(*v)
+    }
+
+    pub fn eval(&self, val: Value) -> Result<Value, EvalError> {
+        // Evaluation is driven by an explicit stack rather than recursion, so deeply nested terms
+        // don't overflow the native stack. Every entry is a node being evaluated and the term
+        // it has been reduced to so far; the final result is written back into the node.
+        let mut arena = self.arena.borrow_mut();
+        let arena = &mut *arena;
+        let mut stack = vec![(val, val)];
+        let mut calls = vec![];
+        let start = Instant::now();
+        let mut steps = 0;
+        let mut profile = self.profile.borrow_mut();
+        if let Some(profile) = profile.as_mut() {
+            profile.start(arena);
         }
-        let value = val.borrow().val.clone();
-        match &value {
-            Value_::Var(v) => self.vars.get(&v).unwrap().clone(),
-            Value_::Number(_) => val,
-            Value_::BuiltIn(_) => val,
-            Value_::Apply(f0, arg0) => {
-                match &self.eval(f0.clone()).borrow().val {
-                    Value_::BuiltIn(BuiltIn::Inc) => {
-                        if let Value_::Number(n) = self.eval(arg0.clone()).borrow().val {
-                            number(n + 1)
-                        } else {
-                            panic!("Invalid argument for `inc`");
+        while let Some(&(target, curr)) = stack.last() {
+            steps += 1;
+            self.check_limits(arena, steps, start)?;
+            let step = match self.memoized(arena, curr, stack.len(), &mut calls) {
+                Some(step) => step,
+                None => self.step(arena, curr, &mut steps, start)?,
+            };
+            if let Some(profile) = profile.as_mut() {
+                let reduced = matches!(step, Step::Reduced(_));
+                profile.step(arena, stack.len(), curr, reduced);
+            }
+            match step {
+                Step::Done => {
+                    if target != curr {
+                        arena.copy(target, curr);
+                    }
+                    arena.set_computed(target);
+                    self.remember(target, stack.len(), &mut calls);
+                    stack.pop();
+                }
+                Step::Reduced(new) => {
+                    if let Some(tracer) = self.tracer.borrow_mut().as_mut() {
+                        if let Some(rewrite) = rewrite_of(arena, curr) {
+                            tracer(arena, rewrite);
                         }
                     }
-                    Value_::BuiltIn(BuiltIn::Dec) => {
-                        number(self.eval(arg0.clone()).borrow().unwrap_number() - 1)
+                    // Unless something points to it, the term just reduced is garbage now
+                    if curr != target {
+                        arena.release(curr, new);
                     }
-                    Value_::BuiltIn(BuiltIn::Neg) => {
-                        number(-self.eval(arg0.clone()).borrow().unwrap_number())
+                    stack.last_mut().unwrap().1 = new;
+                }
+                Step::Need(sub) => stack.push((sub, sub)),
+                Step::Remembered(result) => {
+                    if curr != target {
+                        arena.release(curr, result);
                     }
-                    Value_::BuiltIn(BuiltIn::Pwr2) => number(
-                        (2 as i64).pow(self.eval(arg0.clone()).borrow().unwrap_number() as u32),
-                    ),
-                    Value_::BuiltIn(BuiltIn::I) => arg0.clone(),
-                    Value_::BuiltIn(BuiltIn::Head) => ap(arg0.clone(), b(BuiltIn::True)),
-                    Value_::BuiltIn(BuiltIn::Tail) => ap(arg0.clone(), b(BuiltIn::False)),
-                    Value_::BuiltIn(BuiltIn::Nil) => b(BuiltIn::True),
-                    Value_::BuiltIn(BuiltIn::IsNil) => ap(
-                        arg0.clone(),
-                        ap(b(BuiltIn::True), ap(b(BuiltIn::True), b(BuiltIn::False))),
-                    ),
-
-                    // ===== Arity 2 =====
-                    Value_::Apply(f1, arg1) => {
-                        match &self.eval(f1.clone()).borrow().val {
-                            Value_::BuiltIn(BuiltIn::Add) => number(
-                                self.eval(arg1.clone()).borrow().unwrap_number()
-                                    + self.eval(arg0.clone()).borrow().unwrap_number(),
-                            ),
-                            Value_::BuiltIn(BuiltIn::Mul) => number(
-                                self.eval(arg1.clone()).borrow().unwrap_number()
-                                    * self.eval(arg0.clone()).borrow().unwrap_number(),
-                            ),
-                            Value_::BuiltIn(BuiltIn::Div) => number(
-                                self.eval(arg1.clone()).borrow().unwrap_number()
-                                    / self.eval(arg0.clone()).borrow().unwrap_number(),
-                            ),
-                            Value_::BuiltIn(BuiltIn::Eq) => {
-                                if self.eval(arg1.clone()).borrow().unwrap_number()
-                                    == self.eval(arg0.clone()).borrow().unwrap_number()
-                                {
-                                    b(BuiltIn::True)
-                                } else {
-                                    b(BuiltIn::False)
-                                }
+                    stack.last_mut().unwrap().1 = result;
+                }
+            }
+        }
+        Ok(val)
+    }
+
+    // Answers a call from the memo table, or notes it down to remember what it evaluates to
+    fn memoized(
+        &self,
+        arena: &Arena,
+        curr: Value,
+        depth: usize,
+        calls: &mut Vec<PendingCall>,
+    ) -> Option<Step> {
+        let mut memo = self.memo.borrow_mut();
+        let memo = memo.as_mut()?;
+        if arena.is_computed(curr) {
+            return None;
+        }
+        let (call, node) = memo.call(arena, curr)?;
+        // A call with more arguments is evaluated on its own first, so it can be remembered
+        if node != curr {
+            return if arena.is_computed(node) {
+                None
+            } else {
+                Some(Step::Need(node))
+            };
+        }
+        // Back from evaluating a sub-term of the call
+        let pending = calls.iter().rev().take_while(|(d, _, _)| *d == depth);
+        if pending.into_iter().any(|(_, c, _)| *c == call) {
+            return None;
+        }
+        match memo.get(&call) {
+            Some(result) => Some(Step::Remembered(result)),
+            None => {
+                calls.push((depth, call, self.sends.get()));
+                None
+            }
+        }
+    }
+
+    // `target`, the term of the entry at `depth`, is evaluated, and so are the calls it was
+    fn remember(&self, target: Value, depth: usize, calls: &mut Vec<PendingCall>) {
+        while calls.last().is_some_and(|(d, _, _)| *d == depth) {
+            let (_, call, sends) = calls.pop().unwrap();
+            if let Some(memo) = self.memo.borrow_mut().as_mut() {
+                if sends == self.sends.get() {
+                    memo.insert(call, target);
+                }
+            }
+        }
+    }
+
+    // Fails once the evaluation was cancelled or went past one of the limits
+    fn check_limits(&self, arena: &Arena, steps: u64, start: Instant) -> Result<(), EvalError> {
+        let Limits {
+            steps: max_steps,
+            time,
+            nodes,
+        } = self.limits;
+        if self.cancel.is_cancelled() {
+            return Err(EvalError::Cancelled);
+        }
+        match (max_steps, time, nodes) {
+            (Some(max), _, _) if steps > max => Err(EvalError::LimitExceeded(Limit::Steps(max))),
+            (_, _, Some(max)) if arena.len() > max => {
+                Err(EvalError::LimitExceeded(Limit::Nodes(max)))
+            }
+            (_, Some(max), _) if steps.is_multiple_of(CLOCK_INTERVAL) && start.elapsed() > max => {
+                Err(EvalError::LimitExceeded(Limit::Time(max)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // `steps` so far, built-ins that loop count theirs in
+    fn step(
+        &self,
+        arena: &mut Arena,
+        val: Value,
+        steps: &mut u64,
+        start: Instant,
+    ) -> Result<Step, EvalError> {
+        if arena.is_computed(val) {
+            return Ok(Step::Done);
+        }
+        if let Some(body) = self.instantiate(arena, val) {
+            return Ok(Step::Reduced(body));
+        }
+        let (f0, arg0) = match arena.get(val) {
+            Value_::Var(v) => {
+                // Lifted lambdas only exist as supercombinators
+                let def = self
+                    .vars
+                    .get(v)
+                    .or_else(|| self.compiled.get(v).map(|sc| &sc.value))
+                    .ok_or_else(|| EvalError::UnboundVariable(v.clone()))?;
+                // Evaluate the definition itself so that its result is shared by all the uses
+                return Ok(require(arena, &[*def]).unwrap_or(Step::Reduced(*def)));
+            }
+            Value_::Number(_) => return Ok(Step::Done),
+            Value_::BuiltIn(_) => return Ok(Step::Done),
+            Value_::Picture(_) => return Ok(Step::Done),
+            Value_::Apply(f0, arg0) => (*f0, *arg0),
+        };
+        if let Some(step) = require(arena, &[f0]) {
+            return Ok(step);
+        }
+        let step = match *arena.get(f0) {
+            Value_::Number(_) => {
+                return Err(EvalError::Arity("a number can't be applied".to_string()))
+            }
+            Value_::Picture(_) => {
+                return Err(EvalError::Arity("a picture can't be applied".to_string()))
+            }
+            Value_::BuiltIn(BuiltIn::Inc) => {
+                unary(arena, arg0, BuiltIn::Inc, |a, n| Ok(a.number(n + 1)))?
+            }
+            Value_::BuiltIn(BuiltIn::Dec) => {
+                unary(arena, arg0, BuiltIn::Dec, |a, n| Ok(a.number(n - 1)))?
+            }
+            Value_::BuiltIn(BuiltIn::Neg) => {
+                unary(arena, arg0, BuiltIn::Neg, |a, n| Ok(a.number(-n)))?
+            }
+            Value_::BuiltIn(BuiltIn::Pwr2) => unary(arena, arg0, BuiltIn::Pwr2, |a, n| {
+                pwr2(&n).map(|n| a.number(n))
+            })?,
+            Value_::BuiltIn(BuiltIn::I) => Step::Reduced(arg0),
+            Value_::BuiltIn(BuiltIn::Head) => Step::Reduced(arena.ap(arg0, b(BuiltIn::True))),
+            Value_::BuiltIn(BuiltIn::Tail) => Step::Reduced(arena.ap(arg0, b(BuiltIn::False))),
+            Value_::BuiltIn(BuiltIn::Nil) => Step::Reduced(b(BuiltIn::True)),
+            Value_::BuiltIn(BuiltIn::IsNil) => {
+                let f = arena.ap(b(BuiltIn::True), b(BuiltIn::False));
+                let f = arena.ap(b(BuiltIn::True), f);
+                Step::Reduced(arena.ap(arg0, f))
+            }
+            Value_::BuiltIn(BuiltIn::Draw) => {
+                if let Some(step) = require(arena, &[arg0]) {
+                    return Ok(step);
+                }
+                let points = as_list(arena, arg0, BuiltIn::Draw)?;
+                Step::Reduced(arena.picture(PictureBuilder::from_nested_list_one(points)?))
+            }
+            Value_::BuiltIn(BuiltIn::MultipleDraw) => {
+                if let Some(step) = require(arena, &[arg0]) {
+                    return Ok(step);
+                }
+                let pictures =
+                    PictureBuilder::from_nested_list(as_list(arena, arg0, BuiltIn::MultipleDraw)?)?;
+                let list = pictures.into_iter().rev().fold(b(BuiltIn::Nil), |tail, p| {
+                    let p = arena.picture(p);
+                    let cons = arena.ap(b(BuiltIn::Cons), p);
+                    arena.ap(cons, tail)
+                });
+                Step::Reduced(list)
+            }
+            Value_::BuiltIn(BuiltIn::Modem) => {
+                if let Some(step) = require(arena, &[arg0]) {
+                    return Ok(step);
+                }
+                let list = as_list(arena, arg0, BuiltIn::Modem)?;
+                Step::Reduced(dem_list(&mod_list(&list)).into_value(arena))
+            }
+            Value_::BuiltIn(BuiltIn::Send) => {
+                if let Some(step) = require(arena, &[arg0]) {
+                    return Ok(step);
+                }
+                let list = as_list(arena, arg0, BuiltIn::Send)?;
+                Step::Reduced(self.send(list)?.into_value(arena))
+            }
+
+            // ===== Arity 2 =====
+            Value_::Apply(f1, arg1) => {
+                if let Some(step) = require(arena, &[f1]) {
+                    return Ok(step);
+                }
+                match *arena.get(f1) {
+                    Value_::BuiltIn(BuiltIn::Add) => {
+                        binary(arena, arg1, arg0, BuiltIn::Add, |a, x, y| {
+                            Ok(a.number(x + y))
+                        })?
+                    }
+                    Value_::BuiltIn(BuiltIn::Mul) => {
+                        binary(arena, arg1, arg0, BuiltIn::Mul, |a, x, y| {
+                            Ok(a.number(x * y))
+                        })?
+                    }
+                    Value_::BuiltIn(BuiltIn::Div) => {
+                        binary(arena, arg1, arg0, BuiltIn::Div, |a, x, y| {
+                            if y.is_zero() {
+                                Err(EvalError::DivisionByZero)
+                            } else {
+                                Ok(a.number(x / y))
                             }
-                            Value_::BuiltIn(BuiltIn::Lt) => {
-                                if self.eval(arg1.clone()).borrow().unwrap_number()
-                                    < self.eval(arg0.clone()).borrow().unwrap_number()
-                                {
-                                    b(BuiltIn::True)
-                                } else {
-                                    b(BuiltIn::False)
+                        })?
+                    }
+                    Value_::BuiltIn(BuiltIn::Eq) => {
+                        binary(arena, arg1, arg0, BuiltIn::Eq, |_, x, y| {
+                            Ok(bool_value(x == y))
+                        })?
+                    }
+                    Value_::BuiltIn(BuiltIn::Lt) => {
+                        binary(arena, arg1, arg0, BuiltIn::Lt, |_, x, y| {
+                            Ok(bool_value(x < y))
+                        })?
+                    }
+                    Value_::BuiltIn(BuiltIn::True) => Step::Reduced(arg1),
+                    Value_::BuiltIn(BuiltIn::False) => Step::Reduced(arg0),
+                    Value_::BuiltIn(BuiltIn::Cons) => {
+                        if let Some(step) = require(arena, &[arg1, arg0]) {
+                            return Ok(step);
+                        }
+                        Step::Reduced(arena.cons(arg1, arg0))
+                    }
+                    Value_::BuiltIn(BuiltIn::Checkerboard) => {
+                        if let Some(step) = require(arena, &[arg1]) {
+                            return Ok(step);
+                        }
+                        let size = arena.as_number(arg1, BuiltIn::Checkerboard)?.to_i64();
+                        let size = size.ok_or(EvalError::TypeMismatch {
+                            builtin: BuiltIn::Checkerboard,
+                            expected: "64-bit number",
+                        })?;
+                        if size > 0 && size.saturating_mul(size) > MAX_CHECKERBOARD_POINTS {
+                            return Err(EvalError::TooBig {
+                                builtin: BuiltIn::Checkerboard,
+                                max: "2^24 points",
+                            });
+                        }
+                        let mut points = vec![];
+                        for x in 0..size {
+                            for y in 0..size {
+                                *steps += 1;
+                                self.check_limits(arena, *steps, start)?;
+                                if (x + y) % 2 == 0 {
+                                    points.push((x, y));
                                 }
                             }
-                            Value_::BuiltIn(BuiltIn::True) => arg1.clone(),
-                            Value_::BuiltIn(BuiltIn::False) => arg0.clone(),
+                        }
+                        Step::Reduced(arena.picture(PictureBuilder::from_points(points)))
+                    }
+                    Value_::BuiltIn(BuiltIn::F38) => {
+                        // ap ap f38 x2 x0 = ap ap ap if0 ap car x0
+                        //     ( ap modem ap car ap cdr x0 , ap multipledraw ap car ap cdr ap cdr x0 )
+                        //     ap ap ap interact x2 ap modem ap car ap cdr x0 ap send ap car ap cdr ap cdr x0
+                        let car = |a: &mut Arena, x| a.ap(b(BuiltIn::Head), x);
+                        let cdr = |a: &mut Arena, x| a.ap(b(BuiltIn::Tail), x);
+                        let flag = car(arena, arg0);
+                        let rest = cdr(arena, arg0);
+                        let new_state = car(arena, rest);
+                        let rest = cdr(arena, rest);
+                        let data = car(arena, rest);
+                        let modem = arena.ap(b(BuiltIn::Modem), new_state);
+                        let draw = arena.ap(b(BuiltIn::MultipleDraw), data);
+                        let done = list2(arena, modem, draw);
+                        let if0 = arena.ap(b(BuiltIn::If0), flag);
+                        let if0 = arena.ap(if0, done);
+                        let interact = arena.ap(b(BuiltIn::Interact), arg1);
+                        let interact = arena.ap(interact, modem);
+                        let send = arena.ap(b(BuiltIn::Send), data);
+                        let interact = arena.ap(interact, send);
+                        Step::Reduced(arena.ap(if0, interact))
+                    }
+
+                    // ===== Arity 3 =====
+                    Value_::Apply(f2, arg2) => {
+                        if let Some(step) = require(arena, &[f2]) {
+                            return Ok(step);
+                        }
+                        match *arena.get(f2) {
+                            Value_::BuiltIn(BuiltIn::S) => {
+                                let x = arena.ap(arg2, arg0);
+                                let y = arena.ap(arg1, arg0);
+                                Step::Reduced(arena.ap(x, y))
+                            }
+                            Value_::BuiltIn(BuiltIn::C) => {
+                                let f = arena.ap(arg2, arg0);
+                                Step::Reduced(arena.ap(f, arg1))
+                            }
+                            Value_::BuiltIn(BuiltIn::B) => {
+                                let x = arena.ap(arg1, arg0);
+                                Step::Reduced(arena.ap(arg2, x))
+                            }
                             Value_::BuiltIn(BuiltIn::Cons) => {
-                                let cons = ap(
-                                    ap(b(BuiltIn::Cons), self.eval(arg1.clone())),
-                                    self.eval(arg0.clone()),
-                                );
-                                cons.borrow_mut().computed = true;
-                                cons
+                                let f = arena.ap(arg0, arg2);
+                                Step::Reduced(arena.ap(f, arg1))
                             }
-
-                            // ===== Arity 3 =====
-                            Value_::Apply(f2, arg2) => match &self.eval(f2.clone()).borrow().val {
-                                Value_::BuiltIn(BuiltIn::S) => ap(
-                                    ap(arg2.clone(), arg0.clone()),
-                                    ap(arg1.clone(), arg0.clone()),
-                                ),
-                                Value_::BuiltIn(BuiltIn::C) => {
-                                    ap(ap(arg2.clone(), arg0.clone()), arg1.clone())
-                                }
-                                Value_::BuiltIn(BuiltIn::B) => {
-                                    ap(arg2.clone(), ap(arg1.clone(), arg0.clone()))
+                            Value_::BuiltIn(BuiltIn::If0) => {
+                                if let Some(step) = require(arena, &[arg2]) {
+                                    return Ok(step);
                                 }
-                                Value_::BuiltIn(BuiltIn::Cons) => {
-                                    ap(ap(arg0.clone(), arg2.clone()), arg1.clone())
+                                if arena.as_number(arg2, BuiltIn::If0)?.is_zero() {
+                                    Step::Reduced(arg1)
+                                } else {
+                                    Step::Reduced(arg0)
                                 }
-                                _ => val,
-                            },
-                            _ => val,
+                            }
+                            Value_::BuiltIn(BuiltIn::Interact) => {
+                                // ap ap ap interact x2 x4 x3 = ap ap f38 x2 ap ap x2 x4 x3
+                                let f38 = arena.ap(b(BuiltIn::F38), arg2);
+                                let run = arena.ap(arg2, arg1);
+                                let run = arena.ap(run, arg0);
+                                Step::Reduced(arena.ap(f38, run))
+                            }
+                            _ => Step::Done,
                         }
                     }
-                    _ => val,
+                    _ => Step::Done,
                 }
             }
-        }
+            _ => Step::Done,
+        };
+        Ok(step)
     }
 
-    pub fn interpret(&mut self, stmt: Stmt) {
+    pub fn interpret(&mut self, stmt: Stmt) -> Result<(), EvalError> {
         // println!("Compiling {:?}", stmt.var);
         // println!("Raw: {:?}", stmt.code);
-        let v = self.compile(stmt.code);
+        let Stmt { var, code } = stmt;
+        let v = self.compile(code).map_err(|()| {
+            EvalError::Arity(format!("unbalanced `ap` in the definition of {:?}", var))
+        })?;
         // println!("Compiled: {:?}", v);
-        self.vars.insert(stmt.var, v);
+        if self.backend == Backend::Compiled {
+            self.compiled.remove(&var);
+            self.compiled.extend(compile(self.arena.get_mut(), &var, v));
+        }
+        if let Some(memo) = self.memo.get_mut() {
+            memo.define(self.arena.get_mut(), &var, v);
+        }
+        self.vars.insert(var, v);
+        Ok(())
     }
 
-    fn compile(&self, code: Vec<Token>) -> Value {
+    fn compile(&mut self, code: Vec<Token>) -> Result<Value, ()> {
+        let arena = self.arena.get_mut();
         let mut stack: Vec<Value> = vec![];
         for token in code.into_iter().rev() {
             match token {
-                Token::Var(v) => stack.push(var(v)),
-
-                Token::Number(n) => stack.push(number(n)),
-                Token::True => stack.push(b(BuiltIn::True)),
-                Token::False => stack.push(b(BuiltIn::False)),
-                Token::Nil => stack.push(b(BuiltIn::Nil)),
-
-                Token::Inc => stack.push(b(BuiltIn::Inc)),
-                Token::Dec => stack.push(b(BuiltIn::Dec)),
-                Token::Add => stack.push(b(BuiltIn::Add)),
-                Token::Mul => stack.push(b(BuiltIn::Mul)),
-                Token::Div => stack.push(b(BuiltIn::Div)),
-                Token::Eq => stack.push(b(BuiltIn::Eq)),
-                Token::Lt => stack.push(b(BuiltIn::Lt)),
-                Token::Neg => stack.push(b(BuiltIn::Neg)),
-                Token::S => stack.push(b(BuiltIn::S)),
-                Token::C => stack.push(b(BuiltIn::C)),
-                Token::B => stack.push(b(BuiltIn::B)),
-                Token::Pwr2 => stack.push(b(BuiltIn::Pwr2)),
-                Token::I => stack.push(b(BuiltIn::I)),
-                Token::Cons => stack.push(b(BuiltIn::Cons)),
-                Token::Head => stack.push(b(BuiltIn::Head)),
-                Token::Tail => stack.push(b(BuiltIn::Tail)),
-                Token::IsNil => stack.push(b(BuiltIn::IsNil)),
-
+                Token::Var(v) => stack.push(arena.var(v)),
+                Token::Number(n) => stack.push(arena.number(n)),
                 Token::Ap => {
-                    let x = stack.pop().unwrap();
-                    let v = stack.pop().unwrap();
-                    stack.push(ap(x, v));
+                    let x = stack.pop().ok_or(())?;
+                    let v = stack.pop().ok_or(())?;
+                    stack.push(arena.ap(x, v));
+                }
+                token => stack.push(b(BuiltIn::from_token(&token).ok_or(())?)),
+            }
+        }
+        if stack.len() != 1 {
+            return Err(());
+        }
+        Ok(stack[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::parse_line;
+
+    // Deep enough to overflow the default test thread stack if anything recursed on it
+    const DEPTH: usize = 100_000;
+
+    fn eval_lines(lines: &[&str]) -> (State, Value) {
+        let mut state = State::new();
+        for line in lines {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        let v = state.eval_v(&Var::Named("x".to_string())).unwrap();
+        (state, v)
+    }
+
+    fn eval_number(lines: &[&str]) -> Value_ {
+        let (state, v) = eval_lines(lines);
+        let val = state.arena().get(v).clone();
+        val
+    }
+
+    #[test]
+    fn test_deep_cons_chain() {
+        let code = format!("x = {}nil", "ap ap cons 1 ".repeat(DEPTH));
+        let (state, mut list) = eval_lines(&[&code]);
+        let arena = state.arena();
+        let mut len = 0;
+        loop {
+            list = match arena.get(list) {
+                Value_::Apply(f, tail) => {
+                    if let Value_::Apply(_, head) = arena.get(*f) {
+                        assert_eq!(*arena.get(*head), Value_::Number(1.into()));
+                    }
+                    *tail
                 }
+                Value_::BuiltIn(BuiltIn::Nil) => break,
+                v => panic!("Unexpected value in the list: {:?}", v),
+            };
+            len += 1;
+        }
+        assert_eq!(len, DEPTH);
+    }
+
+    #[test]
+    fn test_deep_argument_nesting() {
+        let code = format!("x = {}0", "ap inc ".repeat(DEPTH));
+        assert_eq!(eval_number(&[&code]), Value_::Number(DEPTH.into()));
+    }
+
+    #[test]
+    fn test_long_ap_spine() {
+        let code = format!("x = {}{}5", "ap ".repeat(DEPTH), "i ".repeat(DEPTH));
+        assert_eq!(eval_number(&[&code]), Value_::Number(5.into()));
+    }
+
+    #[test]
+    fn test_self_reference() {
+        let v = eval_number(&[":1 = ap f :1", "x = ap :1 42"]);
+        assert_eq!(v, Value_::Number(42.into()));
+    }
+
+    #[test]
+    fn test_big_numbers() {
+        let v = eval_number(&["x = ap ap mul ap pwr2 100 ap ap add 9223372036854775807 1"]);
+        let expected = (Number::from(1) << 100usize) * (Number::from(1) << 63usize);
+        assert_eq!(v, Value_::Number(expected));
+
+        let mut state = State::new();
+        state
+            .interpret(parse_line("x = ap pwr2 100000000000").unwrap())
+            .unwrap();
+        let err = state.eval_v(&Var::Named("x".to_string())).unwrap_err();
+        assert!(matches!(
+            err,
+            EvalError::TooBig {
+                builtin: BuiltIn::Pwr2,
+                ..
             }
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let spin = |limits| {
+            let mut state = State::new();
+            state.set_limits(limits);
+            for line in &["omega = ap ap s i i", "x = ap omega omega", "y = ap inc 1"] {
+                state.interpret(parse_line(line).unwrap()).unwrap();
+            }
+            let y = state.eval_v(&Var::Named("y".to_string())).map(|_| ());
+            (state.eval_v(&Var::Named("x".to_string())).map(|_| ()), y)
+        };
+        let steps = Limits {
+            steps: Some(1000),
+            ..Limits::default()
+        };
+        let exceeded = Err(EvalError::LimitExceeded(Limit::Steps(1000)));
+        assert_eq!(spin(steps), (exceeded, Ok(())));
+        let nodes = Limits {
+            nodes: Some(10_000),
+            ..Limits::default()
+        };
+        let exceeded = Err(EvalError::LimitExceeded(Limit::Nodes(10_000)));
+        assert_eq!(spin(nodes), (exceeded, Ok(())));
+        let time = Limits {
+            time: Some(Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        let exceeded = Err(EvalError::LimitExceeded(Limit::Time(
+            Duration::from_millis(10),
+        )));
+        assert_eq!(spin(time), (exceeded, Ok(())));
+
+        // A built-in that loops counts its steps in
+        let mut state = State::new();
+        state.set_limits(steps);
+        for line in &[
+            "x = ap ap checkerboard 1000 0",
+            "y = ap ap checkerboard x 0",
+            "z = ap ap checkerboard 1000000000 0",
+        ] {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        let x = state.eval_v(&Var::Named("x".to_string())).map(|_| ());
+        assert_eq!(x, Err(EvalError::LimitExceeded(Limit::Steps(1000))));
+        state
+            .interpret(parse_line("x = ap pwr2 64").unwrap())
+            .unwrap();
+        let y = state.eval_v(&Var::Named("y".to_string())).map(|_| ());
+        assert!(matches!(y, Err(EvalError::TypeMismatch { .. })), "{:?}", y);
+        // Too many points fail at once, even without limits
+        state.set_limits(Limits::default());
+        let z = state.eval_v(&Var::Named("z".to_string())).map(|_| ());
+        assert!(matches!(z, Err(EvalError::TooBig { .. })), "{:?}", z);
+    }
+
+    #[test]
+    fn test_collect() {
+        let mut state = State::new();
+        state
+            .interpret(parse_line("x = ap ap cons 1 nil").unwrap())
+            .unwrap();
+        let sum = parse_line("y = ap ap add 1 ap ap mul 2 3").unwrap();
+        state.interpret(sum).unwrap();
+        let len = state.arena().len();
+        for _ in 0..100 {
+            let y = state.definition(&Var::Named("y".to_string())).unwrap();
+            let v = state.arena_mut().ap(b(BuiltIn::Inc), y);
+            state.eval(v).unwrap();
         }
-        assert!(stack.len() == 1);
-        stack[0].clone()
+        state.collect();
+        assert!(state.arena().len() <= len);
+        let y = state.eval_v(&Var::Named("y".to_string())).unwrap();
+        assert_eq!(*state.arena().get(y), Value_::Number(7.into()));
     }
 }
@@ -0,0 +1,99 @@
+use std::fmt;
+
+use crate::eval::{BuiltIn, EvalError, State, Value, Value_};
+use crate::syntax::{Number, Stmt, Var};
+
+// What runs definitions: the graph of `eval::State` or the closures of `closures`. Both
+// evaluate to the same data, which is all that's compared between them.
+pub trait Evaluator {
+    fn interpret(&mut self, stmt: Stmt) -> Result<(), EvalError>;
+
+    // Evaluates a definition and everything in it that's data
+    fn eval_data(&mut self, var: &Var) -> Result<Data, EvalError>;
+}
+
+// Which evaluator `test` and `eval` use
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum EvaluatorKind {
+    // `eval::State`, with any backend
+    #[default]
+    Graph,
+    Closures,
+}
+
+// The result of an evaluation as far as it can be compared between evaluators
+#[derive(Debug, PartialEq, Clone)]
+pub enum Data {
+    Number(Number),
+    Bool(bool),
+    Nil,
+    Cons(Box<Data>, Box<Data>),
+    // Anything else, which needs more arguments. Evaluators make functions their own way,
+    // so they are all the same here.
+    Function,
+}
+
+impl Data {
+    // `heads` in front of `last`
+    pub fn list(heads: Vec<Data>, last: Data) -> Data {
+        (heads.into_iter().rev()).fold(last, |tail, head| {
+            Data::Cons(Box::new(head), Box::new(tail))
+        })
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Data::Number(n) => write!(f, "{}", n),
+            Data::Bool(true) => write!(f, "t"),
+            Data::Bool(false) => write!(f, "f"),
+            Data::Nil => write!(f, "nil"),
+            Data::Cons(head, tail) => write!(f, "ap ap cons {} {}", head, tail),
+            Data::Function => write!(f, "<function>"),
+        }
+    }
+}
+
+// The head and tail of `val` if it's a pair
+fn pair(state: &State, val: Value) -> Option<(Value, Value)> {
+    let arena = state.arena();
+    match arena.get(val) {
+        Value_::Apply(f, tail) => match arena.get(*f) {
+            Value_::Apply(cons, head) if *arena.get(*cons) == Value_::BuiltIn(BuiltIn::Cons) => {
+                Some((*head, *tail))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn data(state: &State, val: Value) -> Result<Data, EvalError> {
+    // Long lists are walked along their tails in a loop, only the heads recurse
+    let mut heads = vec![];
+    let mut curr = state.eval(val)?;
+    while let Some((head, tail)) = pair(state, curr) {
+        heads.push(data(state, head)?);
+        curr = state.eval(tail)?;
+    }
+    let last = match state.arena().get(curr) {
+        Value_::Number(n) => Data::Number(n.clone()),
+        Value_::BuiltIn(BuiltIn::Nil) => Data::Nil,
+        Value_::BuiltIn(BuiltIn::True) => Data::Bool(true),
+        Value_::BuiltIn(BuiltIn::False) => Data::Bool(false),
+        _ => Data::Function,
+    };
+    Ok(Data::list(heads, last))
+}
+
+impl Evaluator for State {
+    fn interpret(&mut self, stmt: Stmt) -> Result<(), EvalError> {
+        State::interpret(self, stmt)
+    }
+
+    fn eval_data(&mut self, var: &Var) -> Result<Data, EvalError> {
+        let val = self.eval_v(var)?;
+        data(self, val)
+    }
+}
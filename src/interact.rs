@@ -1,64 +1,234 @@
+use std::fmt;
+
+use num_traits::Zero;
+
 use crate::eval::*;
-use crate::modem::*;
-use crate::send::request;
 use crate::syntax::*;
 use crate::types::*;
 
-fn interact(
-    state: &mut State,
-    protocol: &Var,
-    st: NestedList,
-    coords: NestedList,
-) -> (NestedList, NestedList) {
-    let coords = coords.into_value();
-    let protocol_run = ap(ap(var(protocol.clone()), st.into_value()), coords);
-    let result = state.eval(protocol_run);
-    let list = NestedList::from_value(result);
-    let (a, bcnil) = list.unwrap_cons();
-    let (b, cnil) = bcnil.unwrap_cons();
-    let (c, nil) = cnil.unwrap_cons();
-    assert_eq!(nil, NestedList::Nil);
-    let flag = a.unwrap_number();
-    if flag == 0 {
-        (b, c)
-    } else {
-        let signal = mod_list(&c);
-        let signal_data: Vec<_> = signal
-            .into_iter()
-            .map(|x| if x { b'1' } else { b'0' })
-            .collect();
-        let endpoint = String::from("https://icfpc2020-api.testkontur.ru/aliens/send");
-        let token = "4f4e0ad4dc564c1285a284be8734c9af";
-        let response = match request(&endpoint, &token, signal_data) {
-            Ok(val) => val,
-            Err(err) => panic!("request failed: {:?}", err),
+// What a single evaluation of the protocol returned: `[flag, newState, data]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InteractionStep {
+    pub flag: Number,
+    pub state: NestedList,
+    pub data: NestedList,
+}
+
+// The protocol returned flag 0, `data` is what should be drawn
+#[derive(Debug, Clone, PartialEq)]
+pub struct InteractionOutcome {
+    pub state: NestedList,
+    pub data: NestedList,
+    pub sends: usize,
+}
+
+#[derive(Debug)]
+pub enum InteractionError {
+    Eval(EvalError),
+    // The protocol didn't return `[flag, newState, data]`
+    Malformed(String),
+    // The protocol kept sending past the limit
+    TooManySends(usize),
+}
+
+impl fmt::Display for InteractionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InteractionError::Eval(e) => write!(f, "{}", e),
+            InteractionError::Malformed(msg) => write!(f, "Malformed protocol result: {}", msg),
+            InteractionError::TooManySends(n) => {
+                write!(f, "The protocol didn't stop after {} sends", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InteractionError {}
+
+impl From<EvalError> for InteractionError {
+    fn from(e: EvalError) -> Self {
+        InteractionError::Eval(e)
+    }
+}
+
+type Observer<'a> = Box<dyn FnMut(&InteractionStep) + 'a>;
+
+// Drives a protocol one evaluation at a time, sending to the aliens while it asks to
+pub struct Interaction<'a> {
+    state: &'a State,
+    protocol: Var,
+    max_sends: Option<usize>,
+    observers: Vec<Observer<'a>>,
+}
+
+impl<'a> Interaction<'a> {
+    pub fn new(state: &'a State, protocol: &str) -> Self {
+        Interaction {
+            state,
+            protocol: Var::Named(protocol.to_string()),
+            max_sends: None,
+            observers: vec![],
+        }
+    }
+
+    pub fn max_sends(mut self, max_sends: usize) -> Self {
+        self.max_sends = Some(max_sends);
+        self
+    }
+
+    // Called with the result of every evaluation, including the ones that send
+    pub fn observe<F: FnMut(&InteractionStep) + 'a>(mut self, observer: F) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    // Evaluates the protocol once, without sending anything
+    pub fn step(
+        &mut self,
+        st: NestedList,
+        input: NestedList,
+    ) -> Result<InteractionStep, InteractionError> {
+        let protocol_run = {
+            let mut arena = self.state.arena_mut();
+            let protocol = arena.var(self.protocol.clone());
+            let st = st.into_value(&mut arena);
+            let input = input.into_value(&mut arena);
+            let f = arena.ap(protocol, st);
+            arena.ap(f, input)
         };
+        let list = self
+            .state
+            .eval(protocol_run)
+            .map(|result| NestedList::from_value(&self.state.arena(), result));
+        // Nothing but the definitions is needed for the next evaluation, even after a failed one
+        self.state.collect();
+        let malformed = || InteractionError::Malformed("expected a list of 3 elements".to_string());
+        let list = list?.map_err(|_| malformed())?;
+        let (flag, rest) = list.unwrap_cons().map_err(|_| malformed())?;
+        let (state, rest) = rest.unwrap_cons().map_err(|_| malformed())?;
+        let (data, nil) = rest.unwrap_cons().map_err(|_| malformed())?;
+        if nil != NestedList::Nil {
+            return Err(malformed());
+        }
+        let flag = flag
+            .unwrap_number()
+            .map_err(|_| InteractionError::Malformed("the flag must be a number".to_string()))?;
+        let step = InteractionStep { flag, state, data };
+        for observer in self.observers.iter_mut() {
+            observer(&step);
+        }
+        Ok(step)
+    }
 
-        let signal: Vec<_> = response
-            .into_iter()
-            .map(|c| c == b'1')
-            .collect();
+    // Steps the protocol until it stops sending
+    pub fn run(
+        &mut self,
+        mut st: NestedList,
+        mut input: NestedList,
+    ) -> Result<InteractionOutcome, InteractionError> {
+        let mut sends = 0;
+        loop {
+            let step = self.step(st, input)?;
+            if step.flag.is_zero() {
+                return Ok(InteractionOutcome {
+                    state: step.state,
+                    data: step.data,
+                    sends,
+                });
+            }
+            if matches!(self.max_sends, Some(max) if sends >= max) {
+                return Err(InteractionError::TooManySends(sends));
+            }
+            input = self.state.send(step.data)?;
+            st = step.state;
+            sends += 1;
+        }
+    }
 
-        interact(state, protocol, b, dem_list(&signal))
+    pub fn click(
+        &mut self,
+        st: NestedList,
+        x: i64,
+        y: i64,
+    ) -> Result<InteractionOutcome, InteractionError> {
+        let point = NestedList::Cons(
+            Box::new(NestedList::Number(x.into())),
+            Box::new(NestedList::Number(y.into())),
+        );
+        self.run(st, point)
     }
 }
 
 pub fn run_interaction(
-    state: &mut State,
+    state: &State,
     protocol: &str,
     st: NestedList,
     x: i64,
     y: i64,
-) -> (NestedList, NestedList) {
-    let var_protocol = Var::Named(protocol.to_string());
-    let (state, pic_list) = interact(
-        state,
-        &var_protocol,
-        st,
-        NestedList::Cons(
-            Box::new(NestedList::Number(x)),
-            Box::new(NestedList::Number(y)),
-        ),
-    );
-    (state, pic_list)
+) -> Result<(NestedList, NestedList), InteractionError> {
+    let outcome = Interaction::new(state, protocol).click(st, x, y)?;
+    Ok((outcome.state, outcome.data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send::MockTransport;
+
+    fn state_with(lines: &[&str]) -> State {
+        let mut state = State::new();
+        state.set_transport(Box::new(MockTransport::new(|request| Ok(request.clone()))));
+        for line in lines {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        state
+    }
+
+    fn n(x: i64) -> NestedList {
+        NestedList::Number(x.into())
+    }
+
+    #[test]
+    fn test_steps() {
+        // Sends the click while the state is 0, then draws what came back
+        let state = state_with(&[
+            "sendfirst = ap ap b ap ap c s sendfirst_last ap ap b ap ap c b sendfirst_send if0",
+            "sendfirst_send = ap ap b ap cons 1 ap ap b ap cons 1 ap ap c cons nil",
+            "sendfirst_last = ap ap b ap cons 0 ap ap c cons ap ap cons nil nil",
+        ]);
+        let mut flags = vec![];
+        let outcome = Interaction::new(&state, "sendfirst")
+            .observe(|step| flags.push(step.flag.clone()))
+            .click(n(0), 1, 2)
+            .unwrap();
+        let point = NestedList::Cons(Box::new(n(1)), Box::new(n(2)));
+        assert_eq!(
+            outcome,
+            InteractionOutcome {
+                state: point,
+                data: NestedList::Nil,
+                sends: 1,
+            }
+        );
+        assert_eq!(flags, vec![1.into(), 0.into()]);
+    }
+
+    #[test]
+    fn test_max_sends() {
+        let state = state_with(&[
+            "alwayssend = ap ap b ap b ap cons 1 ap ap b ap ap c b ap ap c cons nil cons",
+        ]);
+        let result =
+            Interaction::new(&state, "alwayssend")
+                .max_sends(3)
+                .click(NestedList::Nil, 0, 0);
+        assert!(matches!(result, Err(InteractionError::TooManySends(3))));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let state = state_with(&["five = ap t ap t 5"]);
+        let result = Interaction::new(&state, "five").click(NestedList::Nil, 0, 0);
+        assert!(matches!(result, Err(InteractionError::Malformed(_))));
+    }
 }
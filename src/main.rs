@@ -1,91 +1,522 @@
-use std::{env, fs, io, thread};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io, process};
 
+mod arena;
+mod bench;
+mod check;
+mod cli;
+mod closures;
+mod compiled;
+mod decompile;
+mod differential;
 mod eval;
+mod evaluator;
 mod interact;
+mod memo;
 mod modem;
+mod optimize;
+mod printer;
+mod profile;
+mod repl;
+mod replay;
+mod script;
 mod send;
 mod syntax;
+mod trace;
 mod types;
 mod ui;
+mod worker;
 
-use crate::eval::State;
+use crate::bench::run_bench;
+use crate::check::Checker;
+use crate::cli::{parse_args, Command, Options, USAGE};
+use crate::closures::Closures;
+use crate::decompile::decompile;
+use crate::differential::{compare_file, compare_random, RANDOM_PROGRAMS};
+use crate::eval::{State, Value};
+use crate::evaluator::{Evaluator, EvaluatorKind};
+use crate::modem::*;
+use crate::printer::{print_stmt, print_value_sugared, var_name};
+use crate::repl::Repl;
+use crate::replay::{parse_log, RecordingTransport, ReplayTransport};
+use crate::script::{frame_to_bmp, parse_script, write_pictures};
+use crate::send::{transport_from_env, HttpTransport, Transport};
 use crate::syntax::*;
+use crate::trace::Debugger;
 use crate::types::*;
 use crate::ui::ui_main;
 
-fn print_pictures(pics: &[Picture]) {
-    if pics.len() == 1 {
-        println!("{}", pics[0]);
+// How many definitions and built-ins `--profile` prints unless it's told
+const PROFILE_TOP: usize = 20;
+
+fn print_pictures(pics: &[Picture]) -> io::Result<()> {
+    write_pictures(&mut io::stdout(), pics)
+}
+
+// Returns whether the line passed, definitions and drawings always do
+fn run_test_line(state: &mut State, line: &str, options: &Options) -> Result<bool, Box<dyn Error>> {
+    if let Some(l) = line.strip_prefix("DRAW ") {
+        let picture = parse_picture(l).map_err(|mut e| {
+            e.column += "DRAW ".len();
+            e
+        })?;
+        state.interpret(picture)?;
+        let v = state.eval_v(&Var::Named("picture".to_string()))?;
+        let pics = PictureBuilder::from_value(&state.arena(), v)?;
+        if !options.quiet() {
+            print_pictures(&pics)?;
+        }
+    } else if is_definition(line) {
+        state.interpret(parse_line(line)?)?;
     } else {
-        for (i, p) in pics.iter().enumerate() {
-            println!("Picture #{}", i);
-            println!("{}", p);
+        let (expr, expected) = parse_test(line)?;
+        state.interpret(expr)?;
+        state.interpret(expected)?;
+        let actual = state.eval_v(&Var::Named("expr".to_string()))?;
+        let expected = state.eval_v(&Var::Named("expected".to_string()))?;
+        let arena = state.arena();
+        if !arena.equal(actual, expected) {
+            println!("FAILED: {}", line);
+            println!("  expected: {}", print_value_sugared(&arena, expected));
+            println!("  actual:   {}", print_value_sugared(&arena, actual));
+            return Ok(false);
+        }
+        if options.verbose() {
+            println!("ok: {}", line);
+        }
+    }
+    Ok(true)
+}
+
+// Like `run_test` with another evaluator, which only runs definitions and checks
+fn run_test_with(evaluator: &mut dyn Evaluator, file: &str, options: &Options) -> usize {
+    let mut failures = 0;
+    // Skip the "TEST" line
+    for (i, line) in file.lines().enumerate().skip(1) {
+        let mut outcome = || -> Result<bool, Box<dyn Error>> {
+            if is_definition(line) {
+                evaluator.interpret(parse_line(line)?)?;
+                return Ok(true);
+            }
+            let (expr, expected) = parse_test(line)?;
+            let (expr_var, expected_var) = (expr.var.clone(), expected.var.clone());
+            evaluator.interpret(expr)?;
+            evaluator.interpret(expected)?;
+            let actual = evaluator.eval_data(&expr_var)?;
+            let expected = evaluator.eval_data(&expected_var)?;
+            if actual != expected {
+                println!("FAILED: {}", line);
+                println!("  expected: {}", expected);
+                println!("  actual:   {}", actual);
+                return Ok(false);
+            }
+            if options.verbose() {
+                println!("ok: {}", line);
+            }
+            Ok(true)
+        };
+        if is_blank(line) {
+        } else if let Some(l) = line.strip_prefix("PRINT ") {
+            if !options.quiet() {
+                println!("{}", l);
+            }
+        } else if line.starts_with("DRAW ") || line.starts_with("REPLAY ") {
+            println!("ERROR: {}", line);
+            println!("  Only the graph evaluator runs DRAW and REPLAY lines");
+            failures += 1;
+        } else {
+            match outcome() {
+                Ok(true) => {}
+                Ok(false) => failures += 1,
+                Err(e) => {
+                    println!("ERROR: {}", line);
+                    match e.downcast::<ParseError>() {
+                        Ok(e) => println!("  {}", e.at_line(i + 1)),
+                        Err(e) => println!("  {}", e),
+                    }
+                    failures += 1;
+                }
+            }
         }
     }
+    failures
 }
 
-fn run_test(file: String) {
+// Returns the number of failed lines
+fn run_test(
+    file: &str,
+    data_folder: &Path,
+    transport: Box<dyn Transport>,
+    options: &Options,
+) -> io::Result<usize> {
     let mut state = State::new();
+    state.set_transport(transport);
+    state.set_backend(options.backend);
+    state.set_limits(options.limits);
+    if let Some(trace) = options.trace() {
+        state.set_tracer(Some(trace.tracer()?));
+    }
+    state.set_profiling(options.profile.is_some());
+    let mut failures = 0;
     // Skip the "TEST" line
-    for line in file.lines().skip(1) {
-        if line.is_empty() {
+    for (i, line) in file.lines().enumerate().skip(1) {
+        if is_blank(line) {
         } else if let Some(l) = line.strip_prefix("PRINT ") {
-            println!("{}", l);
-        } else if let Some(l) = line.strip_prefix("DRAW ") {
-            let picture = parse_picture(l);
-            state.interpret(picture);
-            let v = state.eval_v(&Var::Named("picture".to_string()));
-            let list = NestedList::from_value(v);
-            let pics = PictureBuilder::from_nested_list(list);
-            print_pictures(&pics);
+            if !options.quiet() {
+                println!("{}", l);
+            }
+        } else if let Some(l) = line.strip_prefix("REPLAY ") {
+            // Answers `send` from a recorded log from here on
+            let replay = ReplayTransport::load(&data_folder.join(l))?;
+            state.set_transport(Box::new(replay));
         } else {
-            let (expr, expected) = parse_test(line);
-            state.interpret(expr);
-            state.interpret(expected);
-            assert_eq!(
-                state.eval_v(&Var::Named("expr".to_string())),
-                state.eval_v(&Var::Named("expected".to_string()))
-            );
+            match run_test_line(&mut state, line, options) {
+                Ok(true) => {}
+                Ok(false) => failures += 1,
+                Err(e) => {
+                    println!("ERROR: {}", line);
+                    match e.downcast::<ParseError>() {
+                        Ok(e) => println!("  {}", e.at_line(i + 1)),
+                        Err(e) => println!("  {}", e),
+                    }
+                    failures += 1;
+                }
+            }
         }
     }
+    report_profile(&state, options)?;
+    Ok(failures)
 }
 
-fn run() -> io::Result<()> {
-    let path = if env::args().len() == 2 {
-        env::args().nth(1).unwrap()
-    } else {
-        "./data/i_galaxy.txt".to_string()
+fn transport(options: &Options) -> io::Result<Box<dyn Transport>> {
+    let transport: Box<dyn Transport> = match (&options.replay, &options.record) {
+        (Some(log), _) => Box::new(ReplayTransport::load(log)?),
+        (None, Some(log)) => Box::new(RecordingTransport::create(HttpTransport::from_env(), log)?),
+        (None, None) => transport_from_env()?,
     };
-    let data_folder = std::path::Path::new(&path).parent().unwrap();
-    let file = fs::read_to_string(&path)?;
-    if file.starts_with("TEST") {
-        println!("Mode: test");
-        run_test(file);
-    } else if file.starts_with("INTERACTIVE") {
-        println!("Mode: interactive");
-        ui_main(file, &data_folder)?;
+    if options.verbose() {
+        // Every exchange is echoed in the log format
+        Ok(Box::new(RecordingTransport::new(
+            transport,
+            Box::new(io::stdout()),
+        )))
     } else {
-        println!("Mode: custom");
-        let mut state = State::new();
-        for line in file.lines() {
-            let stmt = parse_line(line);
-            state.interpret(stmt);
-        }
-        println!(
-            "galaxy: {:?}",
-            state.eval_v(&Var::Named("galaxy".to_string()))
-        );
+        Ok(transport)
+    }
+}
+
+fn read_file(path: &Path, options: &Options, header: &str) -> io::Result<(String, PathBuf)> {
+    let file = fs::read_to_string(path)?;
+    if !file.starts_with(header) {
+        let msg = format!("{} doesn't start with {}", path.display(), header);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
     }
+    let data_folder = match &options.data_folder {
+        Some(folder) => folder.clone(),
+        None => path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf(),
+    };
+    Ok((file, data_folder))
+}
+
+// Applies the options about how to evaluate, once the definitions are in
+fn configure(state: &mut State, options: &Options) -> io::Result<()> {
+    if options.optimize {
+        state.optimize();
+    }
+    state.set_backend(options.backend);
+    state.set_memo(options.memo);
+    state.set_limits(options.limits);
+    if let Some(trace) = options.trace() {
+        state.set_tracer(Some(trace.tracer()?));
+    }
+    state.set_profiling(options.profile.is_some());
     Ok(())
 }
 
-// Remove later if not needed
-const THREAD_STACK_SIZE: usize = 16 * 1024 * 1024;
+// With `--profile`, prints what evaluation spent the most on and writes its stacks
+fn report_profile(state: &State, options: &Options) -> io::Result<()> {
+    let (path, profile) = match (&options.profile, state.profile()) {
+        (Some(path), Some(profile)) => (path, profile),
+        _ => return Ok(()),
+    };
+    let top = options.profile_top.unwrap_or(PROFILE_TOP);
+    profile.write_top(&mut io::stdout(), top)?;
+    let mut out = BufWriter::new(File::create(path)?);
+    profile.write_folded(&mut out)?;
+    out.flush()
+}
+
+// A state with the includes and `expr` defined, but not evaluated yet
+fn load_expr(text: &str, options: &Options) -> Result<State, Box<dyn Error>> {
+    let mut state = State::new();
+    state.set_transport(transport(options)?);
+    for path in options.includes.iter() {
+        for stmt in parse_file(&path.display().to_string(), &fs::read_to_string(path)?)? {
+            state.interpret(stmt)?;
+        }
+    }
+    state.interpret(parse_expr(text)?)?;
+    configure(&mut state, options)?;
+    Ok(state)
+}
+
+// The value is in the arena of the state
+fn eval_expr(text: &str, options: &Options) -> Result<(State, Value), Box<dyn Error>> {
+    let state = load_expr(text, options)?;
+    let v = state.eval_v(&Var::Named("expr".to_string()))?;
+    Ok((state, v))
+}
 
-fn main() -> io::Result<()> {
-    let child_thread = thread::Builder::new()
-        .stack_size(THREAD_STACK_SIZE)
-        .spawn(run)?;
+// Returns whether the command succeeded
+fn run(command: Command, options: &Options) -> Result<bool, Box<dyn Error>> {
+    let closures = options.evaluator == EvaluatorKind::Closures;
+    if closures
+        && !matches!(
+            command,
+            Command::Test(_) | Command::Eval(_) | Command::Diff(_)
+        )
+    {
+        return Err("Only `test`, `eval` and `diff` can use the closures evaluator".into());
+    }
+    match command {
+        Command::Test(path) => {
+            let (file, data_folder) = read_file(&path, options, "TEST")?;
+            let failures = if closures {
+                run_test_with(&mut Closures::new(), &file, options)
+            } else {
+                run_test(&file, &data_folder, transport(options)?, options)?
+            };
+            if failures > 0 && !options.quiet() {
+                println!("{} failed", failures);
+            }
+            return Ok(failures == 0);
+        }
+        Command::Explore(path) => {
+            let (file, data_folder) = read_file(&path, options, "INTERACTIVE")?;
+            let (ui_options, finish_options) = (options.clone(), options.clone());
+            ui_main(
+                file,
+                &data_folder,
+                options.protocol.clone(),
+                transport(options)?,
+                move |state| configure(state, &ui_options),
+                move |state| {
+                    if let Err(e) = report_profile(state, &finish_options) {
+                        eprintln!("Couldn't write the profile: {}", e);
+                    }
+                },
+            )?;
+        }
+        Command::Script(path) => {
+            let (file, data_folder) = read_file(&path, options, "SCRIPT")?;
+            let mut script = parse_script(&file, &data_folder, transport(options)?)?;
+            script.set_backend(options.backend);
+            script.set_limits(options.limits);
+            if options.optimize {
+                script.optimize();
+            }
+            script.set_memo(options.memo);
+            script.set_profiling(options.profile.is_some());
+            if let Some(trace) = options.trace() {
+                script.set_tracer(trace.tracer()?);
+            }
+            if let Some(protocol) = &options.protocol {
+                script.set_protocol(protocol);
+            }
+            if let Some(output) = &options.output {
+                script.set_output(output.clone());
+            }
+            if let Some(format) = options.format {
+                script.set_format(format);
+            }
+            script.run(&mut io::stdout())?;
+            report_profile(script.state(), options)?;
+        }
+        Command::Eval(text) if closures => {
+            let mut closures = Closures::new();
+            for path in options.includes.iter() {
+                for stmt in parse_file(&path.display().to_string(), &fs::read_to_string(path)?)? {
+                    closures.interpret(stmt)?;
+                }
+            }
+            closures.interpret(parse_expr(&text)?)?;
+            println!("{}", closures.eval_data(&Var::Named("expr".to_string()))?);
+        }
+        Command::Eval(text) => {
+            let (state, v) = eval_expr(&text, options)?;
+            println!("{}", print_value_sugared(&state.arena(), v));
+            report_profile(&state, options)?;
+        }
+        Command::Debug(text) => {
+            // The debugger takes the place of `--trace`
+            let mut state = load_expr(&text, options)?;
+            let breakpoints = options.breakpoints.clone();
+            let debugger = Debugger::new(
+                io::BufReader::new(io::stdin()),
+                io::stdout(),
+                breakpoints,
+                state.cancel_token(),
+            );
+            state.set_tracer(Some(debugger.into_tracer()));
+            let v = state.eval_v(&Var::Named("expr".to_string()))?;
+            println!("{}", print_value_sugared(&state.arena(), v));
+        }
+        Command::Modulate(text) => {
+            let (state, v) = eval_expr(&text, options)?;
+            let list = NestedList::from_value(&state.arena(), v)?;
+            let signal: String = mod_list(&list)
+                .into_iter()
+                .map(|x| if x { '1' } else { '0' })
+                .collect();
+            println!("{}", signal);
+        }
+        Command::Demodulate(signal) => {
+            let bits: Option<Vec<_>> = signal
+                .trim()
+                .chars()
+                .map(|c| match c {
+                    '0' => Some(false),
+                    '1' => Some(true),
+                    _ => None,
+                })
+                .collect();
+            match bits.and_then(|bits| try_dem_list(&bits).ok()) {
+                Some(list) => println!("{}", list),
+                None => return Err("Invalid signal".into()),
+            }
+        }
+        Command::Render(text) => {
+            let (state, v) = eval_expr(&text, options)?;
+            let pics = PictureBuilder::from_value(&state.arena(), v)?;
+            match &options.output {
+                Some(path) => frame_to_bmp(&pics)?.save(path)?,
+                None => print_pictures(&pics)?,
+            }
+        }
+        Command::Replay(path) => {
+            for (request, response) in parse_log(&fs::read_to_string(path)?)? {
+                let show = |bits: &[bool]| match try_dem_list(bits) {
+                    Ok(list) => list.to_string(),
+                    Err(()) => "<malformed>".to_string(),
+                };
+                println!("{} -> {}", show(&request), show(&response));
+            }
+        }
+        Command::Print(path) => {
+            let mut state = State::new();
+            let name = path.display().to_string();
+            for stmt in parse_file(&name, &fs::read_to_string(&path)?)? {
+                if !options.sugar {
+                    println!("{}", print_stmt(&stmt));
+                    continue;
+                }
+                let var = stmt.var.clone();
+                state.interpret(stmt)?;
+                let val = state.definition(&var).unwrap();
+                println!(
+                    "{} = {}",
+                    var_name(&var),
+                    print_value_sugared(&state.arena(), val)
+                );
+            }
+        }
+        Command::Decompile(path) => {
+            let mut state = State::new();
+            let name = path.display().to_string();
+            for stmt in parse_file(&name, &fs::read_to_string(&path)?)? {
+                let var = stmt.var.clone();
+                state.interpret(stmt)?;
+                let val = state.definition(&var).unwrap();
+                println!("{}", decompile(&state.arena(), &var, val));
+            }
+        }
+        Command::Check(path) => {
+            let mut state = State::new();
+            let name = path.display().to_string();
+            let mut vars = vec![];
+            for stmt in parse_file(&name, &fs::read_to_string(&path)?)? {
+                vars.push(stmt.var.clone());
+                state.interpret(stmt)?;
+            }
+            let checker = Checker::new(&state);
+            let mut failures = 0;
+            for var in vars.iter() {
+                let errors = checker.errors(var);
+                for error in errors.iter() {
+                    println!("{}: {}", var_name(var), error);
+                }
+                if !errors.is_empty() {
+                    failures += 1;
+                } else if options.verbose() {
+                    let t = checker.type_of(var).unwrap();
+                    println!("{}: {}", var_name(var), t);
+                }
+            }
+            if failures > 0 && !options.quiet() {
+                println!("{} of {} definitions are ill-typed", failures, vars.len());
+            }
+            return Ok(failures == 0);
+        }
+        Command::Bench(path) => {
+            let text = fs::read_to_string(&path)?;
+            let protocol = options.protocol.as_deref().unwrap_or("galaxy");
+            let new_transport =
+                || -> Result<Box<dyn Transport>, Box<dyn Error>> { Ok(transport(options)?) };
+            let name = path.display().to_string();
+            run_bench(&name, &text, protocol, &new_transport, &mut io::stdout())?;
+        }
+        Command::Diff(path) => {
+            let (file, _) = read_file(&path, options, "TEST")?;
+            let mut divergences = compare_file(&file, options.backend)?;
+            divergences.extend(compare_random(0, RANDOM_PROGRAMS, options.backend)?);
+            for divergence in divergences.iter() {
+                println!("{}", divergence);
+            }
+            if !options.quiet() {
+                println!(
+                    "{} divergences in {} and {} random programs",
+                    divergences.len(),
+                    path.display(),
+                    RANDOM_PROGRAMS
+                );
+            }
+            return Ok(divergences.is_empty());
+        }
+        Command::Repl => {
+            let transport_options = options.clone();
+            let mut repl = Repl::new(Box::new(move || transport(&transport_options)))?;
+            repl.set_backend(options.backend);
+            repl.set_limits(options.limits);
+            for path in options.includes.iter() {
+                repl.load(path)?;
+            }
+            repl.run(&mut io::stdin().lock(), &mut io::stdout())?;
+        }
+        Command::Help => println!("{}", USAGE),
+    }
+    Ok(true)
+}
 
-    child_thread.join().unwrap()
+fn main() {
+    let code = match parse_args(env::args().skip(1)) {
+        Ok((command, options)) => match run(command, &options) {
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                1
+            }
+        },
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            2
+        }
+    };
+    process::exit(code);
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::size_of;
+
+use crate::decompile::{decompile_term, Term};
+use crate::eval::{Arena, Value, Value_};
+use crate::syntax::Var;
+
+// Remembers what definitions applied to all of their arguments evaluated to, so a call made
+// again, in the same evaluation or a later one, is answered at once. Arguments are keyed on
+// their structure: the arena hash-conses while memoizing, so equal terms are the same node,
+// see `Arena::canonical`. Built-ins need no table: with hash-consing, an application of one
+// is a single node and keeps its result. The arguments and results are roots of collections,
+// so the table stays valid across the collection after every interaction.
+
+// Past this many calls the table starts over at the next collection
+const MAX_ENTRIES: usize = 1 << 16;
+
+type Call = (Var, Vec<Value>);
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct MemoStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    // Roughly, for the table and the hash-consing, not the terms it keeps alive
+    pub bytes: usize,
+}
+
+impl fmt::Display for MemoStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} calls remembered, {} KiB",
+            self.hits,
+            self.misses,
+            self.entries,
+            self.bytes / 1024
+        )
+    }
+}
+
+// How many arguments a definition takes before it is a call
+#[derive(Debug, Clone)]
+enum Arity {
+    Params(usize),
+    // As many as the definition it's another name for, like `galaxy = :1338`
+    Alias(Var),
+}
+
+// Aliases of aliases are followed this far
+const MAX_ALIASES: usize = 16;
+
+#[derive(Default)]
+pub struct Memo {
+    arities: HashMap<Var, Arity>,
+    results: HashMap<Call, Value>,
+    stats: MemoStats,
+}
+
+fn arity(arena: &Arena, val: Value) -> Arity {
+    let mut params = 0;
+    let mut term = decompile_term(arena, val);
+    while let Term::Lambda(_, body) = term {
+        params += 1;
+        term = *body;
+    }
+    match term {
+        Term::Global(var) if params == 0 => Arity::Alias(var),
+        _ => Arity::Params(params),
+    }
+}
+
+impl Memo {
+    pub fn new<'a>(arena: &Arena, vars: impl Iterator<Item = (&'a Var, &'a Value)>) -> Self {
+        let mut memo = Memo::default();
+        for (var, val) in vars {
+            memo.define(arena, var, *val);
+        }
+        memo
+    }
+
+    // Forgets the calls made so far, the definitions they used may have changed
+    pub fn define(&mut self, arena: &Arena, var: &Var, val: Value) {
+        self.arities.insert(var.clone(), arity(arena, val));
+        self.results.clear();
+    }
+
+    // The definition and arguments of `val` if it's a call, with the call itself: `val`, or
+    // the application inside it when `val` has more arguments
+    pub fn call(&self, arena: &Arena, val: Value) -> Option<(Call, Value)> {
+        let mut args = vec![];
+        let mut nodes = vec![];
+        let mut curr = val;
+        let var = loop {
+            match arena.get(curr) {
+                Value_::Apply(f, x) => {
+                    args.push(*x);
+                    nodes.push(curr);
+                    curr = *f;
+                }
+                Value_::Var(v) => break v,
+                _ => return None,
+            }
+        };
+        let (var, arity) = self.arity(var)?;
+        if arity == 0 || args.len() < arity {
+            return None;
+        }
+        let call = nodes[args.len() - arity];
+        let args = args.split_off(args.len() - arity);
+        let args = args.into_iter().rev().map(|a| arena.canonical(a)).collect();
+        Some(((var.clone(), args), call))
+    }
+
+    // The definition a call of `var` runs, following aliases, and its arity
+    fn arity<'a>(&'a self, var: &'a Var) -> Option<(&'a Var, usize)> {
+        let mut var = var;
+        for _ in 0..MAX_ALIASES {
+            match self.arities.get(var)? {
+                Arity::Params(n) => return Some((var, *n)),
+                Arity::Alias(v) => var = v,
+            }
+        }
+        None
+    }
+
+    pub fn get(&mut self, call: &Call) -> Option<Value> {
+        let result = self.results.get(call).copied();
+        match result {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        result
+    }
+
+    pub fn insert(&mut self, call: Call, result: Value) {
+        self.results.insert(call, result);
+    }
+
+    // What the table keeps alive, emptying it first if it's grown too big
+    pub fn roots(&mut self) -> Vec<Value> {
+        if self.results.len() > MAX_ENTRIES {
+            self.results.clear();
+        }
+        (self.results.iter())
+            .flat_map(|((_, args), result)| args.iter().chain(Some(result)))
+            .copied()
+            .collect()
+    }
+
+    pub fn stats(&self, arena: &Arena) -> MemoStats {
+        let entry = size_of::<(Call, Value)>();
+        let args: usize = self.results.keys().map(|(_, args)| args.len()).sum();
+        MemoStats {
+            entries: self.results.len(),
+            bytes: arena.consing_memory()
+                + self.results.capacity() * entry
+                + args * size_of::<Value>(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eval::State;
+    use crate::syntax::{parse_line, Var};
+
+    fn eval(memo: bool) -> (Vec<String>, Option<(u64, u64)>) {
+        let mut state = State::new();
+        for line in &[
+            "sq = ap ap s mul i",
+            "square = sq",
+            "a = ap sq 12",
+            "b = ap ap add 1 ap square 12",
+            "c = ap ap add ap sq 12 ap sq ap inc 11",
+        ] {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        state.set_memo(memo);
+        let values = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let v = state.eval_v(&Var::Named(name.to_string())).unwrap();
+                crate::printer::print_value(&state.arena(), v)
+            })
+            .collect();
+        let stats = state.memo_stats().map(|s| (s.hits, s.misses));
+        (values, stats)
+    }
+
+    #[test]
+    fn test_memo() {
+        let (values, stats) = eval(false);
+        assert_eq!(values, vec!["144", "145", "288"]);
+        assert_eq!(stats, None);
+        // `sq 12` is evaluated once and then remembered, also through an alias. Arguments are
+        // keyed before they are evaluated, so `sq (inc 11)` is another call.
+        let (memo_values, stats) = eval(true);
+        assert_eq!(memo_values, values);
+        assert_eq!(stats, Some((2, 2)));
+    }
+
+    #[test]
+    fn test_memo_across_collections() {
+        let mut state = State::new();
+        state
+            .interpret(parse_line("sq = ap ap s mul i").unwrap())
+            .unwrap();
+        state.set_memo(true);
+        let call = |state: &State| {
+            let mut arena = state.arena_mut();
+            let sq = arena.var(Var::Named("sq".to_string()));
+            let n = arena.number(5000.into());
+            arena.ap(sq, n)
+        };
+        let first = state.eval(call(&state)).unwrap();
+        let first = crate::printer::print_value(&state.arena(), first);
+        // Like after every step of an interaction
+        state.collect();
+        let again = state.eval(call(&state)).unwrap();
+        assert_eq!(crate::printer::print_value(&state.arena(), again), first);
+        let stats = state.memo_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+    }
+}
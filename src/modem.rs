@@ -1,3 +1,7 @@
+use num_bigint::{BigUint, Sign};
+use num_traits::Zero;
+
+use crate::syntax::Number;
 use crate::types::NestedList;
 
 pub fn mod_list(list: &NestedList) -> Vec<bool> {
@@ -7,41 +11,39 @@ pub fn mod_list(list: &NestedList) -> Vec<bool> {
 }
 
 pub fn dem_list(data: &[bool]) -> NestedList {
-    demodulate(&mut data.iter().copied()).unwrap()
+    try_dem_list(data).unwrap()
 }
 
-fn modulate_value(signed_num: i64, res: &mut Vec<bool>) {
-    if signed_num >= 0 {
-        res.push(false);
+pub fn try_dem_list(data: &[bool]) -> Result<NestedList, ()> {
+    demodulate(&mut data.iter().copied())
+}
+
+fn modulate_value(signed_num: &Number, res: &mut Vec<bool>) {
+    if signed_num.sign() == Sign::Minus {
         res.push(true);
+        res.push(false);
     } else {
-        res.push(true);
         res.push(false);
+        res.push(true);
     }
 
-    let num = {
-        if signed_num >= 0 {
-            signed_num as u64
-        } else {
-            (-signed_num) as u64
-        }
+    // the magnitude is written in as many nibbles as required to represent it
+    let nibbles = if signed_num.is_zero() {
+        vec![]
+    } else {
+        signed_num.magnitude().to_radix_be(16)
     };
 
-    // count the number of nibbles required to represent the number
-    let unused_space = num.leading_zeros();
-    let used_space = 64 - unused_space;
-    let needed_nibbles = (used_space + 3) / 4;
-
-    for _ in 0..needed_nibbles {
+    for _ in 0..nibbles.len() {
         res.push(true);
     }
     res.push(false);
 
-    let encoded_space = needed_nibbles * 4;
-    for i in 0..encoded_space {
-        // MSB first
-        let mask = 1u64 << (encoded_space - 1 - i);
-        res.push(num & mask != 0);
+    for nibble in nibbles {
+        for i in (0..4).rev() {
+            // MSB first
+            res.push(nibble & (1 << i) != 0);
+        }
     }
 }
 
@@ -49,7 +51,7 @@ fn modulate(val: &NestedList, res: &mut Vec<bool>) {
     use NestedList::*;
 
     match val {
-        Number(number) => modulate_value(*number, res),
+        Number(number) => modulate_value(number, res),
         Nil => {
             res.push(false);
             res.push(false);
@@ -67,24 +69,28 @@ fn iter_next(iter: &mut dyn Iterator<Item = bool>) -> Result<bool, ()> {
     iter.next().ok_or(())
 }
 
-fn demodulate_value(negative: bool, iter: &mut dyn Iterator<Item = bool>) -> Result<i64, ()> {
-    let used_nibbles = 'outer: loop {
-        for i in 0u64.. {
-            if !iter_next(iter)? {
-                break 'outer i;
-            }
-        }
-    };
+fn demodulate_value(negative: bool, iter: &mut dyn Iterator<Item = bool>) -> Result<Number, ()> {
+    let mut used_nibbles = 0;
+    while iter_next(iter)? {
+        used_nibbles += 1;
+    }
 
-    let mut res = 0u64;
-    let bit_size = used_nibbles * 4;
-    for i in 0..bit_size {
-        if iter_next(iter)? {
-            res |= 1u64 << (bit_size - i - 1);
+    let mut nibbles = Vec::with_capacity(used_nibbles);
+    for _ in 0..used_nibbles {
+        let mut nibble = 0u8;
+        for _ in 0..4 {
+            nibble = nibble << 1 | iter_next(iter)? as u8;
         }
+        nibbles.push(nibble);
     }
 
-    Ok(if negative { -(res as i64) } else { res as i64 })
+    let magnitude = if nibbles.is_empty() {
+        BigUint::zero()
+    } else {
+        BigUint::from_radix_be(&nibbles, 16).ok_or(())?
+    };
+    let sign = if negative { Sign::Minus } else { Sign::Plus };
+    Ok(Number::from_biguint(sign, magnitude))
 }
 
 fn demodulate(iter: &mut dyn Iterator<Item = bool>) -> Result<NestedList, ()> {
@@ -110,13 +116,11 @@ mod tests {
     use NestedList::*;
 
     fn n(num: i64) -> NestedList {
-        NestedList::Number(num)
+        NestedList::Number(num.into())
     }
 
     fn v(s: &str) -> Vec<bool> {
-        s.chars()
-            .map(|c| if c == '1' { true } else { false })
-            .collect()
+        s.chars().map(|c| c == '1').collect()
     }
 
     fn cons(a: NestedList, b: NestedList) -> NestedList {
@@ -142,17 +146,14 @@ mod tests {
     fn test_mod_list() {
         assert_eq!(mod_list(&Nil), v("00"));
         assert_eq!(mod_list(&cons(Nil, Nil)), v("110000"));
-        assert_eq!(mod_list(&cons(Number(0), Nil)), v("1101000"));
+        assert_eq!(mod_list(&cons(n(0), Nil)), v("1101000"));
+        assert_eq!(mod_list(&cons(n(1), n(2))), v("110110000101100010"));
         assert_eq!(
-            mod_list(&cons(Number(1), Number(2))),
-            v("110110000101100010")
-        );
-        assert_eq!(
-            mod_list(&cons(Number(1), cons(Number(2), Nil))),
+            mod_list(&cons(n(1), cons(n(2), Nil))),
             v("1101100001110110001000")
         );
-        let second_item = cons(Number(2), cons(Number(3), Nil));
-        let woosh = cons(Number(1), cons(second_item, cons(Number(4), Nil)));
+        let second_item = cons(n(2), cons(n(3), Nil));
+        let woosh = cons(n(1), cons(second_item, cons(n(4), Nil)));
         assert_eq!(
             mod_list(&woosh),
             v("1101100001111101100010110110001100110110010000")
@@ -162,21 +163,38 @@ mod tests {
     #[test]
     fn test_modem() {
         let cons = |a, b| NestedList::Cons(Box::new(a), Box::new(b));
-        let num = |x| NestedList::Number(x);
         let nil = || NestedList::Nil;
 
         assert_eq!(dem_list(&v("010")), n(0));
         assert_eq!(dem_list(&v("0111000010000")), n(16));
         assert_eq!(dem_list(&v("00")), nil());
-        assert_eq!(dem_list(&v("1101000")), cons(Number(0), Nil));
+        assert_eq!(dem_list(&v("1101000")), cons(n(0), Nil));
 
-        let var0 = cons(Number(0), Nil);
+        let var0 = cons(n(0), Nil);
         assert_eq!(var0, dem_list(&mod_list(&var0)));
 
-        let var1 = cons(num(1), cons(num(2), cons(num(3), nil())));
+        let var1 = cons(n(1), cons(n(2), cons(n(3), nil())));
         assert_eq!(var1, dem_list(&mod_list(&var1)));
 
-        let var2 = cons(cons(num(1), num(2)), cons(nil(), nil()));
+        let var2 = cons(cons(n(1), n(2)), cons(nil(), nil()));
         assert_eq!(var2, dem_list(&mod_list(&var2)));
     }
+
+    #[test]
+    fn test_modem_big() {
+        let mut min = v("10");
+        min.extend(v(&"1".repeat(16)));
+        min.extend(v("01000"));
+        min.extend(v(&"0".repeat(60)));
+        assert_eq!(mod_list(&n(i64::MIN)), min);
+        assert_eq!(dem_list(&min), n(i64::MIN));
+
+        let big = NestedList::Number(crate::syntax::Number::from(1) << 200usize);
+        assert_eq!(mod_list(&big).len(), 2 + 51 + 1 + 51 * 4);
+        assert_eq!(big, dem_list(&mod_list(&big)));
+
+        let neg_big = NestedList::Number(-(crate::syntax::Number::from(3) << 100usize));
+        let list = cons(neg_big, cons(n(123229502148636), Nil));
+        assert_eq!(list, dem_list(&mod_list(&list)));
+    }
 }
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+
+use num_traits::{ToPrimitive, Zero};
+
+use crate::eval::*;
+use crate::syntax::{Number, Var};
+
+// Rewrites definitions into simpler terms with the same meaning before anything is evaluated:
+// combinators that already have all their arguments are reduced, arithmetic on literals is
+// folded, pairs of literals are marked as evaluated, and definitions that are just a built-in
+// waiting for arguments are copied into the terms that use them. Evaluation is lazy, so
+// dropping an argument is always sound, but copying one would evaluate it twice and only
+// happens for names and literals.
+
+// Inlining a definition can make others small enough to inline, this is how many times the
+// definitions are gone over at most
+const ROUNDS: usize = 4;
+
+// The rewrites a single definition may take, reducing combinators doesn't need to terminate
+const FUEL: usize = 10_000;
+
+// Folding `pwr2` past this would make numbers no evaluation may ever ask for
+const MAX_PWR2: u32 = 1024;
+
+fn is_atom(arena: &Arena, v: Value) -> bool {
+    matches!(
+        arena.get(v),
+        Value_::Number(_) | Value_::BuiltIn(_) | Value_::Var(_)
+    )
+}
+
+// The head of an application and its arguments, in order
+fn spine(arena: &Arena, v: Value) -> (Value, Vec<Value>) {
+    let mut args = vec![];
+    let mut head = v;
+    while let Value_::Apply(f, x) = arena.get(head) {
+        args.push(*x);
+        head = *f;
+    }
+    args.reverse();
+    (head, args)
+}
+
+fn builtin(arena: &Arena, v: Value) -> Option<BuiltIn> {
+    match arena.get(v) {
+        Value_::BuiltIn(b) => Some(*b),
+        _ => None,
+    }
+}
+
+// A name, or a built-in that isn't applied to enough arguments to do anything with only
+// literals as arguments. Copying it costs nothing at run time. Names inside what's copied
+// would be inlined again on the next round, without end if the definitions are recursive.
+fn is_inlinable(arena: &Arena, v: Value) -> bool {
+    let (head, args) = spine(arena, v);
+    let is_literal = |a: &Value| matches!(arena.get(*a), Value_::Number(_) | Value_::BuiltIn(_));
+    match builtin(arena, head) {
+        Some(b) => args.len() < b.arity() && args.iter().all(is_literal),
+        None => args.is_empty(),
+    }
+}
+
+struct Optimizer<'a> {
+    arena: &'a mut Arena,
+    // The definition being optimized, which is never inlined into itself
+    var: &'a Var,
+    inline: &'a HashMap<Var, Value>,
+    // The simplified form of every term seen so far, simplified terms map to themselves
+    done: HashMap<Value, Value>,
+    fuel: usize,
+}
+
+enum Item {
+    Visit(Value),
+    Build(Value),
+}
+
+impl Optimizer<'_> {
+    fn simplify(&mut self, root: Value) -> Value {
+        // Galaxy has lists thousands of items long, so terms are walked with an explicit stack
+        let mut stack = vec![Item::Visit(root)];
+        while let Some(item) = stack.pop() {
+            match item {
+                Item::Visit(v) if self.done.contains_key(&v) => {}
+                Item::Visit(v) => match self.arena.get(v) {
+                    Value_::Apply(f, x) => {
+                        let (f, x) = (*f, *x);
+                        stack.push(Item::Build(v));
+                        stack.push(Item::Visit(f));
+                        stack.push(Item::Visit(x));
+                    }
+                    Value_::Var(var) if var != self.var && self.inline.contains_key(var) => {
+                        let term = self.inline[var];
+                        self.mark_done(term);
+                        self.done.insert(v, term);
+                    }
+                    _ => {
+                        self.done.insert(v, v);
+                    }
+                },
+                Item::Build(v) => {
+                    let (f, x) = match self.arena.get(v) {
+                        Value_::Apply(f, x) => (*f, *x),
+                        _ => unreachable!(),
+                    };
+                    let (new_f, new_x) = (self.done[&f], self.done[&x]);
+                    let node = if (new_f, new_x) == (f, x) {
+                        v
+                    } else {
+                        self.arena.ap(new_f, new_x)
+                    };
+                    let simplified = self.rewrite(node);
+                    self.done.insert(v, simplified);
+                    self.done.insert(simplified, simplified);
+                }
+            }
+        }
+        self.done[&root]
+    }
+
+    // Inlined terms are simplified already, and walking them again would inline their names
+    fn mark_done(&mut self, term: Value) {
+        let mut pending = vec![term];
+        while let Some(v) = pending.pop() {
+            self.done.insert(v, v);
+            if let Value_::Apply(f, x) = self.arena.get(v) {
+                pending.push(*f);
+                pending.push(*x);
+            }
+        }
+    }
+
+    fn rewrite(&mut self, v: Value) -> Value {
+        if self.fuel == 0 {
+            return v;
+        }
+        match self.rule(v) {
+            Some(new) => {
+                self.fuel -= 1;
+                self.simplify(new)
+            }
+            None => v,
+        }
+    }
+
+    fn number(&self, v: Value) -> Option<Number> {
+        match self.arena.get(v) {
+            Value_::Number(n) => Some(n.clone()),
+            _ => None,
+        }
+    }
+
+    // The items of `ap ap cons x y`
+    fn pair(&self, v: Value) -> Option<(Value, Value)> {
+        let (head, args) = spine(self.arena, v);
+        match (builtin(self.arena, head), args.as_slice()) {
+            (Some(BuiltIn::Cons), &[x, y]) => Some((x, y)),
+            _ => None,
+        }
+    }
+
+    fn bool(&self, x: bool) -> Value {
+        b(if x { BuiltIn::True } else { BuiltIn::False })
+    }
+
+    // The term `v` rewrites to, its sub-terms are simplified already. A rule that needs fewer
+    // arguments than `v` has would have fired on the application inside it.
+    fn rule(&mut self, v: Value) -> Option<Value> {
+        let (head, args) = spine(self.arena, v);
+        let b0 = builtin(self.arena, head)?;
+        let arg = |i: usize| args[i];
+        let is = |a: &Arena, v: Value, b: BuiltIn| builtin(a, v) == Some(b);
+        let arena = &mut *self.arena;
+        let new = match (b0, args.len()) {
+            (BuiltIn::I, 1) => arg(0),
+            (BuiltIn::True, 2) => arg(0),
+            (BuiltIn::False, 1) => b(BuiltIn::I),
+            (BuiltIn::Nil, 1) => b(BuiltIn::True),
+            // `ap ap b f i` and `ap ap b i f` both are `f`
+            (BuiltIn::B, 2) if is(arena, arg(1), BuiltIn::I) => arg(0),
+            (BuiltIn::B, 2) if is(arena, arg(0), BuiltIn::I) => arg(1),
+            (BuiltIn::B, 3) => {
+                let x = arena.ap(arg(1), arg(2));
+                arena.ap(arg(0), x)
+            }
+            // Selectors of the second and the first of two arguments
+            (BuiltIn::C, 1) if is(arena, arg(0), BuiltIn::True) => b(BuiltIn::False),
+            (BuiltIn::C, 1) if is(arena, arg(0), BuiltIn::False) => b(BuiltIn::True),
+            (BuiltIn::C, 3) => {
+                let f = arena.ap(arg(0), arg(2));
+                arena.ap(f, arg(1))
+            }
+            (BuiltIn::S, 3) if is_atom(arena, arg(2)) => {
+                let f = arena.ap(arg(0), arg(2));
+                let x = arena.ap(arg(1), arg(2));
+                arena.ap(f, x)
+            }
+            (BuiltIn::Cons, 2) => {
+                // A pair of evaluated terms is as evaluated as it gets
+                if arena.is_computed(arg(0)) && arena.is_computed(arg(1)) {
+                    arena.set_computed(v);
+                }
+                return None;
+            }
+            (BuiltIn::Cons, 3) => {
+                let f = arena.ap(arg(2), arg(0));
+                arena.ap(f, arg(1))
+            }
+            (BuiltIn::Head, 1) => self.pair(arg(0))?.0,
+            (BuiltIn::Tail, 1) => self.pair(arg(0))?.1,
+            (BuiltIn::IsNil, 1) if is(arena, arg(0), BuiltIn::Nil) => b(BuiltIn::True),
+            (BuiltIn::IsNil, 1) => {
+                self.pair(arg(0))?;
+                b(BuiltIn::False)
+            }
+            (BuiltIn::If0, 3) => {
+                if self.number(arg(0))?.is_zero() {
+                    arg(1)
+                } else {
+                    arg(2)
+                }
+            }
+            (BuiltIn::Inc, 1) | (BuiltIn::Dec, 1) | (BuiltIn::Neg, 1) | (BuiltIn::Pwr2, 1) => {
+                let n = self.number(arg(0))?;
+                let n = match b0 {
+                    BuiltIn::Inc => n + 1,
+                    BuiltIn::Dec => n - 1,
+                    BuiltIn::Neg => -n,
+                    _ => Number::from(1) << n.to_u32().filter(|n| *n <= MAX_PWR2)?,
+                };
+                self.arena.number(n)
+            }
+            (BuiltIn::Add, 2) | (BuiltIn::Mul, 2) | (BuiltIn::Div, 2) => {
+                let (x, y) = (self.number(arg(0))?, self.number(arg(1))?);
+                let n = match b0 {
+                    BuiltIn::Add => x + y,
+                    BuiltIn::Mul => x * y,
+                    // Dividing by zero is left to fail when it's evaluated, if it ever is
+                    _ if y.is_zero() => return None,
+                    _ => x / y,
+                };
+                self.arena.number(n)
+            }
+            (BuiltIn::Eq, 2) | (BuiltIn::Lt, 2) => {
+                let (x, y) = (self.number(arg(0))?, self.number(arg(1))?);
+                self.bool(if b0 == BuiltIn::Eq { x == y } else { x < y })
+            }
+            _ => return None,
+        };
+        Some(new)
+    }
+}
+
+// Optimizes every definition in place and returns how many rewrites it took. The terms the
+// definitions had before are left for the next collection.
+pub fn optimize(arena: &mut Arena, vars: &mut HashMap<Var, Value>) -> usize {
+    let mut rewrites = 0;
+    let mut inline = HashMap::new();
+    for _ in 0..ROUNDS {
+        for (var, val) in vars.iter_mut() {
+            let mut optimizer = Optimizer {
+                arena,
+                var,
+                inline: &inline,
+                done: HashMap::new(),
+                fuel: FUEL,
+            };
+            *val = optimizer.simplify(*val);
+            rewrites += FUEL - optimizer.fuel;
+        }
+        // Going over the definitions again with the same ones to inline changes nothing
+        let new_inline: HashMap<Var, Value> = (vars.iter())
+            .filter(|(_, val)| is_inlinable(arena, **val))
+            .map(|(var, val)| (var.clone(), *val))
+            .collect();
+        if new_inline == inline {
+            break;
+        }
+        inline = new_inline;
+    }
+    rewrites
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::differential::assert_same_frames;
+    use crate::printer::print_value;
+    use crate::syntax::{parse_file, parse_line, parse_var};
+
+    fn optimized(lines: &[&str]) -> State {
+        let mut state = State::new();
+        for line in lines {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        state.optimize();
+        state
+    }
+
+    fn definition(state: &State, name: &str) -> String {
+        let val = state.definition(&parse_var(name, 1).unwrap()).unwrap();
+        print_value(&state.arena(), val)
+    }
+
+    #[test]
+    fn test_rules() {
+        let state = optimized(&[
+            "eta = ap ap b ap i inc i",
+            "folded = ap ap add 1 ap ap mul 2 3",
+            "second = ap ap ap c t 1 2",
+            "first = ap ap c f 1",
+            "pick = ap ap ap ap lt 1 2 :1 :2",
+            "unknown = ap ap ap ap lt x 2 :1 :2",
+            "swap = ap ap ap c add 1 x",
+            "head = ap car ap ap cons x nil",
+            "zero = ap ap div 1 0",
+            "big = ap pwr2 100000",
+            "empty = ap isnil nil",
+        ]);
+        assert_eq!(definition(&state, "eta"), "inc");
+        assert_eq!(definition(&state, "folded"), "7");
+        assert_eq!(definition(&state, "second"), "2");
+        assert_eq!(definition(&state, "first"), "ap t 1");
+        assert_eq!(definition(&state, "pick"), ":1");
+        assert_eq!(definition(&state, "unknown"), "ap ap ap ap lt x 2 :1 :2");
+        assert_eq!(definition(&state, "swap"), "ap ap add x 1");
+        assert_eq!(definition(&state, "head"), "x");
+        assert_eq!(definition(&state, "zero"), "ap ap div 1 0");
+        assert_eq!(definition(&state, "big"), "ap pwr2 100000");
+        assert_eq!(definition(&state, "empty"), "t");
+    }
+
+    #[test]
+    fn test_inline() {
+        let state = optimized(&[
+            ":1 = ap add 1",
+            ":2 = ap :1 2",
+            ":3 = :2",
+            "pair = ap ap cons 1 ap ap cons 2 nil",
+        ]);
+        assert_eq!(definition(&state, ":2"), "3");
+        assert_eq!(definition(&state, ":3"), "3");
+        let pair = state.definition(&Var::Named("pair".to_string())).unwrap();
+        assert!(state.arena().is_computed(pair));
+    }
+
+    #[test]
+    fn test_galaxy_frames() {
+        let load = || {
+            let mut state = State::new();
+            for stmt in parse_file("galaxy.txt", include_str!("../data/galaxy.txt")).unwrap() {
+                state.interpret(stmt).unwrap();
+            }
+            state
+        };
+        let original = load();
+        let mut optimized = load();
+        assert!(optimized.optimize() > 0);
+        let clicks = [(0, 0), (0, 0), (0, 0), (0, 0), (8, 4), (2, -8), (3, 6)];
+        assert_same_frames(&original, &optimized, &clicks);
+    }
+}
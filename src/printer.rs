@@ -0,0 +1,216 @@
+use crate::eval::*;
+use crate::syntax::*;
+use crate::types::Picture;
+
+// Everything here prints the syntax `syntax::parse_line` reads back, either with plain
+// `ap ap cons` chains or with `( 1 , 2 )` for proper lists
+
+pub fn builtin_name(builtin: BuiltIn) -> &'static str {
+    match builtin {
+        BuiltIn::Inc => "inc",
+        BuiltIn::Dec => "dec",
+        BuiltIn::Add => "add",
+        BuiltIn::Mul => "mul",
+        BuiltIn::Div => "div",
+        BuiltIn::Eq => "eq",
+        BuiltIn::Lt => "lt",
+        BuiltIn::Neg => "neg",
+        BuiltIn::S => "s",
+        BuiltIn::C => "c",
+        BuiltIn::B => "b",
+        BuiltIn::True => "t",
+        BuiltIn::False => "f",
+        BuiltIn::Pwr2 => "pwr2",
+        BuiltIn::I => "i",
+        BuiltIn::Cons => "cons",
+        BuiltIn::Head => "car",
+        BuiltIn::Tail => "cdr",
+        BuiltIn::Nil => "nil",
+        BuiltIn::IsNil => "isnil",
+        BuiltIn::Draw => "draw",
+        BuiltIn::Checkerboard => "checkerboard",
+        BuiltIn::MultipleDraw => "multipledraw",
+        BuiltIn::Modem => "modem",
+        BuiltIn::Send => "send",
+        BuiltIn::If0 => "if0",
+        BuiltIn::F38 => "f38",
+        BuiltIn::Interact => "interact",
+    }
+}
+
+pub fn var_name(var: &Var) -> String {
+    match var {
+        Var::Named(name) => name.clone(),
+        Var::Temp(n) => format!(":{}", n),
+    }
+}
+
+fn token_text(token: &Token) -> String {
+    match token {
+        Token::Number(n) => n.to_string(),
+        Token::Var(v) => var_name(v),
+        Token::Ap => "ap".to_string(),
+        // The other tokens are built-ins and named like them
+        _ => builtin_name(BuiltIn::from_token(token).unwrap()).to_string(),
+    }
+}
+
+pub fn print_tokens(code: &[Token]) -> String {
+    let texts: Vec<String> = code.iter().map(token_text).collect();
+    texts.join(" ")
+}
+
+pub fn print_stmt(stmt: &Stmt) -> String {
+    format!("{} = {}", var_name(&stmt.var), print_tokens(&stmt.code))
+}
+
+// Pictures have no syntax of their own, they are printed as the `draw` that makes them
+fn picture_source(p: &Picture, sugar: bool) -> String {
+    let mut arena = Arena::default();
+    let points = p.points.iter().rev().fold(b(BuiltIn::Nil), |tail, point| {
+        let x = arena.number(point.x.into());
+        let y = arena.number(point.y.into());
+        let point = arena.cons(x, y);
+        arena.cons(point, tail)
+    });
+    let draw = arena.ap(b(BuiltIn::Draw), points);
+    print(&arena, draw, sugar, usize::MAX)
+}
+
+// The elements of `val` if it's a non-empty list built by `cons`
+fn list_items(arena: &Arena, val: Value) -> Option<Vec<Value>> {
+    let mut items = vec![];
+    let mut curr = val;
+    loop {
+        curr = match arena.get(curr) {
+            Value_::BuiltIn(BuiltIn::Nil) if !items.is_empty() => return Some(items),
+            Value_::Apply(f, tail) => match arena.get(*f) {
+                Value_::Apply(cons, head)
+                    if *arena.get(*cons) == Value_::BuiltIn(BuiltIn::Cons) =>
+                {
+                    items.push(*head);
+                    *tail
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+    }
+}
+
+enum Item {
+    Term(Value),
+    Text(&'static str),
+}
+
+fn print(arena: &Arena, val: Value, sugar: bool, max_len: usize) -> String {
+    // Terms can be deeply nested, so they are walked with an explicit stack. Shared
+    // sub-terms are printed every time they occur.
+    let mut out: Vec<String> = vec![];
+    let mut len = 0;
+    let mut stack = vec![Item::Term(val)];
+    while let Some(item) = stack.pop() {
+        if len > max_len {
+            let text = out.join(" ");
+            let end = text
+                .char_indices()
+                .nth(max_len)
+                .map_or(text.len(), |(i, _)| i);
+            return format!("{}...", &text[..end]);
+        }
+        let val = match item {
+            Item::Text(text) => {
+                out.push(text.to_string());
+                len += text.len() + 1;
+                continue;
+            }
+            Item::Term(val) => val,
+        };
+        if sugar {
+            if let Some(items) = list_items(arena, val) {
+                out.push("(".to_string());
+                len += 2;
+                stack.push(Item::Text(")"));
+                for (i, item) in items.into_iter().enumerate().rev() {
+                    stack.push(Item::Term(item));
+                    if i > 0 {
+                        stack.push(Item::Text(","));
+                    }
+                }
+                continue;
+            }
+        }
+        let text = match arena.get(val) {
+            Value_::Var(v) => var_name(v),
+            Value_::Number(n) => n.to_string(),
+            Value_::BuiltIn(builtin) => builtin_name(*builtin).to_string(),
+            Value_::Apply(f, x) => {
+                stack.push(Item::Term(*x));
+                stack.push(Item::Term(*f));
+                "ap".to_string()
+            }
+            Value_::Picture(p) => picture_source(p, sugar),
+        };
+        len += text.len() + 1;
+        out.push(text);
+    }
+    out.join(" ")
+}
+
+pub fn print_value(arena: &Arena, val: Value) -> String {
+    print(arena, val, false, usize::MAX)
+}
+
+pub fn print_value_sugared(arena: &Arena, val: Value) -> String {
+    print(arena, val, true, usize::MAX)
+}
+
+// Cut short after about `max_len` characters, for terms too big to print whole
+pub fn print_value_short(arena: &Arena, val: Value, max_len: usize) -> String {
+    print(arena, val, true, max_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sugar() {
+        let mut state = State::new();
+        let line = "x = ap ap cons 0 ap ap cons ap ap cons 1 2 ap ap cons ap ap cons 3 nil nil";
+        state.interpret(parse_line(line).unwrap()).unwrap();
+        let x = state.definition(&Var::Named("x".to_string())).unwrap();
+        assert_eq!(print_value(&state.arena(), x), &line[4..]);
+        let sugared = print_value_sugared(&state.arena(), x);
+        assert_eq!(sugared, "( 0 , ap ap cons 1 2 , ( 3 ) )");
+
+        state
+            .interpret(parse_line("y = ap draw ( ap ap vec 1 -2 )").unwrap())
+            .unwrap();
+        let y = state.eval_v(&Var::Named("y".to_string())).unwrap();
+        let sugared = print_value_sugared(&state.arena(), y);
+        assert_eq!(sugared, "ap draw ( ap ap cons 1 -2 )");
+    }
+
+    #[test]
+    fn test_round_trip_galaxy() {
+        let mut state = State::new();
+        for line in include_str!("../data/galaxy.txt").lines() {
+            let stmt = parse_line(line).unwrap();
+            assert_eq!(print_stmt(&stmt), line);
+            assert_eq!(parse_line(&print_stmt(&stmt)).as_ref(), Ok(&stmt));
+            state.interpret(stmt).unwrap();
+        }
+        let arena = state.arena();
+        for (var, val) in state.definitions() {
+            let plain = print_value(&arena, *val);
+            for text in [plain.clone(), print_value_sugared(&arena, *val)].iter() {
+                let mut reparsed = State::new();
+                let line = format!("{} = {}", var_name(var), text);
+                reparsed.interpret(parse_line(&line).unwrap()).unwrap();
+                let val = reparsed.definition(var).unwrap();
+                assert_eq!(print_value(&reparsed.arena(), val), plain, "{}", line);
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::eval::{Arena, BuiltIn, Value, Value_};
+use crate::printer::{builtin_name, var_name};
+use crate::syntax::Var;
+
+// Counts what evaluation spends on each definition and built-in. Every step of the evaluation
+// is charged to the rewrite it did, or to the definition being evaluated when it only moved
+// between terms. The stacks are made of the definitions each entry of the evaluation stack
+// was last a call of, so a definition that tail-calls another one is replaced by it.
+
+// What a step of evaluation is charged to
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Site {
+    Var(Var),
+    BuiltIn(BuiltIn),
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Site::Var(v) => write!(f, "{}", var_name(v)),
+            Site::BuiltIn(b) => write!(f, "{}", builtin_name(*b)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SiteStats {
+    pub reductions: u64,
+    // Spent in the steps charged to the site, not in the definitions it calls
+    pub time: Duration,
+    pub allocations: u64,
+}
+
+// What rewriting `val` unfolds or fires, judging by the head of its spine
+fn site_of(arena: &Arena, val: Value) -> Option<Site> {
+    let mut curr = val;
+    while let Value_::Apply(f, _) = arena.get(curr) {
+        curr = *f;
+    }
+    match arena.get(curr) {
+        Value_::Var(v) => Some(Site::Var(v.clone())),
+        Value_::BuiltIn(b) => Some(Site::BuiltIn(*b)),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+pub struct Profile {
+    sites: Vec<Site>,
+    ids: HashMap<Site, usize>,
+    // By the id of the site
+    stats: Vec<SiteStats>,
+    total: SiteStats,
+    // How many reductions were made with each stack of sites, outermost first
+    stacks: HashMap<Vec<usize>, u64>,
+    // The definition each entry of the evaluation stack was last a call of
+    frames: Vec<Option<usize>>,
+    last: Option<Instant>,
+    allocations: u64,
+}
+
+impl Profile {
+    fn id(&mut self, site: Site) -> usize {
+        if let Some(id) = self.ids.get(&site) {
+            return *id;
+        }
+        let id = self.sites.len();
+        self.ids.insert(site.clone(), id);
+        self.sites.push(site);
+        self.stats.push(SiteStats::default());
+        id
+    }
+
+    // An evaluation starts
+    pub fn start(&mut self, arena: &Arena) {
+        self.frames.clear();
+        self.last = Some(Instant::now());
+        self.allocations = arena.allocations();
+    }
+
+    // The evaluation stack is `depth` entries deep and a step on `curr`, the term of the top
+    // entry, is done
+    pub fn step(&mut self, arena: &Arena, depth: usize, curr: Value, reduced: bool) {
+        let now = Instant::now();
+        let time = now - self.last.unwrap_or(now);
+        let allocations = arena.allocations() - self.allocations;
+        self.last = Some(now);
+        self.allocations = arena.allocations();
+        self.frames.resize(depth, None);
+
+        // The interpreter evaluates a definition before unfolding it, that is in the call too
+        let head = site_of(arena, curr).map(|site| self.id(site));
+        if let Some(head) = head {
+            if let Site::Var(_) = self.sites[head] {
+                self.frames[depth - 1] = Some(head);
+            }
+        }
+        let id = head.filter(|_| reduced);
+        let charged = id.or_else(|| self.frames.iter().rev().flatten().next().copied());
+        if let Some(charged) = charged {
+            self.stats[charged].time += time;
+            self.stats[charged].allocations += allocations;
+        }
+        self.total.time += time;
+        self.total.allocations += allocations;
+
+        if let Some(id) = id {
+            self.stats[id].reductions += 1;
+            self.total.reductions += 1;
+            let mut stack: Vec<usize> = vec![];
+            for frame in self.frames.iter().flatten().chain(Some(&id)) {
+                // Recursion through nested entries is one frame
+                if stack.last() != Some(frame) {
+                    stack.push(*frame);
+                }
+            }
+            *self.stacks.entry(stack).or_insert(0) += 1;
+        }
+    }
+
+    // The sites that took the most time, most first
+    pub fn top(&self, n: usize) -> Vec<(&Site, SiteStats)> {
+        let mut top: Vec<(&Site, SiteStats)> = self.sites.iter().zip(self.stats.clone()).collect();
+        top.sort_by(|(a, x), (b, y)| {
+            (y.time, y.reductions)
+                .cmp(&(x.time, x.reductions))
+                .then_with(|| a.to_string().cmp(&b.to_string()))
+        });
+        top.truncate(n);
+        top
+    }
+
+    pub fn write_top(&self, out: &mut dyn Write, n: usize) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:<16} {:>12} {:>12} {:>7} {:>12}",
+            "", "reductions", "time", "", "allocations"
+        )?;
+        let total = self.total.time.as_secs_f64();
+        for (site, stats) in self.top(n) {
+            let share = if total > 0.0 {
+                100.0 * stats.time.as_secs_f64() / total
+            } else {
+                0.0
+            };
+            writeln!(
+                out,
+                "{:<16} {:>12} {:>9.3} ms {:>6.1}% {:>12}",
+                site.to_string(),
+                stats.reductions,
+                stats.time.as_secs_f64() * 1000.0,
+                share,
+                stats.allocations
+            )?;
+        }
+        writeln!(
+            out,
+            "{:<16} {:>12} {:>9.3} ms {:>7} {:>12}",
+            "total",
+            self.total.reductions,
+            total * 1000.0,
+            "",
+            self.total.allocations
+        )
+    }
+
+    // A line per stack, the names of its sites separated by `;` and how many reductions were
+    // made with it, which is what flame graph tools read
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut lines: Vec<(String, u64)> = (self.stacks.iter())
+            .map(|(stack, count)| {
+                let names: Vec<String> =
+                    stack.iter().map(|id| self.sites[*id].to_string()).collect();
+                (names.join(";"), *count)
+            })
+            .collect();
+        lines.sort();
+        for (stack, count) in lines {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::State;
+    use crate::syntax::parse_line;
+
+    fn state() -> State {
+        let mut state = State::new();
+        for line in &[
+            "inc2 = ap ap b inc inc",
+            "twice = ap ap s b i",
+            "x = ap ap add ap inc2 1 ap ap twice inc2 2",
+        ] {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        state.set_profiling(true);
+        let x = state.arena_mut().var(Var::Named("x".to_string()));
+        state.eval(x).unwrap();
+        state
+    }
+
+    fn named(name: &str) -> Site {
+        Site::Var(Var::Named(name.to_string()))
+    }
+
+    #[test]
+    fn test_counts() {
+        let state = state();
+        let profile = state.profile().unwrap();
+        let reductions = |site| {
+            profile
+                .ids
+                .get(&site)
+                .map(|id| profile.stats[*id].reductions)
+        };
+        assert_eq!(reductions(named("x")), Some(1));
+        assert_eq!(reductions(named("inc2")), Some(2));
+        assert_eq!(reductions(named("twice")), Some(1));
+        assert_eq!(reductions(Site::BuiltIn(BuiltIn::Inc)), Some(6));
+        assert_eq!(reductions(Site::BuiltIn(BuiltIn::Add)), Some(1));
+        let sum: u64 = profile
+            .top(usize::MAX)
+            .iter()
+            .map(|(_, s)| s.reductions)
+            .sum();
+        assert_eq!(sum, profile.total.reductions);
+        assert!(profile.total.allocations > 0);
+
+        let mut top = vec![];
+        profile.write_top(&mut top, 3).unwrap();
+        // A header, three sites and the total
+        assert_eq!(String::from_utf8(top).unwrap().lines().count(), 5);
+    }
+
+    #[test]
+    fn test_folded() {
+        let state = state();
+        let profile = state.profile().unwrap();
+        let mut folded = vec![];
+        profile.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let mut total = 0;
+        for line in folded.lines() {
+            let (stack, count) = line.rsplit_once(' ').unwrap();
+            assert!(stack.starts_with("x"), "{}", line);
+            total += count.parse::<u64>().unwrap();
+        }
+        assert_eq!(total, profile.total.reductions);
+        // The rewrites of `inc2` are under it, also when `twice` tail-calls it
+        assert!(folded.contains("x;inc2;inc 6\n"), "{}", folded);
+        assert!(folded.contains("x;add 1\n"), "{}", folded);
+    }
+}
@@ -0,0 +1,298 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::eval::*;
+use crate::printer::{print_value, print_value_sugared, var_name};
+use crate::send::Transport;
+use crate::syntax::*;
+use crate::trace::redex;
+use crate::types::*;
+
+const HELP: &str = "name = expr     Define a variable
+expr            Evaluate an expression
+:load <file>    Load the definitions in a file
+:show [name]    Print a definition, or list the defined names
+:type <expr>    Describe what an expression evaluates to
+:trace <expr>   Evaluate an expression and list the rewrites it took
+:draw <expr>    Draw the pictures an expression evaluates to
+:reset          Forget all definitions
+:quit           Leave";
+
+// Longer traces are cut short, galaxy easily takes millions of rewrites
+const TRACE_LIMIT: usize = 1000;
+
+pub type TransportFactory = Box<dyn Fn() -> io::Result<Box<dyn Transport>>>;
+
+pub struct Repl {
+    state: State,
+    transport: TransportFactory,
+    backend: Backend,
+    limits: Limits,
+}
+
+fn show(arena: &Arena, v: Value) -> String {
+    match arena.get(v) {
+        Value_::Picture(p) => p.to_string(),
+        _ => print_value_sugared(arena, v),
+    }
+}
+
+fn kind(arena: &Arena, v: Value) -> String {
+    if let Ok(list) = NestedList::from_value(arena, v) {
+        return match list {
+            NestedList::Nil => "nil",
+            NestedList::Number(_) => "number",
+            NestedList::Cons(..) if list.to_string().starts_with('(') => "list",
+            NestedList::Cons(..) => "pair",
+        }
+        .to_string();
+    }
+    // Evaluated terms are built-ins applied to fewer arguments than they take
+    let mut args = 0;
+    let mut head = v;
+    loop {
+        head = match arena.get(head) {
+            Value_::Apply(f, _) => *f,
+            Value_::Picture(_) => return "picture".to_string(),
+            Value_::BuiltIn(BuiltIn::True) | Value_::BuiltIn(BuiltIn::False) if args == 0 => {
+                return "bool".to_string()
+            }
+            Value_::BuiltIn(b) => {
+                return match b.arity() - args {
+                    1 => "function of 1 argument".to_string(),
+                    n => format!("function of {} arguments", n),
+                }
+            }
+            _ => return "unevaluated term".to_string(),
+        };
+        args += 1;
+    }
+}
+
+impl Repl {
+    pub fn new(transport: TransportFactory) -> io::Result<Self> {
+        let mut state = State::new();
+        state.set_transport(transport()?);
+        Ok(Repl {
+            state,
+            transport,
+            backend: Backend::Interpreter,
+            limits: Limits::default(),
+        })
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.state.set_backend(backend);
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.state.set_limits(limits);
+    }
+
+    // Returns the number of definitions
+    pub fn load(&mut self, path: &Path) -> Result<usize, Box<dyn Error>> {
+        let stmts = parse_file(&path.display().to_string(), &fs::read_to_string(path)?)?;
+        let count = stmts.len();
+        for stmt in stmts {
+            self.state.interpret(stmt)?;
+        }
+        Ok(count)
+    }
+
+    fn eval(&mut self, text: &str) -> Result<Value, Box<dyn Error>> {
+        self.state.interpret(parse_expr(text)?)?;
+        Ok(self.state.eval_v(&Var::Named("expr".to_string()))?)
+    }
+
+    // The value, the first `TRACE_LIMIT` rewrites and how many there were
+    fn trace(&mut self, text: &str) -> Result<(Value, Vec<String>, usize), Box<dyn Error>> {
+        let rewrites = Rc::new(RefCell::new((vec![], 0)));
+        let rewrites_ = rewrites.clone();
+        self.state.set_tracer(Some(Box::new(move |arena, r| {
+            let (lines, count) = &mut *rewrites_.borrow_mut();
+            if *count < TRACE_LIMIT {
+                lines.push(redex(arena, &r));
+            }
+            *count += 1;
+        })));
+        let result = self.eval(text);
+        self.state.set_tracer(None);
+        let (lines, count) = rewrites.replace((vec![], 0));
+        result.map(|v| (v, lines, count))
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        arg: &str,
+        out: &mut dyn Write,
+    ) -> Result<bool, Box<dyn Error>> {
+        match command {
+            ":load" => {
+                let count = self.load(Path::new(arg))?;
+                writeln!(out, "Loaded {} definitions", count)?;
+            }
+            ":show" if arg.is_empty() => {
+                let mut names: Vec<String> =
+                    self.state.definitions().map(|(v, _)| var_name(v)).collect();
+                names.sort();
+                writeln!(out, "{}", names.join(" "))?;
+            }
+            ":show" => {
+                let var = match arg.strip_prefix(':') {
+                    Some(n) => Var::Temp(n.parse()?),
+                    None => Var::Named(arg.to_string()),
+                };
+                match self.state.definition(&var) {
+                    Some(val) => {
+                        let text = print_value(&self.state.arena(), val);
+                        writeln!(out, "{} = {}", arg, text)?
+                    }
+                    None => writeln!(out, "{} is not defined", arg)?,
+                }
+            }
+            ":type" => {
+                let v = self.eval(arg)?;
+                writeln!(out, "{}", kind(&self.state.arena(), v))?;
+            }
+            ":trace" => {
+                let (v, lines, count) = self.trace(arg)?;
+                for line in lines.iter() {
+                    writeln!(out, "{}", line)?;
+                }
+                if count > lines.len() {
+                    writeln!(out, "... {} more", count - lines.len())?;
+                }
+                writeln!(out, "{} rewrites", count)?;
+                writeln!(out, "{}", show(&self.state.arena(), v))?;
+            }
+            ":draw" => {
+                let v = self.eval(arg)?;
+                let pics = PictureBuilder::from_value(&self.state.arena(), v)?;
+                for p in pics {
+                    writeln!(out, "{}", p)?;
+                }
+            }
+            ":reset" => {
+                self.state = State::new();
+                self.state.set_transport((self.transport)()?);
+                self.state.set_backend(self.backend);
+                self.state.set_limits(self.limits);
+            }
+            ":help" => writeln!(out, "{}", HELP)?,
+            ":quit" | ":q" => return Ok(false),
+            _ => writeln!(out, "Unknown command {}, try :help", command)?,
+        }
+        Ok(true)
+    }
+
+    fn execute_line(&mut self, line: &str, out: &mut dyn Write) -> Result<bool, Box<dyn Error>> {
+        if is_blank(line) {
+        } else if is_definition(line) {
+            self.state.interpret(parse_line(line)?)?;
+        } else if line.starts_with(':') && !line[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            // `:1029` is a variable, `:load` a command
+            let (command, arg) = line.split_at(line.find(' ').unwrap_or(line.len()));
+            return self.command(command, arg.trim(), out);
+        } else {
+            let v = self.eval(line)?;
+            writeln!(out, "{}", show(&self.state.arena(), v))?;
+        }
+        Ok(true)
+    }
+
+    // Returns false once asked to quit
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let result = self.execute_line(line.trim(), out);
+        // Lines don't keep anything but definitions, even the ones that failed midway
+        self.state.collect();
+        match result {
+            Ok(go_on) => Ok(go_on),
+            Err(e) => {
+                writeln!(out, "Error: {}", e)?;
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn run(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        loop {
+            write!(out, "> ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 || !self.execute(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send::MockTransport;
+
+    fn session(lines: &[&str]) -> String {
+        let transport = || Ok(Box::new(MockTransport::new(|_| Ok(NestedList::Nil))) as _);
+        let mut repl = Repl::new(Box::new(transport)).unwrap();
+        let mut out = vec![];
+        for line in lines {
+            repl.execute(line, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let out = session(&[
+            "twice = ap ap s b i",
+            "ap ap twice inc 40",
+            ":1 = ap ap cons 1 nil",
+            "ap ap cons 0 :1",
+            ":type twice",
+            ":type ap add 1",
+            ":type ap ap cons 1 2",
+            ":show twice",
+            "ap car 5",
+            ":reset",
+            "twice",
+        ]);
+        let expected = "42
+( 0 , 1 )
+function of 1 argument
+function of 1 argument
+pair
+twice = ap ap s b i
+Error: Arity error: a number can't be applied
+Error: Unbound variable Named(\"twice\")
+";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_collect_failed_line() {
+        let transport = || Ok(Box::new(MockTransport::new(|_| Ok(NestedList::Nil))) as _);
+        let mut repl = Repl::new(Box::new(transport)).unwrap();
+        let mut out = vec![];
+        let line = "ap ap add ap ap ap s mul i 5000 ap car 5";
+        repl.execute(line, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("Error: "));
+        // Nothing is left to collect
+        let nodes = repl.state.arena().len();
+        repl.state.collect();
+        assert_eq!(repl.state.arena().len(), nodes);
+    }
+
+    #[test]
+    fn test_trace() {
+        let out = session(&["inc2 = ap ap b inc inc", ":trace ap inc2 1"]);
+        let expected = "inc2\nap ap ap b inc inc 1\nap inc 1\nap inc 2\n4 rewrites\n3\n";
+        assert_eq!(out, expected);
+    }
+}
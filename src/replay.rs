@@ -0,0 +1,177 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::modem::*;
+use crate::send::{Transport, TransportError};
+
+// The log has one exchange per line, separated by tabs:
+//   <unix time in ms>  <request bits>  <response bits>  <request>  <response>
+// The lists are only there for people reading the log, replaying matches the request bits.
+// Empty lines and lines starting with `#` are ignored.
+
+fn bits_to_string(bits: &[bool]) -> String {
+    bits.iter().map(|&x| if x { '1' } else { '0' }).collect()
+}
+
+fn string_to_bits(s: &str) -> Option<Vec<bool>> {
+    s.chars()
+        .map(|c| match c {
+            '0' => Some(false),
+            '1' => Some(true),
+            _ => None,
+        })
+        .collect()
+}
+
+fn describe(bits: &[bool]) -> String {
+    match try_dem_list(bits) {
+        Ok(list) => list.to_string(),
+        Err(()) => "<malformed>".to_string(),
+    }
+}
+
+// Passes every exchange on to another transport and appends it to a log
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    log: Box<dyn Write + Send>,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    pub fn new(inner: T, log: Box<dyn Write + Send>) -> Self {
+        RecordingTransport { inner, log }
+    }
+
+    // Appends to the log if it already exists, so a session can be recorded in several runs
+    pub fn create(inner: T, path: &Path) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self::new(inner, Box::new(BufWriter::new(file))))
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn send(&mut self, request: &[bool]) -> Result<Vec<bool>, TransportError> {
+        let response = self.inner.send(request)?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        writeln!(
+            self.log,
+            "{}\t{}\t{}\t{}\t{}",
+            time,
+            bits_to_string(request),
+            bits_to_string(&response),
+            describe(request),
+            describe(&response)
+        )?;
+        self.log.flush()?;
+        Ok(response)
+    }
+}
+
+// Answers from a recorded log. Identical requests get their responses in the recorded
+// order, the last one is repeated once they run out.
+pub struct ReplayTransport {
+    responses: HashMap<Vec<bool>, VecDeque<Vec<bool>>>,
+}
+
+// The request and response of every exchange in a log, in order
+pub fn parse_log(log: &str) -> io::Result<Vec<(Vec<bool>, Vec<bool>)>> {
+    let mut exchanges = vec![];
+    for (i, line) in log.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |what| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", i + 1, what),
+            )
+        };
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            return Err(invalid("expected a time, a request and a response"));
+        }
+        let request = string_to_bits(fields[1]).ok_or_else(|| invalid("invalid request"))?;
+        let response = string_to_bits(fields[2]).ok_or_else(|| invalid("invalid response"))?;
+        exchanges.push((request, response));
+    }
+    Ok(exchanges)
+}
+
+impl ReplayTransport {
+    pub fn parse(log: &str) -> io::Result<Self> {
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+        for (request, response) in parse_log(log)? {
+            responses.entry(request).or_default().push_back(response);
+        }
+        Ok(ReplayTransport { responses })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&mut self, request: &[bool]) -> Result<Vec<bool>, TransportError> {
+        let queue = self
+            .responses
+            .get_mut(request)
+            .ok_or_else(|| format!("no recorded response for {}", describe(request)))?;
+        let response = if queue.len() > 1 {
+            queue.pop_front()
+        } else {
+            queue.front().cloned()
+        };
+        Ok(response.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send::MockTransport;
+    use crate::syntax::Number;
+    use crate::types::NestedList;
+
+    fn n(x: i64) -> NestedList {
+        NestedList::Number(Number::from(x))
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("galaxy-replay-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut counter = 0;
+        let mock = MockTransport::new(move |request| {
+            counter += 1;
+            Ok(NestedList::Cons(
+                Box::new(request.clone()),
+                Box::new(n(counter)),
+            ))
+        });
+        let mut recording = RecordingTransport::create(mock, &path).unwrap();
+        let mut recorded = vec![];
+        for request in [n(1), n(2), n(1)].iter() {
+            recorded.push(recording.send(&mod_list(request)).unwrap());
+        }
+        drop(recording);
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(log.lines().next().unwrap().ends_with("\t1\tap ap cons 1 1"));
+
+        let mut replay = ReplayTransport::parse(&log).unwrap();
+        assert_eq!(replay.send(&mod_list(&n(1))).unwrap(), recorded[0]);
+        assert_eq!(replay.send(&mod_list(&n(2))).unwrap(), recorded[1]);
+        assert_eq!(replay.send(&mod_list(&n(1))).unwrap(), recorded[2]);
+        assert_eq!(replay.send(&mod_list(&n(1))).unwrap(), recorded[2]);
+        assert!(replay.send(&mod_list(&n(3))).is_err());
+    }
+}
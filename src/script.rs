@@ -0,0 +1,275 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::eval::{Backend, Limits, State, Tracer};
+use crate::interact::run_interaction;
+use crate::send::Transport;
+use crate::syntax::{is_blank, parse_file, parse_line, ParseError};
+use crate::types::*;
+
+// A SCRIPT file clicks through a protocol without a window:
+//   SCRIPT
+//   PROTOCOL galaxy
+//   INCLUDE galaxy.txt
+//   OUTPUT frames        (optional, frames go to stdout without it)
+//   FORMAT bmp           (optional, `ascii` by default)
+//   CLICK 0 0
+//   CLICK -3 5
+// Any other line is a definition.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameFormat {
+    Ascii,
+    Bmp,
+}
+
+pub struct Script {
+    state: State,
+    protocol: String,
+    clicks: Vec<(i64, i64)>,
+    output: Option<PathBuf>,
+    format: FrameFormat,
+}
+
+// Drawn in this order, the first picture of a frame is on top
+const COLORS: &[(u8, u8, u8)] = &[
+    (255, 255, 255),
+    (255, 101, 47),
+    (255, 228, 0),
+    (20, 167, 108),
+    (17, 100, 102),
+    (232, 90, 79),
+];
+const BMP_SCALE: u32 = 4;
+// A bitmap wider or taller than this is refused, a single point far away would make it huge
+const MAX_BMP_SIDE: u64 = 8192;
+
+fn invalid_data(line: &str, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", msg, line))
+}
+
+fn parse_error(e: ParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+pub fn parse_script(
+    file: &str,
+    data_folder: &Path,
+    transport: Box<dyn Transport>,
+) -> io::Result<Script> {
+    let mut state = State::new();
+    state.set_transport(transport);
+    let mut protocol = None;
+    let mut clicks = vec![];
+    let mut output = None;
+    let mut format = FrameFormat::Ascii;
+    let define = |state: &mut State, stmt| {
+        state
+            .interpret(stmt)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    // Skip the "SCRIPT" line
+    for (i, line) in file.lines().enumerate().skip(1) {
+        if is_blank(line) {
+        } else if let Some(l) = line.strip_prefix("PROTOCOL ") {
+            protocol = Some(l.to_string());
+        } else if let Some(l) = line.strip_prefix("INCLUDE ") {
+            let path = data_folder.join(l);
+            let stmts = parse_file(&path.display().to_string(), &fs::read_to_string(&path)?)
+                .map_err(parse_error)?;
+            for stmt in stmts {
+                define(&mut state, stmt)?;
+            }
+        } else if let Some(l) = line.strip_prefix("OUTPUT ") {
+            output = Some(data_folder.join(l));
+        } else if let Some(l) = line.strip_prefix("FORMAT ") {
+            format = match l {
+                "ascii" => FrameFormat::Ascii,
+                "bmp" => FrameFormat::Bmp,
+                _ => return Err(invalid_data(line, "Unknown frame format")),
+            };
+        } else if let Some(l) = line.strip_prefix("CLICK ") {
+            let coords: Vec<_> = l.split(' ').map(|c| c.parse::<i64>()).collect();
+            match coords.as_slice() {
+                [Ok(x), Ok(y)] => clicks.push((*x, *y)),
+                _ => return Err(invalid_data(line, "Expected two coordinates")),
+            }
+        } else {
+            let stmt = parse_line(line).map_err(|e| parse_error(e.at_line(i + 1)))?;
+            define(&mut state, stmt)?;
+        }
+    }
+    let protocol = protocol.ok_or_else(|| invalid_data("", "Protocol was not defined"))?;
+    Ok(Script {
+        state,
+        protocol,
+        clicks,
+        output,
+        format,
+    })
+}
+
+impl Script {
+    pub fn set_protocol(&mut self, protocol: &str) {
+        self.protocol = protocol.to_string();
+    }
+
+    pub fn set_output(&mut self, output: PathBuf) {
+        self.output = Some(output);
+    }
+
+    pub fn set_format(&mut self, format: FrameFormat) {
+        self.format = format;
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.state.set_backend(backend);
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.state.set_limits(limits);
+    }
+
+    pub fn optimize(&mut self) {
+        self.state.optimize();
+    }
+
+    pub fn set_memo(&mut self, on: bool) {
+        self.state.set_memo(on);
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.state.set_tracer(Some(tracer));
+    }
+
+    pub fn set_profiling(&mut self, on: bool) {
+        self.state.set_profiling(on);
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    // Clicks through the script, frames are written to `out` unless there's an output folder
+    pub fn run(&self, out: &mut dyn Write) -> io::Result<()> {
+        if let Some(folder) = &self.output {
+            fs::create_dir_all(folder)?;
+        }
+        let mut st = NestedList::Nil;
+        for (i, &(x, y)) in self.clicks.iter().enumerate() {
+            let (new_state, data) = run_interaction(&self.state, &self.protocol, st, x, y)
+                .map_err(|e| invalid_data(&format!("({}, {})", x, y), &e.to_string()))?;
+            let pics = PictureBuilder::from_nested_list(data)
+                .map_err(|e| invalid_data(&format!("({}, {})", x, y), &e.to_string()))?;
+            st = new_state;
+
+            match (&self.output, self.format) {
+                (None, _) => {
+                    writeln!(out, "Frame #{}: click ({}, {})", i, x, y)?;
+                    write_pictures(out, &pics)?;
+                }
+                (Some(folder), FrameFormat::Ascii) => {
+                    let mut file = fs::File::create(folder.join(format!("frame_{:04}.txt", i)))?;
+                    write_pictures(&mut file, &pics)?;
+                }
+                (Some(folder), FrameFormat::Bmp) => {
+                    let path = folder.join(format!("frame_{:04}.bmp", i));
+                    frame_to_bmp(&pics)?.save(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn write_pictures(out: &mut dyn Write, pics: &[Picture]) -> io::Result<()> {
+    if pics.len() == 1 {
+        writeln!(out, "{}", pics[0])?;
+    } else {
+        for (i, p) in pics.iter().enumerate() {
+            writeln!(out, "Picture #{}", i)?;
+            writeln!(out, "{}", p)?;
+        }
+    }
+    Ok(())
+}
+
+// All layers of a frame in one image, with the origin in the middle
+pub fn frame_to_bmp(pics: &[Picture]) -> io::Result<bmp::Image> {
+    let half_width = pics.iter().map(|p| p.width / 2).max().unwrap_or(0);
+    let half_height = pics.iter().map(|p| p.height / 2).max().unwrap_or(0);
+    let side = |half: u32| (half as u64 * 2 + 1) * BMP_SCALE as u64;
+    let (width, height) = (side(half_width), side(half_height));
+    if width > MAX_BMP_SIDE || height > MAX_BMP_SIDE {
+        let msg = format!(
+            "a frame of {}x{} pixels is too big for a bitmap",
+            width, height
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    let mut img = bmp::Image::new(width as u32, height as u32);
+    for (i, p) in pics.iter().enumerate().rev() {
+        let (r, g, b) = COLORS[i.min(COLORS.len() - 1)];
+        for point in p.points.iter() {
+            let x = (point.x + half_width as i32) as u32 * BMP_SCALE;
+            let y = (point.y + half_height as i32) as u32 * BMP_SCALE;
+            for dx in 0..BMP_SCALE {
+                for dy in 0..BMP_SCALE {
+                    img.set_pixel(x + dx, y + dy, bmp::Pixel::new(r, g, b));
+                }
+            }
+        }
+    }
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send::MockTransport;
+
+    #[test]
+    fn test_stateful_script() {
+        let file = "SCRIPT
+PROTOCOL statefuldraw
+statefuldraw = ap ap b ap b ap ap s ap ap b ap b ap cons 0 ap ap c ap ap b b cons ap ap c cons nil ap ap c cons nil ap c cons
+CLICK 1 0
+CLICK -1 1
+";
+        let script = parse_script(
+            file,
+            Path::new("."),
+            Box::new(MockTransport::new(|_| Ok(NestedList::Nil))),
+        )
+        .unwrap();
+        let mut out = vec![];
+        script.run(&mut out).unwrap();
+        let expected = "Frame #0: click (1, 0)
+..#
+Frame #1: click (-1, 1)
+...
+..#
+#..
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_bmp() {
+        let pics = vec![PictureBuilder::from_points(vec![(1, 0), (-1, 1)])];
+        let img = frame_to_bmp(&pics).unwrap();
+        let mut bytes = vec![];
+        img.to_writer(&mut bytes).unwrap();
+        assert_eq!(&bytes[..2], b"BM");
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        // 3x3 points of 4x4 pixels each
+        assert_eq!((u32_at(18), u32_at(22)), (12, 12));
+        assert_eq!(img.get_pixel(8, 4), bmp::Pixel::new(255, 255, 255));
+        assert_eq!(img.get_pixel(4, 4), bmp::Pixel::new(0, 0, 0));
+
+        let far = vec![PictureBuilder::from_points(vec![(0, 0), (100_000, 0)])];
+        assert!(frame_to_bmp(&far).is_err());
+    }
+}
@@ -1,17 +1,140 @@
+use std::env;
+use std::io;
+
 use hyper::header::LOCATION;
 use hyper::{body, Body, Client, Method, Request, StatusCode};
 use hyper_tls::HttpsConnector;
 
+use crate::replay::{RecordingTransport, ReplayTransport};
+
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+const DEFAULT_ENDPOINT: &str = "https://icfpc2020-api.testkontur.ru/aliens/send";
+
+// Carries a modulated message to the aliens and returns their modulated response. The
+// explorer sends from the thread that evaluates, hence `Send`.
+pub trait Transport: Send {
+    fn send(&mut self, request: &[bool]) -> Result<Vec<bool>, TransportError>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&mut self, request: &[bool]) -> Result<Vec<bool>, TransportError> {
+        (**self).send(request)
+    }
+}
+
+fn bits_to_bytes(bits: &[bool]) -> Vec<u8> {
+    bits.iter().map(|&x| if x { b'1' } else { b'0' }).collect()
+}
+
+fn bytes_to_bits(bytes: &[u8]) -> Result<Vec<bool>, TransportError> {
+    bytes
+        .iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .map(|c| match c {
+            b'0' => Ok(false),
+            b'1' => Ok(true),
+            _ => Err(format!("unexpected byte {:?} in a signal", *c as char).into()),
+        })
+        .collect()
+}
+
 fn string_from_bytes(bytes: &[u8]) -> String {
-    String::from(String::from_utf8_lossy(&bytes[..]))
+    String::from(String::from_utf8_lossy(bytes))
+}
+
+pub struct HttpTransport {
+    endpoint: String,
+    api_key: Option<String>,
+}
+
+impl HttpTransport {
+    // Without a key nothing can be sent, which is only an error once something is
+    pub fn new(endpoint: &str, api_key: Option<&str>) -> Self {
+        HttpTransport {
+            endpoint: endpoint.to_string(),
+            api_key: api_key.map(str::to_string),
+        }
+    }
+
+    // The endpoint defaults to the contest server, the key has no default
+    pub fn from_env() -> Self {
+        let endpoint = env::var("GALAXY_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+        HttpTransport::new(&endpoint, env::var("GALAXY_API_KEY").ok().as_deref())
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&mut self, request_bits: &[bool]) -> Result<Vec<bool>, TransportError> {
+        let api_key = self
+            .api_key
+            .as_ref()
+            .ok_or("no API key, set GALAXY_API_KEY")?;
+        let response = request(&self.endpoint, api_key, bits_to_bytes(request_bits))?;
+        bytes_to_bits(&response)
+    }
+}
+
+// Talks to the server unless `GALAXY_REPLAY` names a log to answer from instead.
+// With `GALAXY_RECORD` set all exchanges are appended to that log.
+pub fn transport_from_env() -> io::Result<Box<dyn Transport>> {
+    if let Ok(path) = env::var("GALAXY_REPLAY") {
+        return Ok(Box::new(ReplayTransport::load(path.as_ref())?));
+    }
+    let http = HttpTransport::from_env();
+    match env::var("GALAXY_RECORD") {
+        Ok(path) => Ok(Box::new(RecordingTransport::create(http, path.as_ref())?)),
+        Err(_) => Ok(Box::new(http)),
+    }
+}
+
+// Answers in-process, either with a closure or from a table of known requests
+#[cfg(test)]
+mod mock {
+    use super::{Transport, TransportError};
+    use crate::modem::*;
+    use crate::types::NestedList;
+
+    type Responder = Box<dyn FnMut(&NestedList) -> Result<NestedList, TransportError> + Send>;
+
+    pub struct MockTransport {
+        respond: Responder,
+    }
+
+    impl MockTransport {
+        pub fn new<F>(respond: F) -> Self
+        where
+            F: FnMut(&NestedList) -> Result<NestedList, TransportError> + Send + 'static,
+        {
+            MockTransport {
+                respond: Box::new(respond),
+            }
+        }
+
+        pub fn scripted(table: Vec<(NestedList, NestedList)>) -> Self {
+            MockTransport::new(move |request| {
+                table
+                    .iter()
+                    .find(|(known, _)| known == request)
+                    .map(|(_, response)| response.clone())
+                    .ok_or_else(|| format!("no scripted response for {:?}", request).into())
+            })
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn send(&mut self, request_bits: &[bool]) -> Result<Vec<bool>, TransportError> {
+            let request = try_dem_list(request_bits).map_err(|_| "malformed request")?;
+            Ok(mod_list(&(self.respond)(&request)?))
+        }
+    }
 }
 
+#[cfg(test)]
+pub use mock::MockTransport;
+
 #[tokio::main]
-pub async fn request(
-    base: &str,
-    token: &str,
-    content: Vec<u8>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+pub async fn request(base: &str, token: &str, content: Vec<u8>) -> Result<Vec<u8>, TransportError> {
     let mut endpoint = format!("{}?apiKey={}", base, token);
 
     let https = HttpsConnector::new();
@@ -24,23 +147,195 @@ pub async fn request(
             .uri(endpoint)
             .body(body)?;
 
-        let mut res = match client.request(req).await {
-            Ok(res) => res,
-            Err(err) => panic!("Unexpected server response:\n{}", err),
-        };
+        let mut res = client.request(req).await?;
 
         let body_data = body::to_bytes(res.body_mut()).await?;
 
-        // print!("Server response: ");
-        // println!("{:?}", body_data);
-
         match res.status() {
             StatusCode::OK => break Ok(body_data.into_iter().collect()),
             StatusCode::FOUND => {
                 endpoint = string_from_bytes(res.headers()[LOCATION].as_bytes());
                 println!("updated endpoint to {:?}", endpoint);
             }
-            _ => panic!("Unexpected server response: {}", res.status()),
+            status => {
+                break Err(format!(
+                    "unexpected server response {}: {}",
+                    status,
+                    string_from_bytes(&body_data)
+                )
+                .into())
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::modem::*;
+    use crate::syntax::Number;
+    use crate::types::NestedList;
+    use NestedList::*;
+
+    // A local HTTP server speaking the alien protocol, answering with another transport.
+    // It runs on its own thread until dropped.
+    struct LoopbackServer {
+        addr: SocketAddr,
+        shutdown: Option<oneshot::Sender<()>>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl LoopbackServer {
+        fn start<T: Transport + Send + 'static>(transport: T) -> io::Result<Self> {
+            let transport = Arc::new(Mutex::new(transport));
+            let (addr_tx, addr_rx) = mpsc::channel();
+            let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+            let thread = thread::spawn(move || {
+                let mut runtime = match tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(err) => return addr_tx.send(Err(err)).unwrap_or(()),
+                };
+                runtime.block_on(async move {
+                    let make_service = make_service_fn(move |_| {
+                        let transport = transport.clone();
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |req| {
+                                answer(transport.clone(), req)
+                            }))
+                        }
+                    });
+                    let server = match Server::try_bind(&([127, 0, 0, 1], 0).into()) {
+                        Ok(builder) => builder.serve(make_service),
+                        Err(err) => {
+                            let err = io::Error::other(err);
+                            return addr_tx.send(Err(err)).unwrap_or(());
+                        }
+                    };
+                    addr_tx.send(Ok(server.local_addr())).unwrap_or(());
+                    let shutdown = async {
+                        shutdown_rx.await.ok();
+                    };
+                    if let Err(err) = server.with_graceful_shutdown(shutdown).await {
+                        eprintln!("loopback server failed: {}", err);
+                    }
+                })
+            });
+
+            let addr = addr_rx
+                .recv()
+                .map_err(|_| io::Error::other("loopback server died"))??;
+            Ok(LoopbackServer {
+                addr,
+                shutdown: Some(shutdown_tx),
+                thread: Some(thread),
+            })
+        }
+
+        fn endpoint(&self) -> String {
+            format!("http://{}/aliens/send", self.addr)
+        }
+    }
+
+    impl Drop for LoopbackServer {
+        fn drop(&mut self) {
+            if let Some(shutdown) = self.shutdown.take() {
+                shutdown.send(()).unwrap_or(());
+            }
+            if let Some(thread) = self.thread.take() {
+                thread.join().unwrap_or(());
+            }
+        }
+    }
+
+    async fn answer<T: Transport>(
+        transport: Arc<Mutex<T>>,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        let reply = |status, text: String| {
+            let mut response = Response::new(Body::from(text));
+            *response.status_mut() = status;
+            Ok(response)
+        };
+
+        if req.method() != Method::POST {
+            return reply(StatusCode::METHOD_NOT_ALLOWED, String::new());
+        }
+        let bits = match body::to_bytes(req.into_body()).await {
+            Ok(bytes) => bytes_to_bits(&bytes),
+            Err(err) => return reply(StatusCode::BAD_REQUEST, err.to_string()),
+        };
+        let response = bits.and_then(|bits| transport.lock().unwrap().send(&bits));
+        match response {
+            Ok(bits) => reply(StatusCode::OK, string_from_bytes(&bits_to_bytes(&bits))),
+            Err(err) => reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        }
+    }
+
+    fn n(x: i64) -> NestedList {
+        NestedList::Number(Number::from(x))
+    }
+
+    fn cons(a: NestedList, b: NestedList) -> NestedList {
+        Cons(Box::new(a), Box::new(b))
+    }
+
+    #[test]
+    fn test_mock_scripted() {
+        let mut transport = MockTransport::scripted(vec![(cons(n(0), Nil), cons(n(1), Nil))]);
+        let response = transport.send(&mod_list(&cons(n(0), Nil))).unwrap();
+        assert_eq!(dem_list(&response), cons(n(1), Nil));
+        assert!(transport.send(&mod_list(&n(5))).is_err());
+    }
+
+    // The loopback server is driven with a bare HTTP/1.1 exchange, which is all the aliens'
+    // protocol needs
+    fn post(endpoint: &str, body: &str) -> String {
+        use std::io::{Read, Write};
+
+        let address = endpoint.trim_start_matches("http://");
+        let (host, path) = address.split_at(address.find('/').unwrap());
+        let mut stream = std::net::TcpStream::connect(host).unwrap();
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            host,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_loopback() {
+        let server = LoopbackServer::start(MockTransport::new(|request| match request {
+            Number(x) => Ok(NestedList::Number(x * 2)),
+            _ => Err("expected a number".into()),
+        }))
+        .unwrap();
+        let request = string_from_bytes(&bits_to_bytes(&mod_list(&n(21))));
+        let response = post(&server.endpoint(), &request);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let bits = bytes_to_bits(response.split("\r\n\r\n").nth(1).unwrap().as_bytes());
+        assert_eq!(dem_list(&bits.unwrap()), n(42));
+        let response = post(&server.endpoint(), "00");
+        assert!(response.starts_with("HTTP/1.1 500"), "{}", response);
+    }
+}
@@ -1,29 +1,42 @@
+use std::fmt;
+
+// Numbers are unbounded, the messages don't limit their size
+pub type Number = num_bigint::BigInt;
+
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
-    Number(i64), // #1-4 increase to u64 if needed
-    Inc,         // #5
-    Dec,         // #6
-    Add,         // #7
-    Var(Var),    // #8
-    Mul,         // #9
-    Div,         // #10
-    Eq,          // #11
-    Lt,          // #12
-    Neg,         // #16
-    Ap,          // #17
-    S,           // #18
-    C,           // #19
-    B,           // #20
-    True,        // #21
-    False,       // #22
-    Pwr2,        // #23
-    I,           // #24
-    Cons,        // #25
-    Head,        // #26
-    Tail,        // #27
-    Nil,         // #28
-    IsNil,       // #29
+    Number(Number), // #1-4
+    Inc,            // #5
+    Dec,            // #6
+    Add,            // #7
+    Var(Var),       // #8
+    Mul,            // #9
+    Div,            // #10
+    Eq,             // #11
+    Lt,             // #12
+    Neg,            // #16
+    Ap,             // #17
+    S,              // #18
+    C,              // #19
+    B,              // #20
+    True,           // #21
+    False,          // #22
+    Pwr2,           // #23
+    I,              // #24
+    Cons,           // #25
+    Head,           // #26
+    Tail,           // #27
+    Nil,            // #28
+    IsNil,          // #29
+    Draw,           // #32
+    Checkerboard,   // #33
+    MultipleDraw,   // #34
+    Modem,          // #35
+    Send,           // #36
+    If0,            // #37
+    F38,            // #38
+    Interact,       // #39
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -32,96 +45,697 @@ pub enum Var {
     Temp(u32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Stmt {
     pub var: Var,
     pub code: Vec<Token>,
 }
 
-fn parse(text: &str) -> Vec<Token> {
-    let parts: Vec<&str> = text.split(' ').collect();
-    let mut new_item = false;
-    let mut result = vec![];
-    for s in parts {
-        if s == ")" {
-            new_item = false;
-            result.push(Token::Nil);
-        } else {
-            if new_item {
-                result.push(Token::Ap);
-                result.push(Token::Ap);
-                result.push(Token::Cons);
-                new_item = false;
-            }
-            match s {
-                "inc" => result.push(Token::Inc),
-                "dec" => result.push(Token::Dec),
-                "add" => result.push(Token::Add),
-                "mul" => result.push(Token::Mul),
-                "div" => result.push(Token::Div),
-                "eq" => result.push(Token::Eq),
-                "lt" => result.push(Token::Lt),
-                "neg" => result.push(Token::Neg),
-                "ap" => result.push(Token::Ap),
-                "s" => result.push(Token::S),
-                "c" => result.push(Token::C),
-                "b" => result.push(Token::B),
-                "t" => result.push(Token::True),
-                "f" => result.push(Token::False),
-                "pwr2" => result.push(Token::Pwr2),
-                "i" => result.push(Token::I),
-                "cons" | "vec" => result.push(Token::Cons),
-                "car" => result.push(Token::Head),
-                "cdr" => result.push(Token::Tail),
-                "nil" => result.push(Token::Nil),
-                "isnil" => result.push(Token::IsNil),
-                "(" => new_item = true,
-                "," => new_item = true,
-                s if s.starts_with(':') => result.push(Token::Var(Var::Temp(
-                    s.strip_prefix(':').unwrap().parse::<u32>().unwrap(),
-                ))),
-                s if s.chars().all(|c| c.is_ascii_digit())
-                    || s.starts_with('-') && s.chars().skip(1).all(|c| c.is_ascii_digit()) =>
-                {
-                    result.push(Token::Number(s.parse::<i64>().unwrap()))
+// Where a line or a file stopped making sense. Lines and columns count from 1.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(column: usize, message: String) -> Self {
+        ParseError {
+            file: None,
+            line: 1,
+            column,
+            message,
+        }
+    }
+
+    // The entry points only see one line, callers that read files say which one it was
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(
+                f,
+                "{}:{}:{}: {}",
+                file, self.line, self.column, self.message
+            ),
+            None => write!(
+                f,
+                "line {}, column {}: {}",
+                self.line, self.column, self.message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse_var(s: &str, column: usize) -> Result<Var, ParseError> {
+    match s.strip_prefix(':') {
+        Some(n) => n
+            .parse::<u32>()
+            .map(Var::Temp)
+            .map_err(|_| ParseError::new(column, format!("invalid variable `{}`", s))),
+        None if s.starts_with(|c: char| c.is_ascii_alphabetic())
+            && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok(Var::Named(s.to_string()))
+        }
+        None => Err(ParseError::new(column, format!("invalid variable `{}`", s))),
+    }
+}
+
+// Checks that the tokens make exactly one term, building it the way `State::compile` does
+fn check_terms(code: &[Token], columns: &[usize], start: usize) -> Result<(), ParseError> {
+    // Where each term built so far starts, the last one is the leftmost
+    let mut terms = vec![];
+    for (token, &column) in code.iter().zip(columns).rev() {
+        if *token == Token::Ap {
+            if terms.len() < 2 {
+                let msg = "`ap` is missing an argument".to_string();
+                return Err(ParseError::new(column, msg));
+            }
+            terms.truncate(terms.len() - 2);
+        }
+        terms.push(column);
+    }
+    match terms.as_slice() {
+        [] => Err(ParseError::new(start, "expected an expression".to_string())),
+        [_] => Ok(()),
+        [.., extra, _] => Err(ParseError::new(
+            *extra,
+            "unexpected term after the expression, is an `ap` missing?".to_string(),
+        )),
+    }
+}
+
+// A word of a line and the column it starts at
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Lexeme<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+// Words are separated by any whitespace, brackets, `,` and `\\` stand on their own even
+// without it. `--` and `#` start a comment that runs to the end of the line.
+fn lex(line: &str) -> Vec<Lexeme<'_>> {
+    let is_punct = |c: char| "(,)[]\\".contains(c);
+    let mut lexemes = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '#' || line[i..].starts_with("--") {
+            break;
+        }
+        let mut end = i + c.len_utf8();
+        if !is_punct(c) {
+            while let Some(&(j, c)) = chars.peek() {
+                if c.is_whitespace() || is_punct(c) {
+                    break;
                 }
-                _ => panic!("{}", s),
+                end = j + c.len_utf8();
+                chars.next();
             }
         }
+        lexemes.push(Lexeme {
+            text: &line[i..end],
+            column: i + 1,
+        });
     }
-    result
+    lexemes
+}
+
+// Lines with nothing but whitespace and comments
+pub fn is_blank(line: &str) -> bool {
+    lex(line).is_empty()
+}
+
+pub fn is_definition(line: &str) -> bool {
+    let lexemes = lex(line);
+    matches!(lexemes.get(1), Some(l) if l.text == "=") || is_lambda_definition(&lexemes)
 }
 
-pub fn parse_line(text: &str) -> Stmt {
-    let parts: Vec<&str> = text.split(" = ").collect();
-    let var = match parts[0].strip_prefix(":") {
-        Some(s) => Var::Temp(s.parse().unwrap()),
-        None => Var::Named(parts[0].to_string()),
+fn token(lexeme: Lexeme) -> Result<Token, ParseError> {
+    let Lexeme { text, column } = lexeme;
+    let token = match text {
+        "inc" => Token::Inc,
+        "dec" => Token::Dec,
+        "add" => Token::Add,
+        "mul" => Token::Mul,
+        "div" => Token::Div,
+        "eq" => Token::Eq,
+        "lt" => Token::Lt,
+        "neg" => Token::Neg,
+        "ap" => Token::Ap,
+        "s" => Token::S,
+        "c" => Token::C,
+        "b" => Token::B,
+        "t" => Token::True,
+        "f" => Token::False,
+        "pwr2" => Token::Pwr2,
+        "i" => Token::I,
+        "cons" | "vec" => Token::Cons,
+        "car" => Token::Head,
+        "cdr" => Token::Tail,
+        "nil" => Token::Nil,
+        "isnil" => Token::IsNil,
+        "draw" => Token::Draw,
+        "checkerboard" => Token::Checkerboard,
+        "multipledraw" => Token::MultipleDraw,
+        "modem" => Token::Modem,
+        "send" => Token::Send,
+        "if0" => Token::If0,
+        "f38" => Token::F38,
+        "interact" => Token::Interact,
+        s if s.chars().all(|c| c.is_ascii_digit())
+            || s.starts_with('-') && s.len() > 1 && s[1..].chars().all(|c| c.is_ascii_digit()) =>
+        {
+            Token::Number(s.parse::<Number>().unwrap())
+        }
+        s if s.starts_with(':') => Token::Var(parse_var(s, column)?),
+        s => match parse_var(s, column) {
+            Ok(var) => Token::Var(var),
+            Err(_) => return Err(ParseError::new(column, format!("unknown token `{}`", s))),
+        },
     };
-    Stmt {
-        var,
-        code: parse(parts[1]),
+    Ok(token)
+}
+
+// Tokens with the column each one came from, list sugar expands to several tokens
+#[derive(Default)]
+struct Code {
+    tokens: Vec<Token>,
+    columns: Vec<usize>,
+}
+
+impl Code {
+    fn push(&mut self, token: Token, column: usize) {
+        self.tokens.push(token);
+        self.columns.push(column);
+    }
+
+    // Turns lexemes into tokens up to the end or the first `,` or `)` that doesn't belong
+    // to a list of their own, which is left for the caller
+    fn terms(&mut self, lexemes: &[Lexeme], pos: &mut usize) -> Result<(), ParseError> {
+        while let Some(&lexeme) = lexemes.get(*pos) {
+            match lexeme.text {
+                "," | ")" => return Ok(()),
+                "(" => {
+                    *pos += 1;
+                    self.list(lexemes, pos, lexeme.column)?;
+                }
+                _ => {
+                    self.push(token(lexeme)?, lexeme.column);
+                    *pos += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // `( a , b )` is `ap ap cons a ap ap cons b nil`, `( )` is `nil`. Each element must be
+    // a single term.
+    fn list(&mut self, lexemes: &[Lexeme], pos: &mut usize, open: usize) -> Result<(), ParseError> {
+        let column = |pos: usize| lexemes.get(pos).map_or(open, |l| l.column);
+        if matches!(lexemes.get(*pos), Some(l) if l.text == ")") {
+            self.push(Token::Nil, column(*pos));
+            *pos += 1;
+            return Ok(());
+        }
+        loop {
+            let start = column(*pos);
+            for token in [Token::Ap, Token::Ap, Token::Cons] {
+                self.push(token, start);
+            }
+            let item = self.tokens.len();
+            self.terms(lexemes, pos)?;
+            check_terms(&self.tokens[item..], &self.columns[item..], column(*pos))?;
+            match lexemes.get(*pos) {
+                Some(l) if l.text == "," => *pos += 1,
+                Some(l) => {
+                    self.push(Token::Nil, l.column);
+                    *pos += 1;
+                    return Ok(());
+                }
+                None => return Err(ParseError::new(open, "unclosed `(`".to_string())),
+            }
+        }
+    }
+}
+
+// `start` is the column reported when there's no expression at all
+fn parse(lexemes: &[Lexeme], start: usize) -> Result<Vec<Token>, ParseError> {
+    let mut code = Code::default();
+    let mut pos = 0;
+    code.terms(lexemes, &mut pos)?;
+    match lexemes.get(pos) {
+        Some(l) if l.text == "," => {
+            return Err(ParseError::new(
+                l.column,
+                "`,` outside of a list".to_string(),
+            ))
+        }
+        Some(l) => return Err(ParseError::new(l.column, "unmatched `)`".to_string())),
+        None => {}
     }
+    check_terms(&code.tokens, &code.columns, start)?;
+    Ok(code.tokens)
+}
+
+// Where `==` splits a line, if it does
+fn split_at_eq(lexemes: &[Lexeme]) -> Option<usize> {
+    lexemes.iter().position(|l| l.text == "==")
 }
 
-pub fn parse_test(text: &str) -> (Stmt, Stmt) {
-    let parts: Vec<&str> = text.split("==").collect();
-    (
+pub fn parse_line(text: &str) -> Result<Stmt, ParseError> {
+    let lexemes = lex(text);
+    if is_lambda_definition(&lexemes) {
+        return parse_lambda_definition(&lexemes);
+    }
+    match lexemes.as_slice() {
+        [name, eq, code @ ..] if eq.text == "=" => Ok(Stmt {
+            var: parse_var(name.text, name.column)?,
+            code: parse(code, eq.column + 2)?,
+        }),
+        _ => Err(ParseError::new(
+            lexemes.get(1).map_or(1, |l| l.column),
+            "expected `<name> = <expression>`".to_string(),
+        )),
+    }
+}
+
+pub fn parse_test(text: &str) -> Result<(Stmt, Stmt), ParseError> {
+    let lexemes = lex(text);
+    let eq = split_at_eq(&lexemes)
+        .ok_or_else(|| ParseError::new(1, "expected `<expression> == <expression>`".to_string()))?;
+    Ok((
         Stmt {
             var: Var::Named("expr".to_string()),
-            code: parse(parts[0].trim()),
+            code: parse(&lexemes[..eq], 1)?,
         },
         Stmt {
             var: Var::Named("expected".to_string()),
-            code: parse(parts[1].trim()),
+            code: parse(&lexemes[eq + 1..], lexemes[eq].column + 3)?,
         },
-    )
+    ))
 }
 
-pub fn parse_picture(text: &str) -> Stmt {
-    let parts: Vec<&str> = text.split("==").collect();
-    Stmt {
+// Anything after `==` describes the picture for the reader and isn't checked
+pub fn parse_picture(text: &str) -> Result<Stmt, ParseError> {
+    let lexemes = lex(text);
+    let end = split_at_eq(&lexemes).unwrap_or(lexemes.len());
+    Ok(Stmt {
         var: Var::Named("picture".to_string()),
-        code: parse(parts[0].trim()),
+        code: parse(&lexemes[..end], 1)?,
+    })
+}
+
+pub fn parse_expr(text: &str) -> Result<Stmt, ParseError> {
+    Ok(Stmt {
+        var: Var::Named("expr".to_string()),
+        code: parse(&lex(text), 1)?,
+    })
+}
+
+// A file of definitions, blank lines and comments are skipped
+pub fn parse_file(name: &str, text: &str) -> Result<Vec<Stmt>, ParseError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !is_blank(line))
+        .map(|(i, line)| parse_line(line).map_err(|e| e.at_line(i + 1).in_file(name)))
+        .collect()
+}
+
+// Definitions can also be written with lambdas, one per line after `def`:
+//   def statefuldraw st click = [0, cons click st, [cons click st]]
+//   def twice f = \x -> f (f x)
+//   def abs x = if lt x 0 then neg x else x
+//   def sq x = let y = mul x x in add y 0
+// Application is juxtaposition, `if c then a else b` is `c a b` for a boolean `c` and
+// `[a, b]` is a list. Names bound by lambdas hide built-ins of the same name. The lambdas
+// are compiled away by bracket abstraction, so the result is an ordinary `Stmt`.
+
+#[derive(Debug, PartialEq, Clone)]
+enum Expr {
+    Bound(String),
+    Token(Token),
+    Apply(Box<Expr>, Box<Expr>),
+    Lambda(String, Box<Expr>),
+    // Kept apart from `Apply` so that long literal lists don't nest deeply
+    List(Vec<Expr>),
+}
+
+fn apply(f: Expr, x: Expr) -> Expr {
+    Expr::Apply(Box::new(f), Box::new(x))
+}
+
+fn lambdas(params: Vec<String>, body: Expr) -> Expr {
+    params
+        .into_iter()
+        .rev()
+        .fold(body, |body, x| Expr::Lambda(x, Box::new(body)))
+}
+
+const KEYWORDS: &[&str] = &[
+    "=", "->", "\\", "let", "in", "if", "then", "else", "(", ")", "[", "]", ",",
+];
+
+fn is_lambda_definition(lexemes: &[Lexeme]) -> bool {
+    // `def = ...` still defines a variable called `def`
+    matches!(lexemes, [def, name, ..] if def.text == "def" && name.text != "=")
+}
+
+struct LambdaParser<'a> {
+    lexemes: &'a [Lexeme<'a>],
+    pos: usize,
+    // Innermost last
+    bound: Vec<String>,
+}
+
+impl<'a> LambdaParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.lexemes.get(self.pos).map(|l| l.text)
+    }
+
+    // Where the next lexeme is, or just past the last one
+    fn column(&self) -> usize {
+        match self.lexemes.get(self.pos) {
+            Some(l) => l.column,
+            None => self.lexemes.last().map_or(1, |l| l.column + l.text.len()),
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, ParseError> {
+        Err(ParseError::new(self.column(), message))
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), ParseError> {
+        if self.peek() != Some(text) {
+            return self.error(format!("expected `{}`", text));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(text) if !KEYWORDS.contains(&text) => {
+                let column = self.column();
+                if let Var::Temp(_) = parse_var(text, column)? {
+                    return Err(ParseError::new(column, format!("can't bind `{}`", text)));
+                }
+                self.pos += 1;
+                Ok(text.to_string())
+            }
+            _ => self.error("expected a name".to_string()),
+        }
+    }
+
+    // Names up to `stop`, which is skipped
+    fn params(&mut self, stop: &str) -> Result<Vec<String>, ParseError> {
+        let mut params = vec![];
+        while self.peek() != Some(stop) {
+            params.push(self.name()?);
+        }
+        self.pos += 1;
+        Ok(params)
+    }
+
+    // `body` is parsed with `params` in scope
+    fn scoped(&mut self, params: &[String]) -> Result<Expr, ParseError> {
+        let depth = self.bound.len();
+        self.bound.extend(params.iter().cloned());
+        let body = self.expr();
+        self.bound.truncate(depth);
+        body
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some("\\") => {
+                self.pos += 1;
+                let column = self.column();
+                let params = self.params("->")?;
+                if params.is_empty() {
+                    return Err(ParseError::new(column, "expected a name".to_string()));
+                }
+                let body = self.scoped(&params)?;
+                Ok(lambdas(params, body))
+            }
+            Some("let") => {
+                self.pos += 1;
+                let name = self.name()?;
+                let params = self.params("=")?;
+                let value = self.scoped(&params)?;
+                self.expect("in")?;
+                let body = self.scoped(std::slice::from_ref(&name))?;
+                Ok(apply(
+                    Expr::Lambda(name, Box::new(body)),
+                    lambdas(params, value),
+                ))
+            }
+            Some("if") => {
+                self.pos += 1;
+                let cond = self.expr()?;
+                self.expect("then")?;
+                let then = self.expr()?;
+                self.expect("else")?;
+                let other = self.expr()?;
+                Ok(apply(apply(cond, then), other))
+            }
+            _ => {
+                let mut term = self.atom()?;
+                loop {
+                    match self.peek() {
+                        // A lambda, `let` or `if` runs to the end, so it can be the last argument
+                        Some("\\") | Some("let") | Some("if") => {
+                            return Ok(apply(term, self.expr()?))
+                        }
+                        Some(text) if !KEYWORDS.contains(&text) || text == "(" || text == "[" => {
+                            term = apply(term, self.atom()?)
+                        }
+                        _ => return Ok(term),
+                    }
+                }
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some("(") => {
+                self.pos += 1;
+                let e = self.expr()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Some("[") => {
+                self.pos += 1;
+                let mut items = vec![];
+                if self.peek() != Some("]") {
+                    items.push(self.expr()?);
+                    while self.peek() == Some(",") {
+                        self.pos += 1;
+                        items.push(self.expr()?);
+                    }
+                }
+                self.expect("]")?;
+                Ok(Expr::List(items))
+            }
+            Some(text) if self.bound.iter().any(|b| b == text) => {
+                self.pos += 1;
+                Ok(Expr::Bound(text.to_string()))
+            }
+            Some(text) if !KEYWORDS.contains(&text) => {
+                let t = token(self.lexemes[self.pos])?;
+                if t == Token::Ap {
+                    return self.error("`ap` isn't needed, apply by juxtaposition".to_string());
+                }
+                self.pos += 1;
+                Ok(Expr::Token(t))
+            }
+            _ => self.error("expected an expression".to_string()),
+        }
+    }
+}
+
+fn occurs(x: &str, e: &Expr) -> bool {
+    match e {
+        Expr::Bound(y) => x == y,
+        Expr::Token(_) => false,
+        Expr::Apply(f, a) => occurs(x, f) || occurs(x, a),
+        Expr::Lambda(y, body) => x != y && occurs(x, body),
+        Expr::List(items) => items.iter().any(|i| occurs(x, i)),
+    }
+}
+
+// Removes every lambda, innermost first
+fn compile_lambdas(e: Expr) -> Expr {
+    match e {
+        Expr::Lambda(x, body) => abstract_var(&x, compile_lambdas(*body)),
+        Expr::Apply(f, a) => apply(compile_lambdas(*f), compile_lambdas(*a)),
+        Expr::List(items) => Expr::List(items.into_iter().map(compile_lambdas).collect()),
+        e => e,
+    }
+}
+
+// A term without `x` that gives `e` when applied to `x`. `e` has no lambdas left.
+fn abstract_var(x: &str, e: Expr) -> Expr {
+    if !occurs(x, &e) {
+        return apply(Expr::Token(Token::True), e);
+    }
+    match e {
+        Expr::Bound(_) => Expr::Token(Token::I),
+        Expr::Apply(f, a) => match (occurs(x, &f), occurs(x, &a)) {
+            (false, _) if *a == Expr::Bound(x.to_string()) => *f,
+            (true, false) => apply(apply(Expr::Token(Token::C), abstract_var(x, *f)), *a),
+            (false, _) => apply(apply(Expr::Token(Token::B), *f), abstract_var(x, *a)),
+            (true, true) => apply(
+                apply(Expr::Token(Token::S), abstract_var(x, *f)),
+                abstract_var(x, *a),
+            ),
+        },
+        Expr::List(mut items) => {
+            let first = items.remove(0);
+            let cons = apply(apply(Expr::Token(Token::Cons), first), Expr::List(items));
+            abstract_var(x, cons)
+        }
+        Expr::Token(_) | Expr::Lambda(..) => unreachable!(),
+    }
+}
+
+fn to_tokens(e: Expr, code: &mut Vec<Token>) {
+    let mut stack = vec![e];
+    while let Some(e) = stack.pop() {
+        match e {
+            Expr::Token(t) => code.push(t),
+            Expr::Apply(f, a) => {
+                code.push(Token::Ap);
+                stack.push(*a);
+                stack.push(*f);
+            }
+            Expr::List(items) => {
+                stack.push(Expr::Token(Token::Nil));
+                for item in items.into_iter().rev() {
+                    stack.push(item);
+                    stack.push(Expr::Token(Token::Cons));
+                    stack.push(Expr::Token(Token::Ap));
+                    stack.push(Expr::Token(Token::Ap));
+                }
+            }
+            Expr::Bound(_) | Expr::Lambda(..) => unreachable!(),
+        }
+    }
+}
+
+fn parse_lambda_definition(lexemes: &[Lexeme]) -> Result<Stmt, ParseError> {
+    let mut parser = LambdaParser {
+        lexemes,
+        pos: 1,
+        bound: vec![],
+    };
+    let column = parser.column();
+    let var = parse_var(lexemes[1].text, column)?;
+    parser.pos += 1;
+    let params = parser.params("=")?;
+    let body = parser.scoped(&params)?;
+    if parser.pos < lexemes.len() {
+        return parser.error("unexpected term after the expression".to_string());
+    }
+    let mut code = vec![];
+    to_tokens(compile_lambdas(lambdas(params, body)), &mut code);
+    Ok(Stmt { var, code })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> (usize, String) {
+        let e = parse_line(text).unwrap_err();
+        (e.column, e.message)
+    }
+
+    fn code(text: &str) -> Vec<Token> {
+        parse_expr(text).unwrap().code
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("x = ap inc fooo!").0, 12);
+        assert_eq!(error("x = ap inc fooo!").1, "unknown token `fooo!`");
+        assert_eq!(error("x ap inc 1").0, 3);
+        assert_eq!(error(":x = 1").1, "invalid variable `:x`");
+        assert_eq!(error("x = ( 1 , 2").0, 5);
+        assert_eq!(error("x = ( 1 ) )").1, "unmatched `)`");
+        assert_eq!(error("x = ap ap add 1").0, 5);
+        assert_eq!(error("x = ap inc 1 2").0, 14);
+        assert_eq!(error("x = ").1, "expected an expression");
+        assert_eq!(error("x = ( 1 , )").0, 11);
+        assert_eq!(error("x = ( 1 2 )").0, 9);
+        assert_eq!(parse_test("ap inc 1 == ap").unwrap_err().column, 13);
+
+        let e = parse_file("defs.txt", "x = 1\n\ny = ap inc").unwrap_err();
+        assert_eq!(e.to_string(), "defs.txt:3:5: `ap` is missing an argument");
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            code("( ( 1 , 2 ) , 3 )"),
+            code("ap ap cons ap ap cons 1 ap ap cons 2 nil ap ap cons 3 nil")
+        );
+        assert_eq!(code("((1,2),3)"), code("( ( 1 , 2 ) , 3 )"));
+        assert_eq!(code("( )"), vec![Token::Nil]);
+        assert_eq!(
+            code("( ( ) , ap inc 1 )"),
+            code("ap ap cons nil ap ap cons ap inc 1 nil")
+        );
+    }
+
+    #[test]
+    fn test_whitespace_and_comments() {
+        let stmt = parse_line("\tx  =\tap  inc 1  -- the successor of 1").unwrap();
+        assert_eq!(stmt, parse_line("x = ap inc 1").unwrap());
+        assert_eq!(code("ap neg -1 # comment"), code("ap neg -1"));
+        assert!(is_blank("  -- nothing but a comment"));
+        assert!(is_blank(" \t"));
+        assert!(!is_definition("ap inc 1 == 2"));
+        assert!(is_definition("x\t= 1"));
+
+        let stmts = parse_file("defs.txt", "# header\n\nx = 1\n  \ny = ( x , x ) -- pair\n");
+        assert_eq!(stmts.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_lambda_definitions() {
+        let twice = parse_line("def twice f x = f (f x)").unwrap();
+        assert_eq!(twice, parse_line("twice = ap ap s b i").unwrap());
+        let pair = parse_line("def pair x = [x, [], \\y -> y]").unwrap();
+        assert_eq!(pair, parse_line("pair = ap ap c cons ( nil , i )").unwrap());
+        assert!(is_definition("def f x = x"));
+        assert_eq!(
+            parse_line("def = 1").unwrap().var,
+            Var::Named("def".to_string())
+        );
+
+        let error = |text| parse_line(text).unwrap_err().column;
+        assert_eq!(error("def f x = ap f x"), 11);
+        assert_eq!(error("def f x = (x"), 13);
+        assert_eq!(error("def f :1 = 1"), 7);
+        assert_eq!(error("def f x = if x then 1"), 22);
     }
 }
@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::eval::{Arena, CancelToken, Rewrite, Tracer, Value};
+use crate::printer::{builtin_name, print_value_short, var_name};
+use crate::syntax::{parse_var, Var};
+
+// Arguments are cut short after this many characters in a trace line
+const MAX_ARG_LEN: usize = 60;
+// And after this many when the debugger prints them in full
+const MAX_FULL_LEN: usize = 10_000;
+
+const DEBUG_HELP: &str = "s, Enter    Go on to the next rewrite
+c           Continue to the next breakpoint
+b <name>    Break whenever the variable is unfolded
+d <name>    Remove a breakpoint
+a           Print the arguments of the rewrite
+q           Stop the evaluation";
+
+fn head_and_args(rewrite: &Rewrite) -> (String, &[Value]) {
+    match rewrite {
+        Rewrite::Unfold(v, args) => (var_name(v), args),
+        Rewrite::Rule(b, args) => (builtin_name(*b).to_string(), args),
+    }
+}
+
+// The term a rewrite reduced, as traces show it: `ap ap add 1 ap inc 2`
+pub fn redex(arena: &Arena, rewrite: &Rewrite) -> String {
+    let (head, args) = head_and_args(rewrite);
+    let mut text = "ap ".repeat(args.len()) + &head;
+    for arg in args {
+        text.push(' ');
+        text.push_str(&print_value_short(arena, *arg, MAX_ARG_LEN));
+    }
+    text
+}
+
+fn unfolds(rewrite: &Rewrite, vars: &[Var]) -> bool {
+    matches!(rewrite, Rewrite::Unfold(v, _) if vars.contains(v))
+}
+
+// Writes a line per rewrite: the number of the rewrite and the term it reduced. With `vars`,
+// only the unfolds of those variables are written, still numbered among all the rewrites.
+pub fn writer<W: Write + 'static>(mut out: W, vars: Vec<Var>) -> Tracer {
+    let mut step = 0;
+    Box::new(move |arena, rewrite| {
+        step += 1;
+        if vars.is_empty() || unfolds(&rewrite, &vars) {
+            // A trace that can't be written isn't worth failing the evaluation for
+            let _ = writeln!(out, "{} {}", step, redex(arena, &rewrite));
+        }
+    })
+}
+
+// Where `--trace` goes and what it's limited to
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceOptions {
+    // `-` is the standard output
+    pub path: PathBuf,
+    pub vars: Vec<Var>,
+}
+
+impl TraceOptions {
+    pub fn tracer(&self) -> io::Result<Tracer> {
+        let vars = self.vars.clone();
+        if self.path == Path::new("-") {
+            return Ok(writer(io::stdout(), vars));
+        }
+        Ok(writer(BufWriter::new(File::create(&self.path)?), vars))
+    }
+}
+
+// Stops after rewrites to ask what to do next. It starts out stepping through every rewrite,
+// or continuing to the first breakpoint if it's given any.
+pub struct Debugger<R, W> {
+    input: R,
+    out: W,
+    breakpoints: Vec<Var>,
+    stepping: bool,
+    step: u64,
+    // Stops the evaluation on `q`
+    cancel: CancelToken,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    pub fn new(input: R, out: W, breakpoints: Vec<Var>, cancel: CancelToken) -> Self {
+        Debugger {
+            input,
+            out,
+            stepping: breakpoints.is_empty(),
+            breakpoints,
+            step: 0,
+            cancel,
+        }
+    }
+
+    // Runs to the end without stopping again
+    fn detach(&mut self) {
+        self.stepping = false;
+        self.breakpoints.clear();
+    }
+
+    pub fn on_rewrite(&mut self, arena: &Arena, rewrite: &Rewrite) -> io::Result<()> {
+        self.step += 1;
+        if !self.stepping && !unfolds(rewrite, &self.breakpoints) {
+            return Ok(());
+        }
+        writeln!(self.out, "{} {}", self.step, redex(arena, rewrite))?;
+        loop {
+            write!(self.out, "debug> ")?;
+            self.out.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                self.detach();
+                return Ok(());
+            }
+            let line = line.trim();
+            let (command, arg) = line.split_at(line.find(' ').unwrap_or(line.len()));
+            let var = parse_var(arg.trim(), 1);
+            match (command, var) {
+                ("", _) | ("s", _) => {
+                    self.stepping = true;
+                    return Ok(());
+                }
+                ("c", _) => {
+                    self.stepping = false;
+                    return Ok(());
+                }
+                ("b", Ok(var)) => self.breakpoints.push(var),
+                ("d", Ok(var)) => self.breakpoints.retain(|v| *v != var),
+                ("a", _) => {
+                    for (i, arg) in head_and_args(rewrite).1.iter().enumerate() {
+                        let text = print_value_short(arena, *arg, MAX_FULL_LEN);
+                        writeln!(self.out, "{}: {}", i + 1, text)?;
+                    }
+                }
+                ("q", _) => {
+                    self.cancel.cancel();
+                    self.detach();
+                    return Ok(());
+                }
+                _ => writeln!(self.out, "{}", DEBUG_HELP)?,
+            }
+        }
+    }
+
+    pub fn into_tracer(mut self) -> Tracer
+    where
+        R: 'static,
+        W: 'static,
+    {
+        Box::new(move |arena, rewrite| {
+            // Without a terminal to talk to, the evaluation just goes on
+            if self.on_rewrite(arena, &rewrite).is_err() {
+                self.detach();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{EvalError, State};
+    use crate::syntax::parse_line;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // An output that can still be read once the tracer owns it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn state() -> State {
+        let mut state = State::new();
+        for line in &[
+            "inc2 = ap ap b inc inc",
+            "x = ap ap add ap inc2 1 ap inc2 2",
+        ] {
+            state.interpret(parse_line(line).unwrap()).unwrap();
+        }
+        state
+    }
+
+    fn eval_x(state: &State) -> Result<Value, EvalError> {
+        let x = state.arena_mut().var(Var::Named("x".to_string()));
+        state.eval(x)
+    }
+
+    #[test]
+    fn test_writer() {
+        let out = Shared::default();
+        let mut state = state();
+        state.set_tracer(Some(writer(out.clone(), vec![])));
+        eval_x(&state).unwrap();
+        // Strict arguments are evaluated by the time their rule fires
+        let expected = "1 inc2
+2 ap ap ap b inc inc 1
+3 ap inc 1
+4 ap inc 2
+5 inc2
+6 ap ap ap b inc inc 2
+7 ap inc 2
+8 ap inc 3
+9 ap ap add 3 4
+10 x
+";
+        assert_eq!(out.text(), expected);
+
+        // Only the unfolds of `inc2`, numbered from the start of the trace
+        let out = Shared::default();
+        let vars = vec![Var::Named("inc2".to_string())];
+        state.set_tracer(Some(writer(out.clone(), vars)));
+        state
+            .interpret(parse_line("y = ap inc2 5").unwrap())
+            .unwrap();
+        state.eval_v(&Var::Named("y".to_string())).unwrap();
+        assert_eq!(out.text(), "1 inc2\n");
+    }
+
+    #[test]
+    fn test_debugger() {
+        let out = Shared::default();
+        let mut state = state();
+        let breakpoints = vec![Var::Named("inc2".to_string())];
+        let cancel = state.cancel_token();
+        let debugger = Debugger::new(
+            "a\nb x\nc\nc\n".as_bytes(),
+            out.clone(),
+            breakpoints,
+            cancel,
+        );
+        state.set_tracer(Some(debugger.into_tracer()));
+        eval_x(&state).unwrap();
+        let expected = "1 inc2\ndebug> debug> debug> 5 inc2\ndebug> 10 x\ndebug> ";
+        assert_eq!(out.text(), expected);
+
+        // Stepping, then quitting
+        let out = Shared::default();
+        let mut state = self::state();
+        let cancel = state.cancel_token();
+        let debugger = Debugger::new("s\na\nq\n".as_bytes(), out.clone(), vec![], cancel);
+        state.set_tracer(Some(debugger.into_tracer()));
+        assert_eq!(eval_x(&state), Err(EvalError::Cancelled));
+        let expected = "1 inc2
+debug> 2 ap ap ap b inc inc 1
+debug> 1: inc
+2: inc
+3: 1
+debug> ";
+        assert_eq!(out.text(), expected);
+    }
+}
@@ -1,4 +1,9 @@
+use std::collections::HashSet;
+
+use num_traits::ToPrimitive;
+
 use crate::eval::*;
+use crate::syntax::Number;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Point {
@@ -13,28 +18,60 @@ pub struct Picture {
     pub points: Vec<Point>,
 }
 
-#[derive(Debug, Default)]
-pub struct PictureBuilder {
-    points: Vec<(i64, i64)>,
+fn invalid_picture() -> EvalError {
+    EvalError::Stuck("expected a list of lists of points".to_string())
 }
 
+#[derive(Debug, Default)]
+pub struct PictureBuilder;
+
 impl PictureBuilder {
-    pub fn from_nested_list(mut list: NestedList) -> Vec<Picture> {
+    pub fn from_nested_list(mut list: NestedList) -> Result<Vec<Picture>, EvalError> {
         let mut result = vec![];
         loop {
             match list {
                 NestedList::Nil => break,
                 NestedList::Cons(head, tail) => {
-                    result.push(Self::from_nested_list_one(*head));
+                    result.push(Self::from_nested_list_one(*head)?);
                     list = *tail;
                 }
-                _ => panic!("Invalid list"),
+                _ => return Err(invalid_picture()),
             }
         }
-        result
+        Ok(result)
     }
 
-    fn from_nested_list_one(mut list: NestedList) -> Picture {
+    // Accepts a picture, or a list of pictures and lists of points
+    pub fn from_value(arena: &Arena, val: Value) -> Result<Vec<Picture>, EvalError> {
+        if let Value_::Picture(p) = arena.get(val) {
+            return Ok(vec![p.clone()]);
+        }
+        let mut result = vec![];
+        let mut curr = val;
+        loop {
+            curr = match arena.get(curr) {
+                Value_::BuiltIn(BuiltIn::Nil) => break,
+                Value_::Apply(f1, tail) => match arena.get(*f1) {
+                    Value_::Apply(f0, head)
+                        if *arena.get(*f0) == Value_::BuiltIn(BuiltIn::Cons) =>
+                    {
+                        if let Value_::Picture(p) = arena.get(*head) {
+                            result.push(p.clone());
+                        } else {
+                            let points = NestedList::from_value(arena, *head)?;
+                            result.push(Self::from_nested_list_one(points)?);
+                        }
+                        *tail
+                    }
+                    _ => return Err(invalid_picture()),
+                },
+                _ => return Err(invalid_picture()),
+            };
+        }
+        Ok(result)
+    }
+
+    pub fn from_nested_list_one(mut list: NestedList) -> Result<Picture, EvalError> {
         let mut points = vec![];
         loop {
             // we expect a list of pairs here
@@ -42,28 +79,30 @@ impl PictureBuilder {
                 NestedList::Nil => break,
                 NestedList::Cons(head, tail) => {
                     match *head {
-                        NestedList::Cons(x, y) => {
-                            if let NestedList::Number(x) = *x {
-                                if let NestedList::Number(y) = *y {
-                                    points.push((x, y));
-                                } else {
-                                    panic!("Invalid list")
+                        NestedList::Cons(x, y) => match (*x, *y) {
+                            (NestedList::Number(x), NestedList::Number(y)) => {
+                                match (x.to_i64(), y.to_i64()) {
+                                    (Some(x), Some(y)) => points.push((x, y)),
+                                    _ => {
+                                        return Err(EvalError::Stuck(
+                                            "point coordinates out of range".to_string(),
+                                        ))
+                                    }
                                 }
-                            } else {
-                                panic!("Invalid list")
                             }
-                        }
-                        _ => panic!("Invalid list"),
+                            _ => return Err(invalid_picture()),
+                        },
+                        _ => return Err(invalid_picture()),
                     }
                     list = *tail;
                 }
-                _ => panic!("Invalid list"),
+                _ => return Err(invalid_picture()),
             }
         }
-        Self::from_points(points)
+        Ok(Self::from_points(points))
     }
 
-    fn from_points(points: Vec<(i64, i64)>) -> Picture {
+    pub fn from_points(points: Vec<(i64, i64)>) -> Picture {
         if points.is_empty() {
             Picture::default()
         } else {
@@ -101,19 +140,23 @@ impl PictureBuilder {
 
 impl std::fmt::Display for Picture {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // SLOW - TODO REWRITE
-        for y in 0..self.height {
-            for x in 0..self.width {
-                if self.points.contains(&Point {
-                    x: x as i32,
-                    y: y as i32,
-                }) {
+        // Nothing at all, not even the origin
+        if self.points.is_empty() {
+            return Ok(());
+        }
+        // The picture is centered on the origin, see `from_points`
+        let points: HashSet<&Point> = self.points.iter().collect();
+        let half_width = (self.width / 2) as i32;
+        let half_height = (self.height / 2) as i32;
+        for y in -half_height..=half_height {
+            for x in -half_width..=half_width {
+                if points.contains(&Point { x, y }) {
                     write!(f, "#")?;
                 } else {
                     write!(f, ".")?;
                 }
             }
-            if y != self.height - 1 {
+            if y != half_height {
                 writeln!(f)?;
             }
         }
@@ -125,56 +168,91 @@ impl std::fmt::Display for Picture {
 pub enum NestedList {
     Nil,
     Cons(Box<NestedList>, Box<NestedList>),
-    Number(i64),
+    Number(Number),
 }
 
 impl NestedList {
-    pub fn from_value(val: Value) -> NestedList {
+    pub fn from_value(arena: &Arena, val: Value) -> Result<NestedList, EvalError> {
         // println!("{:?}", val);
-        match &val.borrow().val {
-            Value_::Apply(f1, tail) => {
-                if let Value_::Apply(f0, head) = &f1.borrow().val {
-                    if let Value_::BuiltIn(BuiltIn::Cons) = &f0.borrow().val {
-                        NestedList::Cons(
-                            Box::new(Self::from_value(head.clone())),
-                            Box::new(Self::from_value(tail.clone())),
-                        )
-                    } else {
-                        panic!("Invalid list format")
+        // Long lists are walked along their tails in a loop, only the heads recurse
+        let mut heads = vec![];
+        let mut curr = val;
+        let last = loop {
+            curr = match arena.get(curr) {
+                Value_::Apply(f1, tail) => match arena.get(*f1) {
+                    Value_::Apply(f0, head)
+                        if *arena.get(*f0) == Value_::BuiltIn(BuiltIn::Cons) =>
+                    {
+                        heads.push(Self::from_value(arena, *head)?);
+                        *tail
                     }
-                } else {
-                    panic!("Invalid list format")
-                }
-            }
-            Value_::BuiltIn(BuiltIn::Nil) => NestedList::Nil,
-            Value_::Number(n) => NestedList::Number(*n),
-            _ => panic!("Invalid value in eval_list: {:?}", val),
-        }
+                    _ => return Err(EvalError::Stuck("invalid list format".to_string())),
+                },
+                Value_::BuiltIn(BuiltIn::Nil) => break NestedList::Nil,
+                Value_::Number(n) => break NestedList::Number(n.clone()),
+                _ => return Err(EvalError::Stuck("not a list".to_string())),
+            };
+        };
+        Ok(heads.into_iter().rev().fold(last, |tail, head| {
+            NestedList::Cons(Box::new(head), Box::new(tail))
+        }))
     }
 
-    pub fn into_value(self) -> Value {
+    pub fn into_value(self, arena: &mut Arena) -> Value {
         match self {
             NestedList::Nil => b(BuiltIn::Nil),
             NestedList::Cons(head, tail) => {
-                ap(ap(b(BuiltIn::Cons), head.into_value()), tail.into_value())
+                let head = head.into_value(arena);
+                let tail = tail.into_value(arena);
+                arena.cons(head, tail)
             }
-            NestedList::Number(n) => number(n),
+            NestedList::Number(n) => arena.number(n),
         }
     }
 
-    pub fn unwrap_cons(self) -> (NestedList, NestedList) {
+    pub fn unwrap_cons(self) -> Result<(NestedList, NestedList), EvalError> {
         if let NestedList::Cons(a, b) = self {
-            (*a, *b)
+            Ok((*a, *b))
         } else {
-            panic!("Not a cons");
+            Err(EvalError::Stuck("not a cons".to_string()))
         }
     }
 
-    pub fn unwrap_number(self) -> i64 {
+    pub fn unwrap_number(self) -> Result<Number, EvalError> {
         if let NestedList::Number(n) = self {
-            n
+            Ok(n)
         } else {
-            panic!("Not a number")
+            Err(EvalError::Stuck("not a number".to_string()))
+        }
+    }
+}
+
+// Writes the list in the notation the parser reads: `( 1 , 2 )` for proper lists and
+// `ap ap cons 1 2` for everything else
+impl std::fmt::Display for NestedList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NestedList::Nil => write!(f, "nil"),
+            NestedList::Number(n) => write!(f, "{}", n),
+            NestedList::Cons(head, tail) => {
+                let mut items = vec![head];
+                let mut rest = tail;
+                while let NestedList::Cons(head, tail) = &**rest {
+                    items.push(head);
+                    rest = tail;
+                }
+                if **rest != NestedList::Nil {
+                    return write!(f, "ap ap cons {} {}", head, tail);
+                }
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ,")?;
+                    }
+                    write!(f, " {}", item)?;
+                }
+                write!(f, " )")
+            }
         }
     }
 }
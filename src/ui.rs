@@ -1,12 +1,20 @@
-use std::{cell::RefCell, collections::HashMap, path::Path, sync::Arc};
+use std::{cell::RefCell, collections::HashMap, path::Path, rc::Rc};
 
 use fltk::{app::*, draw::*, window::*};
 
-use crate::eval::State;
-use crate::interact::run_interaction;
+use crate::eval::{EvalError, State};
 use crate::modem::*;
-use crate::syntax::parse_line;
+use crate::send::Transport;
+use crate::syntax::{is_blank, parse_file, parse_line, ParseError};
 use crate::types::*;
+use crate::worker::{Outcome, Worker};
+
+// A click that takes more round-trips than this is assumed to be stuck
+const MAX_SENDS: usize = 100;
+
+const TITLE: &str = "Galaxy Explorer";
+// The title while a click is evaluated
+const BUSY_TITLE: &str = "Galaxy Explorer (evaluating, Esc cancels)";
 
 #[derive(Default)]
 struct Data {
@@ -73,26 +81,92 @@ fn draw_pixel(point: &Point, blender: &Blender, scale: i32, center_x: i32, cente
     }
 }
 
-pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
+fn invalid_data(e: EvalError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+fn parse_error(e: ParseError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+// What a click came to, reporting the failure instead of bringing the window down
+fn frame(outcome: Outcome) -> Option<(NestedList, NestedList, Vec<Picture>)> {
+    let result = outcome.and_then(|outcome| {
+        let pictures = PictureBuilder::from_nested_list(outcome.data.clone())?;
+        Ok((outcome.state, outcome.data, pictures))
+    });
+    match result {
+        Ok(r) => Some(r),
+        Err(e) => {
+            println!("Evaluation failed: {}", e);
+            None
+        }
+    }
+}
+
+// The definitions of an INTERACTIVE file and the protocol to explore
+fn load(
+    file: &str,
+    data_folder: &Path,
+    mut protocol: Option<String>,
+    transport: Box<dyn Transport>,
+) -> std::io::Result<(State, String)> {
     let mut state = State::new();
-    let mut protocol = None;
+    state.set_transport(transport);
     // Skip the "INTERACTIVE" line
-    for line in file.lines().skip(1) {
-        if line.is_empty() {
+    for (i, line) in file.lines().enumerate().skip(1) {
+        if is_blank(line) {
         } else if let Some(l) = line.strip_prefix("PROTOCOL ") {
-            println!("Protocol: {}", l);
-            protocol = Some(l.to_string());
+            // The command line takes precedence
+            protocol.get_or_insert_with(|| l.to_string());
         } else if let Some(l) = line.strip_prefix("INCLUDE ") {
-            let f = std::fs::read_to_string(data_folder.join(l))?;
-            for l in f.lines() {
-                state.interpret(parse_line(l));
+            let path = data_folder.join(l);
+            let f = std::fs::read_to_string(&path)?;
+            for stmt in parse_file(&path.display().to_string(), &f).map_err(parse_error)? {
+                state.interpret(stmt).map_err(invalid_data)?;
             }
         } else {
-            state.interpret(parse_line(line));
+            let stmt = parse_line(line).map_err(|e| parse_error(e.at_line(i + 1)))?;
+            state.interpret(stmt).map_err(invalid_data)?;
         }
     }
 
-    let protocol = protocol.expect("Protocol was not defined in the instruction file");
+    let protocol = protocol.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Protocol was not defined in the instruction file",
+        )
+    })?;
+    Ok((state, protocol))
+}
+
+// `configure` gets the state once the definitions are in, and `finish` once the window is
+// closed, both on the thread that evaluates clicks
+pub fn ui_main<C, F>(
+    file: String,
+    data_folder: &Path,
+    protocol: Option<String>,
+    transport: Box<dyn Transport>,
+    configure: C,
+    finish: F,
+) -> std::io::Result<()>
+where
+    C: FnOnce(&mut State) -> std::io::Result<()> + Send + 'static,
+    F: FnOnce(&State) + Send + 'static,
+{
+    // The state lives on the worker's thread, evaluating there keeps the window responsive
+    let data_folder = data_folder.to_path_buf();
+    let (worker, protocol) = Worker::spawn(
+        move || {
+            let (mut state, protocol) = load(&file, &data_folder, protocol, transport)?;
+            configure(&mut state)?;
+            Ok((state, protocol))
+        },
+        MAX_SENDS,
+        || awake(Box::new(|| {})),
+        finish,
+    )?;
+    println!("Protocol: {}", protocol);
 
     const VIEWPORT_WIDTH: u32 = 1024;
     const VIEWPORT_HEIGHT: u32 = 768;
@@ -110,22 +184,17 @@ pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
     ];
 
     let app = App::default();
-    let mut window = Window::new(
-        0,
-        0,
-        VIEWPORT_WIDTH as i32,
-        VIEWPORT_HEIGHT as i32,
-        "Galaxy Explorer",
-    );
+    let mut window = Window::new(0, 0, VIEWPORT_WIDTH as i32, VIEWPORT_HEIGHT as i32, TITLE);
     window.set_type(WindowType::Double);
-    let window = Arc::new(RefCell::new(window.center_screen()));
+    let window = Rc::new(RefCell::new(window.center_screen()));
 
-    let scale = Arc::new(RefCell::new(1 as i32));
-    let is_blending_enabled = Arc::new(RefCell::new(false));
+    let scale = Rc::new(RefCell::new(1));
+    let is_blending_enabled = Rc::new(RefCell::new(false));
 
-    let pics_data = Arc::new(RefCell::new(Data::default()));
-    let mut interaction_state = NestedList::Nil;
-    let mut history: Vec<(NestedList, NestedList)> = vec![];
+    let pics_data = Rc::new(RefCell::new(Data::default()));
+    let interaction_state = Rc::new(RefCell::new(NestedList::Nil));
+    let history: Rc<RefCell<Vec<(NestedList, NestedList)>>> = Rc::new(RefCell::new(vec![]));
+    let worker = Rc::new(RefCell::new(worker));
 
     let pics_ = pics_data.clone();
     let scale_ = scale.clone();
@@ -178,16 +247,31 @@ pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
     }));
 
     if &protocol == "galaxy" {
-        let (new_state, pics) =
-            run_interaction(&mut state, &protocol, interaction_state.clone(), 0, 0);
-        history.push((new_state.clone(), pics.clone()));
-        interaction_state = new_state;
-        pics_data.borrow_mut().vec = PictureBuilder::from_nested_list(pics);
+        worker.borrow_mut().click(NestedList::Nil, 0, 0);
+        window.borrow_mut().set_label(BUSY_TITLE);
     }
 
     let window_ = window.clone();
+    let worker_ = worker.clone();
+    let interaction_state_ = interaction_state.clone();
+    let history_ = history.clone();
+    let pics_data_ = pics_data.clone();
     window.borrow_mut().handle(Box::new(move |e| -> bool {
         match e {
+            fltk::enums::Event::KeyDown
+                if worker_.borrow().is_busy() && event_key() == fltk::enums::Key::Escape =>
+            {
+                // Handled here, Escape would close the window otherwise
+                println!("Cancelling...");
+                worker_.borrow().cancel();
+                true
+            }
+            fltk::enums::Event::Released | fltk::enums::Event::KeyUp
+                if worker_.borrow().is_busy() && event_key() != fltk::enums::Key::Escape =>
+            {
+                println!("Still evaluating, press Escape to cancel");
+                true
+            }
             fltk::enums::Event::Released => {
                 let (mut x, mut y) = get_mouse();
                 // Coords processing
@@ -206,23 +290,11 @@ pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
                         y / scale
                     };
                 }
-                // Click
+                // Click, the frame is drawn once the worker is done
                 println!("Clicked on ({}, {})", x, y);
-                let (new_state, pics) = run_interaction(
-                    &mut state,
-                    &protocol,
-                    interaction_state.clone(),
-                    x as i64,
-                    y as i64,
-                );
-                if history.last() != Some(&(new_state.clone(), pics.clone())) {
-                    history.push((new_state.clone(), pics.clone()));
-                }
-                interaction_state = new_state;
-                pics_data.borrow_mut().vec = PictureBuilder::from_nested_list(pics);
-
-                window_.borrow_mut().redraw();
-
+                let st = interaction_state_.borrow().clone();
+                worker_.borrow_mut().click(st, x as i64, y as i64);
+                window_.borrow_mut().set_label(BUSY_TITLE);
                 true
             }
             fltk::enums::Event::KeyUp => {
@@ -232,7 +304,7 @@ pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
                         let mut img = bmp::Image::new(VIEWPORT_WIDTH, VIEWPORT_HEIGHT);
                         let mut img_data = vec![];
 
-                        let pics = &pics_data.borrow().vec;
+                        let pics = &pics_data_.borrow().vec;
                         for p in pics.iter() {
                             for point in p.points.iter() {
                                 img.set_pixel(
@@ -249,27 +321,32 @@ pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
                     }
                     fltk::enums::Key::BackSpace => {
                         println!("Going back...");
-                        history.pop();
-                        if let Some((st, pics)) = history.last() {
-                            interaction_state = st.clone();
-                            pics_data.borrow_mut().vec = PictureBuilder::from_nested_list(pics.clone());
-                            window_.borrow_mut().redraw();
+                        let mut history = history_.borrow_mut();
+                        let previous = history.len().checked_sub(2).map(|i| &history[i]);
+                        if let Some((st, pics)) = previous {
+                            match PictureBuilder::from_nested_list(pics.clone()) {
+                                Ok(pictures) => {
+                                    *interaction_state_.borrow_mut() = st.clone();
+                                    pics_data_.borrow_mut().vec = pictures;
+                                    history.pop();
+                                    window_.borrow_mut().redraw();
+                                }
+                                // The current frame and its entry stay
+                                Err(e) => println!("Invalid frame in the history: {}", e),
+                            }
                         }
                         true
                     }
                     k => {
                         if k == fltk::enums::Key::from_i32(0xffbf) {
                             // F2 - enable/disable blending
-                            if *is_blending_enabled.borrow() {
-                                *is_blending_enabled.borrow_mut() = false;
-                            } else {
-                                *is_blending_enabled.borrow_mut() = true;
-                            }
+                            let enabled = *is_blending_enabled.borrow();
+                            *is_blending_enabled.borrow_mut() = !enabled;
                             window_.borrow_mut().redraw();
                         }
                         if k == fltk::enums::Key::from_i32(0xffc2) {
                             // F5 - save
-                            if let Some((st, pics)) = history.last() {
+                            if let Some((st, pics)) = history_.borrow().last() {
                                 println!("Saving state...");
                                 let data =
                                     NestedList::Cons(Box::new(st.clone()), Box::new(pics.clone()));
@@ -285,18 +362,25 @@ pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
                             // F8 - load
                             println!("Loading state...");
                             let file = std::fs::read("./save.dat").unwrap();
-                            let serialized: Vec<_> =
-                                file.into_iter().map(|c| c == b'1').collect();
+                            let serialized: Vec<_> = file.into_iter().map(|c| c == b'1').collect();
                             let list = dem_list(&serialized);
-                            let (st, pics) = list.unwrap_cons();
+                            let loaded = list.unwrap_cons().and_then(|(st, pics)| {
+                                let pictures = PictureBuilder::from_nested_list(pics.clone())?;
+                                Ok((st, pics, pictures))
+                            });
+                            match loaded {
+                                Ok((st, pics, pictures)) => {
+                                    let mut history = history_.borrow_mut();
+                                    if history.last() != Some(&(st.clone(), pics.clone())) {
+                                        history.push((st.clone(), pics));
+                                    }
 
-                            if history.last() != Some(&(st.clone(), pics.clone())) {
-                                history.push((st.clone(), pics.clone()));
+                                    *interaction_state_.borrow_mut() = st;
+                                    pics_data_.borrow_mut().vec = pictures;
+                                    window_.borrow_mut().redraw();
+                                }
+                                Err(e) => println!("Invalid save file: {}", e),
                             }
-
-                            interaction_state = st;
-                            pics_data.borrow_mut().vec = PictureBuilder::from_nested_list(pics);
-                            window_.borrow_mut().redraw();
                             true
                         } else {
                             println!("Unhandled key: {:?}", k);
@@ -305,14 +389,30 @@ pub fn ui_main(file: String, data_folder: &Path) -> std::io::Result<()> {
                     }
                 }
             }
-            _ => false
+            _ => false,
         }
     }));
 
     window.borrow().end();
     window.borrow_mut().show();
 
-    app.run().unwrap();
+    // Like `app.run()`, checking for the worker's outcome whenever it wakes the loop up
+    while app.wait().unwrap() {
+        let outcome = worker.borrow_mut().try_outcome();
+        if let Some(outcome) = outcome {
+            window.borrow_mut().set_label(TITLE);
+            if let Some((new_state, pics, pictures)) = frame(outcome) {
+                let mut history = history.borrow_mut();
+                if history.last() != Some(&(new_state.clone(), pics.clone())) {
+                    history.push((new_state.clone(), pics));
+                }
+                *interaction_state.borrow_mut() = new_state;
+                pics_data.borrow_mut().vec = pictures;
+                window.borrow_mut().redraw();
+            }
+        }
+    }
 
+    worker.borrow_mut().stop();
     Ok(())
 }
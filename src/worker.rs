@@ -0,0 +1,195 @@
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use num_traits::Zero;
+
+use crate::eval::{CancelToken, State};
+use crate::interact::{Interaction, InteractionError, InteractionOutcome};
+use crate::types::NestedList;
+
+pub type Outcome = Result<InteractionOutcome, InteractionError>;
+
+struct Click {
+    st: NestedList,
+    x: i64,
+    y: i64,
+}
+
+// Evaluates clicks on a thread that owns the `State`, so the thread handing them over stays
+// responsive and can cancel them. One click is evaluated at a time.
+pub struct Worker {
+    // Gone once the worker is stopped
+    clicks: Option<Sender<Click>>,
+    outcomes: Receiver<Outcome>,
+    cancel: CancelToken,
+    busy: bool,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    // `load` runs on the new thread and returns the state and the protocol to click on.
+    // `notify` is called from that thread whenever an outcome is ready, and `finish` with the
+    // state once the worker is stopped or dropped.
+    pub fn spawn<L, N, F>(
+        load: L,
+        max_sends: usize,
+        notify: N,
+        finish: F,
+    ) -> io::Result<(Worker, String)>
+    where
+        L: FnOnce() -> io::Result<(State, String)> + Send + 'static,
+        N: Fn() + Send + 'static,
+        F: FnOnce(&State) + Send + 'static,
+    {
+        let (clicks, pending) = channel::<Click>();
+        let (done, outcomes) = channel();
+        let (loaded_tx, loaded) = channel();
+        let thread = thread::spawn(move || {
+            let (state, protocol) = match load() {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    let _ = loaded_tx.send(Err(e));
+                    return;
+                }
+            };
+            if loaded_tx
+                .send(Ok((protocol.clone(), state.cancel_token())))
+                .is_err()
+            {
+                return;
+            }
+            // Runs until the worker is dropped
+            for Click { st, x, y } in pending {
+                let outcome = Interaction::new(&state, &protocol)
+                    .max_sends(max_sends)
+                    .observe(|step| {
+                        if !step.flag.is_zero() {
+                            println!("Sending {}", step.data);
+                        }
+                    })
+                    .click(st, x, y);
+                if done.send(outcome).is_err() {
+                    break;
+                }
+                notify();
+            }
+            finish(&state);
+        });
+        let (protocol, cancel) = loaded
+            .recv()
+            .map_err(|_| io::Error::other("worker died while loading"))??;
+        let worker = Worker {
+            clicks: Some(clicks),
+            outcomes,
+            cancel,
+            busy: false,
+            thread: Some(thread),
+        };
+        Ok((worker, protocol))
+    }
+
+    // Starts evaluating a click, the worker must not be busy
+    pub fn click(&mut self, st: NestedList, x: i64, y: i64) {
+        debug_assert!(!self.busy);
+        self.cancel.reset();
+        // A worker that stopped never answers, which leaves it busy
+        if let Some(clicks) = &self.clicks {
+            let _ = clicks.send(Click { st, x, y });
+        }
+        self.busy = true;
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    // Makes the click being evaluated fail with `EvalError::Cancelled`. A `send` in progress
+    // finishes first.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    // Cancels the click being evaluated and waits for the thread to finish
+    pub fn stop(&mut self) {
+        self.cancel();
+        self.clicks = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    // The outcome of the last click, once it's there
+    pub fn try_outcome(&mut self) -> Option<Outcome> {
+        let outcome = self.outcomes.try_recv().ok()?;
+        self.busy = false;
+        Some(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::EvalError;
+    use crate::syntax::parse_line;
+    use std::time::Duration;
+
+    fn spawn(lines: &'static [&'static str], protocol: &'static str) -> Worker {
+        let load = move || {
+            let mut state = State::new();
+            for line in lines {
+                state.interpret(parse_line(line).unwrap()).unwrap();
+            }
+            Ok((state, protocol.to_string()))
+        };
+        Worker::spawn(load, 10, || {}, |_| {}).unwrap().0
+    }
+
+    fn wait(worker: &mut Worker) -> Outcome {
+        loop {
+            if let Some(outcome) = worker.try_outcome() {
+                return outcome;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_click() {
+        // Draws the clicked point and keeps it as the state
+        let mut worker = spawn(
+            &["echo = ap t ap ap b ap cons 0 ap ap c cons ap ap cons nil nil"],
+            "echo",
+        );
+        assert!(!worker.is_busy());
+        worker.click(NestedList::Nil, 1, 2);
+        assert!(worker.is_busy());
+        let outcome = wait(&mut worker).unwrap();
+        let point = NestedList::Cons(
+            Box::new(NestedList::Number(1.into())),
+            Box::new(NestedList::Number(2.into())),
+        );
+        assert_eq!(outcome.state, point);
+        assert!(!worker.is_busy());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut worker = spawn(&["omega = ap ap s i i", "spin = ap omega omega"], "spin");
+        worker.click(NestedList::Nil, 0, 0);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(worker.try_outcome().map(|_| ()), None);
+        worker.cancel();
+        assert!(matches!(
+            wait(&mut worker),
+            Err(InteractionError::Eval(EvalError::Cancelled))
+        ));
+
+        // The next click starts over
+        worker.click(NestedList::Nil, 0, 0);
+        thread::sleep(Duration::from_millis(20));
+        assert!(worker.is_busy());
+        worker.cancel();
+        assert!(wait(&mut worker).is_err());
+    }
+}